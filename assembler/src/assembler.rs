@@ -1,9 +1,18 @@
-use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::collections::{ HashMap, HashSet };
 use std::fs::File;
 use std::io::{ Read, BufReader };
+use std::path::{ Path, PathBuf };
+use std::rc::Rc;
 
-use crate::error::{ ParseError, ErrorKind };
-use crate::token::{ Token, TokenType, Register, TokenStream };
+use crate::error::{ closest, Diagnostic, ErrorKind, ParseError, Severity };
+use crate::expression::{ fits, parse_expression, Expr };
+use crate::image::{ Image, Segment };
+use crate::lexer::{ KEYWORDS, UNDOCUMENTED };
+use crate::listing::{ line_of, Emitted, LineKey, Listing, SourceLine };
+use crate::macros::{ in_expansion, same_source };
+use crate::timing::{ machine_cycles, t_states };
+use crate::token::{ Expansion, Token, TokenType, Register, TokenStream };
 
 
 #[derive(Clone, Copy)]
@@ -19,11 +28,43 @@ impl Instruction {
     }
 }
 
-type SymbolTable = HashMap<String, u16>;
+/// Symbol values as their expressions gave them, so that `-2` stays negative and still fits
+/// an 8-bit operand.
+pub(crate) type SymbolTable = HashMap<String, i32>;
+
+/// Settings that apply to a whole assembly run.
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    /// Symbols defined before the first line as if by EQU, like `-D BOARD=2` on a command line.
+    pub defines: HashMap<String, u16>,
+    /// Directories searched, in order, for INCLUDE files not found next to the including file.
+    pub include_dirs: Vec<PathBuf>,
+    /// Accepts the undocumented 8085 instructions (DSUB, ARHL, RDEL, LDHI, LDSI, RSTV, SHLX, JNK,
+    /// LHLX and JK). Off by default, so those names stay free for labels and macros.
+    pub undocumented: bool,
+}
+
+/// An IF block the first pass is currently inside of.
+struct Conditional {
+    position: (usize, usize),
+    file: Option<Rc<str>>,
+    seen_else: bool,
+}
+
+/// Where an expression is in the source: the position of its first token and its length in bytes.
+type Span = ((usize, usize), usize);
 
 enum ParsedToken {
     Code(u8),
-    Symbol(String),
+    Byte(Expr, Span, Option<Rc<Expansion>>, Option<Rc<str>>),
+    Word(Expr, Span, Option<Rc<Expansion>>, Option<Rc<str>>),
+    Org(u16),
+    Reserve(u16),
+    Entry(u16),
+    /// The code that follows belongs to this source line, for the listing.
+    Line(LineKey),
+    /// The next byte is an instruction's opcode, for the listing's T-state column.
+    Opcode,
 }
 
 fn next_token(
@@ -32,21 +73,26 @@ fn next_token(
 ) -> Result<Token, ParseError> {
     if let Some(token) = iterator.next() {
         if expected.contains(&token.token) {
-            return Ok(token.to_owned());
+            Ok(token.to_owned())
         } else {
-            return Err(ParseError {
+            Err(ParseError {
                 position: token.position,
+                file: None,
+                length: token.length,
                 error: ErrorKind::UnexpectedToken(expected, token.token.clone()),
-            });
+            })
         }
     } else {
         if expected.contains(&TokenType::End) {
-            Ok( Token { position: (0, 0), token: TokenType::End })
+            Ok( Token { position: (0, 0), length: 0, token: TokenType::End, expansion: None, file: None })
         }
         else {
+            // `parse_first_pass` moves this onto the last token read, which is not known here.
             Err(ParseError {
                 position: (0, 0),
-                error: ErrorKind::Eof,
+                file: None,
+                length: 0,
+                error: ErrorKind::UnexpectedToken(expected, TokenType::End),
             })
         }
     }
@@ -64,6 +110,8 @@ fn get_register_index(name: &Register, position: (usize, usize)) -> Result<u8, P
         Register::M => Ok(6),
         _ => Err(ParseError {
             position,
+            file: None,
+            length: 0,
             error: ErrorKind::InvalidArguments("Register".to_owned(), format!("{:?}", name)),
         }),
     }
@@ -77,131 +125,443 @@ fn get_register_pair_index(name: &Register, position: (usize, usize)) -> Result<
         Register::SP | Register::PSW => Ok(3),
         _ => Err(ParseError {
             position,
+            file: None,
+            length: 0,
             error: ErrorKind::InvalidArguments("Register Pair".to_owned(), format!("{:?}", name)),
         }),
     }
 }
 
-fn parse_first_pass(
-    iterator: &mut std::slice::Iter<Token>
-) -> Result<(Vec<ParsedToken>, SymbolTable), ParseError> {
-    let mut byte = 0u16;
-    let mut symbol_table = SymbolTable::new();
-    let mut stream: Vec<ParsedToken> = vec![];
-    let opcodes: HashMap<&str, Instruction> = HashMap::from([
-        ("ADD", Instruction::new(0x80, 1, 1)),
-        ("ACI", Instruction::new(0xCE, 2, 1)),
-        ("ADC", Instruction::new(0x88, 1, 1)),
-        ("ADI", Instruction::new(0xC6, 2, 1)),
-        ("ANA", Instruction::new(0xA0, 1, 1)),
-        ("ANI", Instruction::new(0xE6, 2, 1)),
-        ("CALL", Instruction::new(0xCD, 3, 1)),
-        ("CC", Instruction::new(0xDC, 3, 1)),
-        ("CM", Instruction::new(0xFC, 3, 1)),
-        ("CMA", Instruction::new(0x2F, 1, 0)),
-        ("CMC", Instruction::new(0x3D, 1, 0)),
-        ("CMP", Instruction::new(0xB8, 1, 1)),
-        ("CNC", Instruction::new(0xD4, 3, 1)),
-        ("CNZ", Instruction::new(0xC4, 3, 1)),
-        ("CP", Instruction::new(0xF4, 3, 1)),
-        ("CPE", Instruction::new(0xEC, 3, 1)),
-        ("CPI", Instruction::new(0xFE, 2, 1)),
-        ("CPO", Instruction::new(0xE4, 3, 1)),
-        ("CZ", Instruction::new(0xCC, 3, 1)),
-        ("DAA", Instruction::new(0x27, 1, 0)),
-        ("DAD", Instruction::new(0x09, 1, 1)),
-        ("DCR", Instruction::new(0x05, 1, 1)),
-        ("DCX", Instruction::new(0x0B, 1, 1)),
-        ("DI", Instruction::new(0xF3, 1, 0)),
-        ("EI", Instruction::new(0xFB, 1, 0)),
-        ("HLT", Instruction::new(0x76, 1, 0)),
-        ("IN", Instruction::new(0xDB, 2, 1)),
-        ("INR", Instruction::new(0x04, 1, 1)),
-        ("INX", Instruction::new(0x03, 1, 1)),
-        ("JC", Instruction::new(0xDA, 3, 1)),
-        ("JNC", Instruction::new(0xD2, 3, 1)),
-        ("JM", Instruction::new(0xFA, 3, 1)),
-        ("JMP", Instruction::new(0xC3, 3, 1)),
-        ("JNZ", Instruction::new(0xC2, 3, 1)),
-        ("JP", Instruction::new(0xF2, 3, 1)),
-        ("JPE", Instruction::new(0xEA, 3, 1)),
-        ("JPO", Instruction::new(0xE2, 3, 1)),
-        ("JZ", Instruction::new(0xCA, 3, 1)),
-        ("LDA", Instruction::new(0x3A, 3, 1)),
-        ("LDAX", Instruction::new(0xA, 1, 1)),
-        ("LHLD", Instruction::new(0x2A, 3, 1)),
-        ("LXI", Instruction::new(0x01, 3, 1)),
-        ("MOV", Instruction::new(0x40, 1, 2)),
-        ("MVI", Instruction::new(0x06, 2, 2)),
-        ("NOP", Instruction::new(0x00, 1, 0)),
-        ("ORA", Instruction::new(0xB0, 1, 1)),
-        ("ORI", Instruction::new(0xF6, 2, 1)),
-        ("OUT", Instruction::new(0xD3, 2, 1)),
-        ("PCHL", Instruction::new(0xE9, 1, 0)),
-        ("POP", Instruction::new(0xC1, 1, 1)),
-        ("POP", Instruction::new(0xF1, 1, 1)),
-        ("PUSH", Instruction::new(0xC5, 1, 1)),
-        ("RAL", Instruction::new(0x17, 1, 0)),
-        ("RAR", Instruction::new(0x1F, 1, 0)),
-        ("RC", Instruction::new(0xD8, 1, 0)),
-        ("RET", Instruction::new(0xC9, 1, 0)),
-        ("RIM", Instruction::new(0x20, 1, 0)),
-        ("RLC", Instruction::new(0x07, 1, 0)),
-        ("RM", Instruction::new(0xF8, 1, 0)),
-        ("RNC", Instruction::new(0xD0, 1, 0)),
-        ("RNZ", Instruction::new(0xC0, 1, 0)),
-        ("RP", Instruction::new(0xF0, 1, 0)),
-        ("RPE", Instruction::new(0xE8, 1, 0)),
-        ("RPO", Instruction::new(0xE0, 1, 0)),
-        ("RRC", Instruction::new(0x0F, 1, 0)),
-        ("RST", Instruction::new(0xC7, 1, 1)),
-        ("RZ", Instruction::new(0xC8, 1, 0)),
-        ("SBB", Instruction::new(0x98, 1, 1)),
-        ("SBI", Instruction::new(0xDE, 2, 1)),
-        ("SHLD", Instruction::new(0x22, 3, 1)),
-        ("SIM", Instruction::new(0x30, 1, 0)),
-        ("SPHL", Instruction::new(0xF9, 1, 0)),
-        ("STA", Instruction::new(0x32, 3, 1)),
-        ("STAX", Instruction::new(0x02, 1, 1)),
-        ("STC", Instruction::new(0x37, 1, 0)),
-        ("SUB", Instruction::new(0x90, 1, 1)),
-        ("SUI", Instruction::new(0xD6, 2, 1)),
-        ("XCHG", Instruction::new(0xEB, 1, 0)),
-        ("XRA", Instruction::new(0xA8, 1, 1)),
-        ("XRI", Instruction::new(0xEE, 2, 1)),
-        ("XTHL", Instruction::new(0xE3, 1, 0)),
-    ]);
-    loop {
-        match next_token(
+fn peek<'a>(iterator: &std::slice::Iter<'a, Token>) -> Option<&'a Token> {
+    iterator.as_slice().first()
+}
+
+fn is_directive(operation: &str) -> bool {
+    ["ORG", "DB", "DW", "DS", "EQU", "SET"].contains(&operation)
+}
+
+/// Evaluates `expr` and checks that the result fits in an operand of `bits` bits.
+fn resolve(expr: &Expr, symbol_table: &SymbolTable, span: Span, bits: u32) -> Result<u16, ParseError> {
+    let value = evaluate_checked(expr, symbol_table, span, bits)?;
+    Ok((value & ((1 << bits) - 1)) as u16)
+}
+
+/// Like `resolve`, but keeps the value as it was evaluated rather than as the operand's bits.
+fn evaluate_checked(
+    expr: &Expr,
+    symbol_table: &SymbolTable,
+    (position, length): Span,
+    bits: u32,
+) -> Result<i32, ParseError> {
+    let value = expr.evaluate(symbol_table, position)?;
+    if fits(value, bits) {
+        Ok(value)
+    } else {
+        Err(ParseError {
+            position,
+            file: None,
+            length,
+            error: ErrorKind::ValueOutOfRange(value, bits),
+        })
+    }
+}
+
+fn expression_position(iterator: &std::slice::Iter<Token>) -> (usize, usize) {
+    peek(iterator).map_or((0, 0), |token| token.position)
+}
+
+/// Parses an expression, also returning its span up to its last token on the line it starts on.
+fn parse_spanned(iterator: &mut std::slice::Iter<Token>, location: u16) -> Result<(Expr, Span), ParseError> {
+    let tokens = iterator.as_slice();
+    let expr = parse_expression(iterator, location)?;
+    let consumed = &tokens[..tokens.len() - iterator.as_slice().len()];
+    let span = match consumed.first() {
+        Some(first) => {
+            let end = consumed
+                .iter()
+                .filter(|token| token.position.0 == first.position.0 && token.position.1 >= first.position.1)
+                .map(|token| token.position.1 + token.length)
+                .max()
+                .unwrap_or(first.position.1 + first.length);
+            (first.position, end - first.position.1)
+        }
+        None => (expression_position(iterator), 0),
+    };
+    Ok((expr, span))
+}
+
+/// Reads an expression that has to be resolvable right away, as needed by ORG, DS, EQU and SET
+/// which move the location counter or define symbols during the first pass.
+fn next_value(
+    iterator: &mut std::slice::Iter<Token>,
+    location: u16,
+    symbol_table: &SymbolTable,
+) -> Result<u16, ParseError> {
+    let (expr, span) = parse_spanned(iterator, location)?;
+    resolve(&expr, symbol_table, span, 16)
+}
+
+/// Reads an 8 or 16 bit operand. It is emitted straight away when every symbol it uses is
+/// already defined (which also gives SET symbols their value at this point in the source), and
+/// otherwise left for the second pass.
+fn push_operand(
+    iterator: &mut std::slice::Iter<Token>,
+    location: u16,
+    symbol_table: &SymbolTable,
+    stream: &mut Vec<ParsedToken>,
+    bits: u32,
+) -> Result<(), ParseError> {
+    let expansion = peek(iterator).and_then(|token| token.expansion.clone());
+    let file = peek(iterator).and_then(|token| token.file.clone());
+    let (expr, span) = parse_spanned(iterator, location)?;
+    if !expr.is_resolved(symbol_table) {
+        stream.push(match bits {
+            8 => ParsedToken::Byte(expr, span, expansion, file),
+            _ => ParsedToken::Word(expr, span, expansion, file),
+        });
+        return Ok(());
+    }
+    let value = resolve(&expr, symbol_table, span, bits)?;
+    stream.push(ParsedToken::Code((value << 8 >> 8) as u8));
+    if bits == 16 {
+        stream.push(ParsedToken::Code((value >> 8) as u8));
+    }
+    Ok(())
+}
+
+fn next_is_comma(iterator: &mut std::slice::Iter<Token>) -> bool {
+    if let Some(Token { token: TokenType::Comma, .. }) = peek(iterator) {
+        iterator.next();
+        true
+    } else {
+        false
+    }
+}
+
+fn parse_directive(
+    directive: &str,
+    position: (usize, usize),
+    iterator: &mut std::slice::Iter<Token>,
+    byte: &mut u32,
+    symbol_table: &SymbolTable,
+    stream: &mut Vec<ParsedToken>,
+) -> Result<(), ParseError> {
+    let location = *byte as u16;
+    match directive {
+        "ORG" => {
+            let origin = next_value(iterator, location, symbol_table)?;
+            *byte = origin as u32;
+            stream.push(ParsedToken::Org(origin));
+        }
+        "DS" => {
+            let size = next_value(iterator, location, symbol_table)?;
+            advance(byte, size, position)?;
+            stream.push(ParsedToken::Reserve(size));
+        }
+        "DB" => loop {
+            // A string on its own is a run of bytes, anything else (including 'A'-20H) is an
+            // expression.
+            let tokens = iterator.as_slice();
+            match (tokens.first(), tokens.get(1)) {
+                (Some(Token { token: TokenType::Str(text), .. }), next)
+                    if !matches!(next, Some(Token { token: TokenType::Operator(_), .. })) =>
+                {
+                    iterator.next();
+                    advance(byte, text.len() as u16, position)?;
+                    stream.extend(text.bytes().map(ParsedToken::Code));
+                }
+                _ => {
+                    push_operand(iterator, location, symbol_table, stream, 8)?;
+                    advance(byte, 1, position)?;
+                }
+            }
+            if !next_is_comma(iterator) {
+                break;
+            }
+        },
+        "DW" => loop {
+            push_operand(iterator, location, symbol_table, stream, 16)?;
+            advance(byte, 2, position)?;
+            if !next_is_comma(iterator) {
+                break;
+            }
+        },
+        // EQU and SET only make sense after the name they define.
+        _ => {
+            return Err(ParseError {
+                position,
+                file: None,
+                length: 0,
+                error: ErrorKind::UnexpectedToken(
+                    vec![TokenType::Label("".to_owned())],
+                    TokenType::Operation(directive.to_owned()),
+                ),
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Moves the location counter on by `size` bytes, failing rather than wrapping past FFFFH. The
+/// counter is one past the last byte, so it may reach 10000H when memory is filled to the top.
+fn advance(byte: &mut u32, size: u16, position: (usize, usize)) -> Result<(), ParseError> {
+    let end = *byte + size as u32;
+    if end > 0x10000 {
+        return Err(ParseError {
+            position,
+            file: None,
+            length: 0,
+            error: ErrorKind::AddressOverflow,
+        });
+    }
+    *byte = end;
+    Ok(())
+}
+
+fn is_conditional(operation: &str) -> bool {
+    ["IF", "IFDEF", "IFNDEF", "ELSE", "ENDIF"].contains(&operation)
+}
+
+/// Skips the tokens of a branch that is not being assembled, up to the ELSE (if `stop_at_else`)
+/// or ENDIF that closes it. Returns true when it stopped at an ELSE. Skipping an ELSE branch
+/// carries on to the ENDIF past any second ELSE, then reports the first one.
+fn skip_branch(
+    iterator: &mut std::slice::Iter<Token>,
+    stop_at_else: bool,
+    opened_at: (usize, usize),
+    file: &Option<Rc<str>>,
+) -> Result<bool, ParseError> {
+    let mut depth = 0usize;
+    let mut duplicate = None;
+    for token in iterator.by_ref() {
+        if let TokenType::Operation(operation) = &token.token {
+            match operation.as_str() {
+                "IF" | "IFDEF" | "IFNDEF" => depth += 1,
+                "ENDIF" if depth == 0 => {
+                    return match duplicate {
+                        Some(error) => Err(error),
+                        None => Ok(false),
+                    };
+                }
+                "ENDIF" => depth -= 1,
+                "ELSE" if depth == 0 && stop_at_else => return Ok(true),
+                "ELSE" if depth == 0 && duplicate.is_none() => {
+                    duplicate = Some(ParseError {
+                        position: token.position,
+                        file: token.file.clone(),
+                        length: token.length,
+                        error: ErrorKind::DuplicateElse,
+                    });
+                }
+                _ => {}
+            }
+        }
+    }
+    Err(ParseError {
+        position: opened_at,
+        file: file.clone(),
+        length: 0,
+        error: ErrorKind::UnterminatedConditional,
+    })
+}
+
+/// Conditions are decided during the first pass, so a symbol that is only defined further down
+/// would give a different answer in each pass. Those are rejected instead.
+fn check_phase(
+    queried: &HashSet<String>,
+    symbol: &str,
+    position: (usize, usize),
+) -> Result<(), ParseError> {
+    if queried.contains(symbol) {
+        Err(ParseError {
+            position,
+            file: None,
+            length: 0,
+            error: ErrorKind::PhaseError(symbol.to_owned()),
+        })
+    } else {
+        Ok(())
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn parse_conditional(
+    directive: &str,
+    position: (usize, usize),
+    file: &Option<Rc<str>>,
+    iterator: &mut std::slice::Iter<Token>,
+    location: u16,
+    symbol_table: &SymbolTable,
+    conditionals: &mut Vec<Conditional>,
+    queried: &mut HashSet<String>,
+) -> Result<(), ParseError> {
+    match directive {
+        "IF" => {
+            let expression_position = expression_position(iterator);
+            let condition = parse_expression(iterator, location).and_then(|expr| {
+                expr.evaluate(symbol_table, expression_position).map_err(|error| match error.error {
+                    ErrorKind::UndefinedSymbol(symbol) => ParseError {
+                        position: error.position,
+                        file: None,
+                        length: error.length,
+                        error: ErrorKind::PhaseError(symbol),
+                    },
+                    _ => error,
+                })
+            });
+            match condition {
+                Ok(condition) => open_conditional(iterator, position, file, condition != 0, conditionals),
+                Err(error) => {
+                    // Go on as if the condition were false, so that its ELSE and ENDIF still
+                    // have an IF to match. The expression is the error to report either way.
+                    let _ = open_conditional(iterator, position, file, false, conditionals);
+                    Err(error)
+                }
+            }
+        }
+        "IFDEF" | "IFNDEF" => {
+            let symbol = match next_token(iterator, vec![TokenType::Label("".to_owned())])?.token {
+                TokenType::Label(symbol) => symbol,
+                _ => unreachable!("should never happen!"),
+            };
+            let defined = symbol_table.contains_key(&symbol);
+            if !defined {
+                queried.insert(symbol);
+            }
+            open_conditional(iterator, position, file, defined == (directive == "IFDEF"), conditionals)
+        }
+        "ELSE" => match conditionals.last() {
+            Some(conditional) if !conditional.seen_else => {
+                // Reaching ELSE means the IF branch was assembled, so the rest is skipped.
+                let conditional = conditionals.pop().expect("matched above");
+                skip_branch(iterator, false, conditional.position, &conditional.file)?;
+                Ok(())
+            }
+            Some(_) => Err(ParseError {
+                position,
+                file: None,
+                length: 0,
+                error: ErrorKind::DuplicateElse,
+            }),
+            None => Err(ParseError {
+                position,
+                file: None,
+                length: 0,
+                error: ErrorKind::UnmatchedConditional(directive.to_owned()),
+            }),
+        },
+        _ => match conditionals.pop() {
+            Some(_) => Ok(()),
+            None => Err(ParseError {
+                position,
+                file: None,
+                length: 0,
+                error: ErrorKind::UnmatchedConditional(directive.to_owned()),
+            }),
+        },
+    }
+}
+
+fn open_conditional(
+    iterator: &mut std::slice::Iter<Token>,
+    position: (usize, usize),
+    file: &Option<Rc<str>>,
+    condition: bool,
+    conditionals: &mut Vec<Conditional>,
+) -> Result<(), ParseError> {
+    if condition {
+        conditionals.push(Conditional { position, file: file.clone(), seen_else: false });
+    } else if skip_branch(iterator, true, position, file)? {
+        conditionals.push(Conditional { position, file: file.clone(), seen_else: true });
+    }
+    Ok(())
+}
+
+/// The position and file of a symbol's definition.
+type Definition = ((usize, usize), Option<Rc<str>>);
+
+/// State carried from one statement to the next during the first pass.
+struct FirstPass {
+    /// The location counter, kept wider than an address so code may end exactly at FFFFH.
+    byte: u32,
+    symbol_table: SymbolTable,
+    variables: HashSet<String>,
+    conditionals: Vec<Conditional>,
+    queried: HashSet<String>,
+    line: Option<LineKey>,
+    stream: Vec<ParsedToken>,
+    /// Where each symbol was first defined, to point at when it is defined again.
+    definitions: HashMap<String, Definition>,
+}
+
+impl FirstPass {
+    /// Parses one statement. Returns true once the end of the program is reached.
+    fn statement(
+        &mut self,
+        iterator: &mut std::slice::Iter<Token>,
+        opcodes: &HashMap<&str, Instruction>,
+        diagnostics: &mut Vec<Diagnostic>,
+    ) -> Result<bool, ParseError> {
+        let FirstPass {
+            byte,
+            symbol_table,
+            variables,
+            conditionals,
+            queried,
+            line,
+            stream,
+            definitions,
+        } = self;
+        let next = next_token(
             iterator,
             vec![
                 TokenType::Operation("".to_owned()),
                 TokenType::Label("".to_owned()),
                 TokenType::End,
             ],
-        )?
-        .token
-        {
+        )?;
+        let key = line_of(&next);
+        if line.as_ref() != Some(&key) {
+            stream.push(ParsedToken::Line(key.clone()));
+            *line = Some(key);
+        }
+        let Token { position, length, token, file, expansion } = next;
+        match token {
+            TokenType::Operation(operation) if is_conditional(&operation) => {
+                parse_conditional(
+                    &operation,
+                    position,
+                    &file,
+                    iterator,
+                    *byte as u16,
+                    symbol_table,
+                    conditionals,
+                    queried,
+                )?;
+            }
+            TokenType::Operation(operation) if is_directive(&operation) => {
+                parse_directive(&operation, position, iterator, byte, symbol_table, stream)?;
+            }
             TokenType::Operation(operation) => {
                 let instruction = opcodes[operation.as_str()];
-                byte += instruction.size as u16;
+                let location = *byte as u16;
+                advance(byte, instruction.size as u16, position)?;
+                stream.push(ParsedToken::Opcode);
                 if instruction.args == 0 {
                     stream.push(ParsedToken::Code(instruction.opcode));
                 } else {
                     match operation.as_str() {
-                        "SUI" | "SBI" | "ORI" | "CPI" | "ANI" | "ADI" | "ACI" | "IN" | "OUT" => {
+                        "SUI" | "SBI" | "ORI" | "XRI" | "CPI" | "ANI" | "ADI" | "ACI" | "IN" | "OUT"
+                        | "LDHI" | "LDSI" => {
                             stream.push(ParsedToken::Code(instruction.opcode));
-                            if let TokenType::U8(byte) =
-                                next_token(iterator, vec![TokenType::U8(0)])?.token
-                            {
-                                stream.push(ParsedToken::Code(byte));
-                            }
+                            push_operand(iterator, location, symbol_table, stream, 8)?;
                         }
                         "ADD" | "ADC" | "ANA" | "ORA" | "SUB" | "CMP" | "SBB" | "XRA" => {
                             let mut opcode = instruction.opcode;
                             if let Token {
                                 position,
                                 token: TokenType::Register(reg),
+                                ..
                             } = next_token(iterator, vec![TokenType::Register(Register::A)])?
                             {
                                 opcode += get_register_index(&reg, position)?;
@@ -209,32 +569,19 @@ fn parse_first_pass(
                             }
                         }
                         "CALL" | "CC" | "CM" | "CNC" | "CNZ" | "CP" | "CPE" | "CPO" | "CZ" | "JC"
-                        | "JM" | "JMP" | "JNC" | "JNZ" | "JP" | "JPE" | "JPO" | "JZ" => {
+                        | "JM" | "JMP" | "JNC" | "JNZ" | "JP" | "JPE" | "JPO" | "JZ" | "JNK" | "JK" => {
                             stream.push(ParsedToken::Code(instruction.opcode));
-                            if let TokenType::Label(label) =
-                                next_token(iterator, vec![TokenType::Label("".to_owned())])?.token
-                            {
-                                stream.push(ParsedToken::Symbol(label));
-                            }
+                            push_operand(iterator, location, symbol_table, stream, 16)?;
                         }
                         "LDA" | "LHLD" | "SHLD" | "STA" => {
                             stream.push(ParsedToken::Code(instruction.opcode));
-                            match next_token(iterator, vec![TokenType::U16(0), TokenType::U8(0)])?.token {
-                                TokenType::U8(byte) => {
-                                    stream.push(ParsedToken::Code(byte));
-                                    stream.push(ParsedToken::Code(0));
-                                }
-                                TokenType::U16(addr) => {
-                                    stream.push(ParsedToken::Code((addr << 8 >> 8) as u8));
-                                    stream.push(ParsedToken::Code((addr >> 8) as u8));
-                                }
-                                _ => unreachable!("should never happen!")
-                            }
+                            push_operand(iterator, location, symbol_table, stream, 16)?;
                         }
                         "DAD" => {
                             if let Token {
                                 position,
                                 token: TokenType::Register(reg),
+                                ..
                             } = next_token(iterator, vec![TokenType::Register(Register::A)])?
                             {
                                 stream.push(ParsedToken::Code({
@@ -246,6 +593,8 @@ fn parse_first_pass(
                                         _ => {
                                             return Err(ParseError {
                                                 position,
+                                                file: None,
+                                                length: 0,
                                                 error: ErrorKind::InvalidArguments(
                                                     "B, D, H or SP".to_owned(),
                                                     format!("{:?}", reg),
@@ -260,6 +609,7 @@ fn parse_first_pass(
                             if let Token {
                                 position,
                                 token: TokenType::Register(reg),
+                                ..
                             } = next_token(iterator, vec![TokenType::Register(Register::A)])?
                             {
                                 stream.push(ParsedToken::Code({
@@ -271,6 +621,8 @@ fn parse_first_pass(
                                         _ => {
                                             return Err(ParseError {
                                                 position,
+                                                file: None,
+                                                length: 0,
                                                 error: ErrorKind::InvalidArguments(
                                                     "B, D, H or SP".to_owned(),
                                                     format!("{:?}", reg),
@@ -285,6 +637,7 @@ fn parse_first_pass(
                             if let Token {
                                 position,
                                 token: TokenType::Register(reg),
+                                ..
                             } = next_token(iterator, vec![TokenType::Register(Register::A)])?
                             {
                                 stream.push(ParsedToken::Code({
@@ -296,6 +649,8 @@ fn parse_first_pass(
                                         _ => {
                                             return Err(ParseError {
                                                 position,
+                                                file: None,
+                                                length: 0,
                                                 error: ErrorKind::InvalidArguments(
                                                     "B, D, H or SP".to_owned(),
                                                     format!("{:?}", reg),
@@ -310,17 +665,19 @@ fn parse_first_pass(
                             if let Token {
                                 position,
                                 token: TokenType::Register(reg),
+                                ..
                             } = next_token(iterator, vec![TokenType::Register(Register::A)])?
                             {
-                                let opcode;
-                                match reg {
-                                    Register::B => opcode = 0x01,
-                                    Register::D => opcode = 0x11,
-                                    Register::H => opcode = 0x21,
-                                    Register::SP => opcode = 0x31,
+                                let opcode = match reg {
+                                    Register::B => 0x01,
+                                    Register::D => 0x11,
+                                    Register::H => 0x21,
+                                    Register::SP => 0x31,
                                     _ => {
                                         return Err(ParseError {
                                             position,
+                                            file: None,
+                                            length: 0,
                                             error: ErrorKind::InvalidArguments(
                                                 "B, D, H or SP".to_owned(),
                                                 format!("{:?}", reg),
@@ -330,17 +687,7 @@ fn parse_first_pass(
                                 };
                                 stream.push(ParsedToken::Code(opcode));
                                 next_token(iterator, vec![TokenType::Comma])?;
-                                match next_token(iterator, vec![TokenType::U16(0), TokenType::U8(0)])?.token {
-                                    TokenType::U8(byte) => {
-                                        stream.push(ParsedToken::Code(byte));
-                                        stream.push(ParsedToken::Code(0));
-                                    }
-                                    TokenType::U16(addr) => {
-                                        stream.push(ParsedToken::Code((addr << 8 >> 8) as u8));
-                                        stream.push(ParsedToken::Code((addr >> 8) as u8));
-                                    }
-                                    _ => unreachable!("should never happen!")
-                                }
+                                push_operand(iterator, location, symbol_table, stream, 16)?;
                             }
                         }
                         "LDAX" | "STAX" => {
@@ -348,6 +695,7 @@ fn parse_first_pass(
                             if let Token {
                                 position,
                                 token: TokenType::Register(reg),
+                                ..
                             } = next_token(iterator, vec![TokenType::Register(Register::A)])?
                             {
                                 match reg {
@@ -357,6 +705,8 @@ fn parse_first_pass(
                                     _ => {
                                         return Err(ParseError {
                                             position,
+                                            file: None,
+                                            length: 0,
                                             error: ErrorKind::InvalidArguments(
                                                 "B or D".to_owned(),
                                                 format!("{:?}", reg),
@@ -371,11 +721,14 @@ fn parse_first_pass(
                             if let Token {
                                 position,
                                 token: TokenType::Register(reg),
+                                ..
                             } = next_token(iterator, vec![TokenType::Register(Register::A)])?
                             {
                                 if reg == Register::SP {
                                     return Err(ParseError {
                                         position,
+                                        file: None,
+                                        length: 0,
                                         error: ErrorKind::InvalidArguments(
                                             "B, D, H or M".to_owned(),
                                             "SP".to_owned(),
@@ -389,30 +742,27 @@ fn parse_first_pass(
                             }
                         }
                         "RST" => {
-                            let opcode = instruction.opcode;
-                            if let Token {
-                                position,
-                                token: TokenType::U8(number),
-                            } = next_token(iterator, vec![TokenType::U8(0)])?
-                            {
-                                if !((0..7).contains(&number)) {
-                                    return Err(ParseError {
-                                        position,
-                                        error: ErrorKind::InvalidArguments(
-                                            "[0-7]".to_owned(),
-                                            format!("{}", number),
-                                        ),
-                                    });
-                                } else {
-                                    stream.push(ParsedToken::Code(opcode + 8 * number));
-                                }
+                            let position = expression_position(iterator);
+                            let number = next_value(iterator, location, symbol_table)?;
+                            if number > 7 {
+                                return Err(ParseError {
+                                    position,
+                                    file: None,
+                                    length: 0,
+                                    error: ErrorKind::InvalidArguments(
+                                        "[0-7]".to_owned(),
+                                        format!("{}", number),
+                                    ),
+                                });
                             }
+                            stream.push(ParsedToken::Code(instruction.opcode + 8 * number as u8));
                         }
                         "MOV" => {
                             let mut opcode = instruction.opcode;
                             if let Token {
                                 position,
                                 token: TokenType::Register(reg),
+                                ..
                             } = next_token(iterator, vec![TokenType::Register(Register::A)])?
                             {
                                 opcode += 8 * get_register_index(&reg, position)?;
@@ -421,6 +771,7 @@ fn parse_first_pass(
                             if let Token {
                                 position,
                                 token: TokenType::Register(reg),
+                                ..
                             } = next_token(iterator, vec![TokenType::Register(Register::A)])?
                             {
                                 opcode += get_register_index(&reg, position)?;
@@ -431,6 +782,7 @@ fn parse_first_pass(
                             if let Token {
                                 position,
                                 token: TokenType::Register(reg),
+                                ..
                             } = next_token(iterator, vec![TokenType::Register(Register::A)])?
                             {
                                 stream.push(match reg {
@@ -445,6 +797,8 @@ fn parse_first_pass(
                                     _ => {
                                         return Err(ParseError {
                                             position,
+                                            file: None,
+                                            length: 0,
                                             error: ErrorKind::InvalidArguments(
                                                 "Register".to_owned(),
                                                 format!("{:?}", reg),
@@ -454,16 +808,13 @@ fn parse_first_pass(
                                 });
                             }
                             next_token(iterator, vec![TokenType::Comma])?;
-                            if let TokenType::U8(number) =
-                                next_token(iterator, vec![TokenType::U8(0)])?.token
-                            {
-                                stream.push(ParsedToken::Code(number));
-                            }
+                            push_operand(iterator, location, symbol_table, stream, 8)?;
                         }
                         "INR" => {
                             if let Token {
                                 position,
                                 token: TokenType::Register(reg),
+                                ..
                             } = next_token(iterator, vec![TokenType::Register(Register::A)])?
                             {
                                 stream.push(match reg {
@@ -478,6 +829,8 @@ fn parse_first_pass(
                                     _ => {
                                         return Err(ParseError {
                                             position,
+                                            file: None,
+                                            length: 0,
                                             error: ErrorKind::InvalidArguments(
                                                 "Register".to_owned(),
                                                 format!("{:?}", reg),
@@ -491,6 +844,7 @@ fn parse_first_pass(
                             if let Token {
                                 position,
                                 token: TokenType::Register(reg),
+                                ..
                             } = next_token(iterator, vec![TokenType::Register(Register::A)])?
                             {
                                 stream.push(match reg {
@@ -505,6 +859,8 @@ fn parse_first_pass(
                                     _ => {
                                         return Err(ParseError {
                                             position,
+                                            file: None,
+                                            length: 0,
                                             error: ErrorKind::InvalidArguments(
                                                 "Register".to_owned(),
                                                 format!("{:?}", reg),
@@ -518,45 +874,492 @@ fn parse_first_pass(
                     }
                 }
             }
-            TokenType::Label(label) => {
-                next_token(iterator, vec![TokenType::Colon])?;
-                symbol_table.insert(label.clone(), byte);
-            }
-            TokenType::End => break,
+            TokenType::Label(label) => match peek(iterator).map(|next| &next.token) {
+                Some(TokenType::Operation(directive)) if directive == "EQU" || directive == "SET" => {
+                    let redefinable = directive == "SET";
+                    iterator.next();
+                    let (expr, span) = parse_spanned(iterator, *byte as u16)?;
+                    let value = evaluate_checked(&expr, symbol_table, span, 16)?;
+                    check_phase(queried, &label, position)?;
+                    if symbol_table.contains_key(&label)
+                        && !(redefinable && variables.contains(&label))
+                    {
+                        redefinition(label, position, file, &expansion, definitions, diagnostics);
+                        return Ok(false);
+                    }
+                    if redefinable {
+                        variables.insert(label.clone());
+                    }
+                    definitions.entry(label.clone()).or_insert((position, file));
+                    symbol_table.insert(label, value);
+                }
+                Some(TokenType::Colon) => {
+                    iterator.next();
+                    check_phase(queried, &label, position)?;
+                    // A duplicate label keeps its first address; the rest of the line still assembles.
+                    match symbol_table.entry(label) {
+                        Entry::Occupied(entry) => {
+                            redefinition(entry.key().clone(), position, file, &expansion, definitions, diagnostics)
+                        }
+                        Entry::Vacant(entry) => {
+                            definitions.insert(entry.key().clone(), (position, file));
+                            entry.insert(i32::from(*byte as u16));
+                        }
+                    }
+                }
+                _ if label == "END" => {
+                    // An operand on the same line as END names the program's start address.
+                    if peek(iterator).is_some_and(|next| next.position.0 == position.0 && next.file == file) {
+                        stream.push(ParsedToken::Entry(next_value(iterator, *byte as u16, symbol_table)?));
+                    }
+                    return Ok(true);
+                }
+                _ => {
+                    // A name with operands after it, or one that is (almost) a mnemonic, was meant
+                    // as an instruction; anything else is more likely a label missing its colon.
+                    let operands =
+                        peek(iterator).is_some_and(|next| next.position.0 == position.0 && next.file == file);
+                    let mnemonic = UNDOCUMENTED.contains(&label.as_str())
+                        || closest(&label, KEYWORDS.iter().copied()).is_some();
+                    if operands || mnemonic {
+                        return Err(ParseError {
+                            position,
+                            file: None,
+                            length,
+                            error: ErrorKind::UnknownInstruction(label),
+                        });
+                    }
+                    next_token(iterator, vec![TokenType::Colon])?;
+                }
+            },
+            TokenType::End => return Ok(true),
             _ => unreachable!("should never happen!"),
         }
+        Ok(false)
     }
-    Ok((stream, symbol_table))
 }
 
-fn second_pass(symbol_table: &SymbolTable, token_stream: &Vec<ParsedToken>) -> Vec<u8> {
-    let mut bytes = vec![];
+/// Reports `symbol` being defined again at `position`, with a note pointing at its first definition.
+fn redefinition(
+    symbol: String,
+    position: (usize, usize),
+    file: Option<Rc<str>>,
+    expansion: &Option<Rc<Expansion>>,
+    definitions: &HashMap<String, Definition>,
+    diagnostics: &mut Vec<Diagnostic>,
+) {
+    let previous = definitions.get(&symbol).map(|(position, file)| ParseError {
+        position: *position,
+        file: file.clone(),
+        length: 0,
+        error: ErrorKind::PreviousDefinition(symbol.clone()),
+    });
+    let error = ParseError {
+        position,
+        file,
+        length: 0,
+        error: ErrorKind::SymbolRedefined(symbol),
+    };
+    diagnostics.push(Diagnostic::new(Severity::Error, in_expansion(error, expansion)));
+    if let Some(previous) = previous {
+        diagnostics.push(Diagnostic::new(Severity::Note, previous));
+    }
+}
+
+/// Wraps an error with the file and macro expansion of `token`, the last one consumed.
+fn located(error: ParseError, token: Option<&Token>) -> ParseError {
+    let file = token.and_then(|token| token.file.clone());
+    let expansion = token.and_then(|token| token.expansion.clone());
+    in_expansion(error.in_file(&file), &expansion)
+}
+
+fn parse_first_pass(
+    iterator: &mut std::slice::Iter<Token>,
+    options: &Options,
+    diagnostics: &mut Vec<Diagnostic>,
+) -> (Vec<ParsedToken>, SymbolTable) {
+    let opcodes: HashMap<&str, Instruction> = HashMap::from([
+        ("ADD", Instruction::new(0x80, 1, 1)),
+        ("ACI", Instruction::new(0xCE, 2, 1)),
+        ("ADC", Instruction::new(0x88, 1, 1)),
+        ("ADI", Instruction::new(0xC6, 2, 1)),
+        ("ANA", Instruction::new(0xA0, 1, 1)),
+        ("ANI", Instruction::new(0xE6, 2, 1)),
+        ("CALL", Instruction::new(0xCD, 3, 1)),
+        ("CC", Instruction::new(0xDC, 3, 1)),
+        ("CM", Instruction::new(0xFC, 3, 1)),
+        ("CMA", Instruction::new(0x2F, 1, 0)),
+        ("CMC", Instruction::new(0x3F, 1, 0)),
+        ("CMP", Instruction::new(0xB8, 1, 1)),
+        ("CNC", Instruction::new(0xD4, 3, 1)),
+        ("CNZ", Instruction::new(0xC4, 3, 1)),
+        ("CP", Instruction::new(0xF4, 3, 1)),
+        ("CPE", Instruction::new(0xEC, 3, 1)),
+        ("CPI", Instruction::new(0xFE, 2, 1)),
+        ("CPO", Instruction::new(0xE4, 3, 1)),
+        ("CZ", Instruction::new(0xCC, 3, 1)),
+        ("DAA", Instruction::new(0x27, 1, 0)),
+        ("DAD", Instruction::new(0x09, 1, 1)),
+        ("DCR", Instruction::new(0x05, 1, 1)),
+        ("DCX", Instruction::new(0x0B, 1, 1)),
+        ("DI", Instruction::new(0xF3, 1, 0)),
+        ("EI", Instruction::new(0xFB, 1, 0)),
+        ("HLT", Instruction::new(0x76, 1, 0)),
+        ("IN", Instruction::new(0xDB, 2, 1)),
+        ("INR", Instruction::new(0x04, 1, 1)),
+        ("INX", Instruction::new(0x03, 1, 1)),
+        ("JC", Instruction::new(0xDA, 3, 1)),
+        ("JNC", Instruction::new(0xD2, 3, 1)),
+        ("JM", Instruction::new(0xFA, 3, 1)),
+        ("JMP", Instruction::new(0xC3, 3, 1)),
+        ("JNZ", Instruction::new(0xC2, 3, 1)),
+        ("JP", Instruction::new(0xF2, 3, 1)),
+        ("JPE", Instruction::new(0xEA, 3, 1)),
+        ("JPO", Instruction::new(0xE2, 3, 1)),
+        ("JZ", Instruction::new(0xCA, 3, 1)),
+        ("LDA", Instruction::new(0x3A, 3, 1)),
+        ("LDAX", Instruction::new(0xA, 1, 1)),
+        ("LHLD", Instruction::new(0x2A, 3, 1)),
+        ("LXI", Instruction::new(0x01, 3, 1)),
+        ("MOV", Instruction::new(0x40, 1, 2)),
+        ("MVI", Instruction::new(0x06, 2, 2)),
+        ("NOP", Instruction::new(0x00, 1, 0)),
+        ("ORA", Instruction::new(0xB0, 1, 1)),
+        ("ORI", Instruction::new(0xF6, 2, 1)),
+        ("OUT", Instruction::new(0xD3, 2, 1)),
+        ("PCHL", Instruction::new(0xE9, 1, 0)),
+        ("POP", Instruction::new(0xC1, 1, 1)),
+        ("PUSH", Instruction::new(0xC5, 1, 1)),
+        ("RAL", Instruction::new(0x17, 1, 0)),
+        ("RAR", Instruction::new(0x1F, 1, 0)),
+        ("RC", Instruction::new(0xD8, 1, 0)),
+        ("RET", Instruction::new(0xC9, 1, 0)),
+        ("RIM", Instruction::new(0x20, 1, 0)),
+        ("RLC", Instruction::new(0x07, 1, 0)),
+        ("RM", Instruction::new(0xF8, 1, 0)),
+        ("RNC", Instruction::new(0xD0, 1, 0)),
+        ("RNZ", Instruction::new(0xC0, 1, 0)),
+        ("RP", Instruction::new(0xF0, 1, 0)),
+        ("RPE", Instruction::new(0xE8, 1, 0)),
+        ("RPO", Instruction::new(0xE0, 1, 0)),
+        ("RRC", Instruction::new(0x0F, 1, 0)),
+        ("RST", Instruction::new(0xC7, 1, 1)),
+        ("RZ", Instruction::new(0xC8, 1, 0)),
+        ("SBB", Instruction::new(0x98, 1, 1)),
+        ("SBI", Instruction::new(0xDE, 2, 1)),
+        ("SHLD", Instruction::new(0x22, 3, 1)),
+        ("SIM", Instruction::new(0x30, 1, 0)),
+        ("SPHL", Instruction::new(0xF9, 1, 0)),
+        ("STA", Instruction::new(0x32, 3, 1)),
+        ("STAX", Instruction::new(0x02, 1, 1)),
+        ("STC", Instruction::new(0x37, 1, 0)),
+        ("SUB", Instruction::new(0x90, 1, 1)),
+        ("SUI", Instruction::new(0xD6, 2, 1)),
+        ("XCHG", Instruction::new(0xEB, 1, 0)),
+        ("XRA", Instruction::new(0xA8, 1, 1)),
+        ("XRI", Instruction::new(0xEE, 2, 1)),
+        ("XTHL", Instruction::new(0xE3, 1, 0)),
+        ("DSUB", Instruction::new(0x08, 1, 0)),
+        ("ARHL", Instruction::new(0x10, 1, 0)),
+        ("RDEL", Instruction::new(0x18, 1, 0)),
+        ("LDHI", Instruction::new(0x28, 2, 1)),
+        ("LDSI", Instruction::new(0x38, 2, 1)),
+        ("RSTV", Instruction::new(0xCB, 1, 0)),
+        ("SHLX", Instruction::new(0xD9, 1, 0)),
+        ("JNK", Instruction::new(0xDD, 3, 1)),
+        ("LHLX", Instruction::new(0xED, 1, 0)),
+        ("JK", Instruction::new(0xFD, 3, 1)),
+    ]);
+    let mut pass = FirstPass {
+        byte: 0,
+        symbol_table: options
+            .defines
+            .iter()
+            .map(|(symbol, value)| (symbol.to_ascii_uppercase(), i32::from(*value)))
+            .collect(),
+        variables: HashSet::new(),
+        conditionals: vec![],
+        queried: HashSet::new(),
+        line: None,
+        stream: vec![],
+        definitions: HashMap::new(),
+    };
+    let tokens = iterator.as_slice();
+    loop {
+        let remaining = iterator.as_slice().len();
+        let error = match pass.statement(iterator, &opcodes, diagnostics) {
+            Ok(true) => break,
+            Ok(false) => continue,
+            Err(error) => error,
+        };
+        // The failing statement is the one the last consumed token belongs to.
+        let consumed = tokens.len() - iterator.as_slice().len();
+        let last = consumed.checked_sub(1).map(|i| &tokens[i]);
+        // Running out of tokens has no position of its own, so it is shown on the last one read.
+        let error = match last {
+            Some(last) if error.position == (0, 0) => {
+                ParseError { position: last.position, length: last.length, ..error }
+            }
+            _ => error,
+        };
+        diagnostics.push(Diagnostic::new(Severity::Error, located(error, last)));
+        // Carry on with the next line.
+        if iterator.as_slice().len() == remaining {
+            iterator.next();
+        }
+        if let Some(last) = last {
+            while iterator
+                .as_slice()
+                .first()
+                .is_some_and(|next| next.position.0 == last.position.0 && same_source(next, last))
+            {
+                iterator.next();
+            }
+        }
+    }
+    if let Some(next) = iterator.as_slice().first() {
+        diagnostics.push(Diagnostic::new(Severity::Warning, located(ParseError {
+            position: next.position,
+            file: None,
+            length: next.length,
+            error: ErrorKind::CodeAfterEnd,
+        }, Some(next))));
+    }
+    if let Some(conditional) = pass.conditionals.last() {
+        diagnostics.push(Diagnostic::new(Severity::Error, ParseError {
+            position: conditional.position,
+            file: conditional.file.clone(),
+            length: 0,
+            error: ErrorKind::UnterminatedConditional,
+        }));
+    }
+    (pass.stream, pass.symbol_table)
+}
+
+fn second_pass(
+    symbol_table: &SymbolTable,
+    token_stream: &Vec<ParsedToken>,
+    diagnostics: &mut Vec<Diagnostic>,
+) -> (Image, HashMap<LineKey, Emitted>) {
+    let mut image = Image::default();
+    let mut segment = Segment::new(0);
+    let mut lines: HashMap<LineKey, Emitted> = HashMap::new();
+    let mut line = None;
+    let mut opcode = false;
     for parsed in token_stream {
+        let start = segment.bytes.len();
         match parsed {
-            ParsedToken::Code(byte) => bytes.push(*byte),
-            ParsedToken::Symbol(symbol) => {
-                let word = symbol_table[symbol];
-                bytes.push((word << 8 >> 8) as u8);
-                bytes.push((word >> 8) as u8);
+            ParsedToken::Code(byte) => segment.bytes.push(*byte),
+            ParsedToken::Byte(expr, span, expansion, file) => {
+                let byte = resolve(expr, symbol_table, *span, 8).unwrap_or_else(|error| {
+                    diagnostics.push(Diagnostic::new(Severity::Error, in_expansion(error.in_file(file), expansion)));
+                    0
+                });
+                segment.bytes.push(byte as u8);
+            }
+            ParsedToken::Word(expr, span, expansion, file) => {
+                let word = resolve(expr, symbol_table, *span, 16).unwrap_or_else(|error| {
+                    diagnostics.push(Diagnostic::new(Severity::Error, in_expansion(error.in_file(file), expansion)));
+                    0
+                });
+                segment.bytes.push((word << 8 >> 8) as u8);
+                segment.bytes.push((word >> 8) as u8);
+            }
+            ParsedToken::Org(origin) => {
+                image.push(segment);
+                segment = Segment::new(*origin);
+                // An ORG line is listed at the address it sets.
+                if let Some(emitted) = line.as_ref().and_then(|key| lines.get_mut(key)) {
+                    if emitted.bytes.is_empty() {
+                        emitted.address = Some(*origin);
+                    }
+                }
+            }
+            ParsedToken::Reserve(size) => {
+                let next = segment.end().wrapping_add(*size);
+                image.push(segment);
+                segment = Segment::new(next);
+            }
+            ParsedToken::Entry(entry) => image.entry = Some(*entry),
+            ParsedToken::Line(key) => {
+                lines.entry(key.clone()).or_default().address.get_or_insert(segment.end());
+                line = Some(key.clone());
+            }
+            ParsedToken::Opcode => opcode = true,
+        }
+        let bytes = segment.bytes.get(start..).unwrap_or_default();
+        if let (Some(emitted), Some(first)) = (line.as_ref().and_then(|key| lines.get_mut(key)), bytes.first()) {
+            if std::mem::take(&mut opcode) {
+                if let Some((low, high)) = t_states(*first) {
+                    let (total_low, total_high) = emitted.t_states.unwrap_or_default();
+                    emitted.t_states = Some((total_low + low as u32, total_high + high as u32));
+                    let (total_low, total_high) = emitted.machine_cycles.unwrap_or_default();
+                    emitted.machine_cycles = Some((
+                        total_low + machine_cycles(low) as u32,
+                        total_high + machine_cycles(high) as u32,
+                    ));
+                }
             }
+            emitted.bytes.extend_from_slice(bytes);
         }
     }
-    bytes
+    image.push(segment);
+    (image, lines)
 }
 
-fn assemble_tokens(tokens: &mut TokenStream) -> Result<Vec<u8>, ParseError> {
-    let (pre, symbol_table) = parse_first_pass(&mut tokens.iter())?;
-    Ok(second_pass(&symbol_table, &pre))
+/// Everything an assembly run produced. `image` and `listing` are only there when none of the
+/// diagnostics is an error.
+#[derive(Debug, Clone, Default)]
+pub struct Assembly {
+    pub image: Option<Image>,
+    pub listing: Option<Listing>,
+    pub diagnostics: Vec<Diagnostic>,
+    /// The source lines that were read, to show the diagnostics in context.
+    pub sources: Vec<SourceLine>,
 }
 
-pub fn assemble_file<P>(filename: P) -> std::io::Result<Result<Vec<u8>, ParseError>> 
-where P: AsRef<std::path::Path> {
+impl Assembly {
+    /// Every diagnostic with the source line it points at, as `Diagnostic::render` shows it.
+    pub fn render(&self) -> String {
+        self.diagnostics
+            .iter()
+            .map(|diagnostic| {
+                let (file, number) = (&diagnostic.message.file, diagnostic.message.position.0);
+                let line = self.sources.iter().find(|line| &line.file == file && line.number == number);
+                diagnostic.render(line.map(|line| line.text.as_str()))
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    pub fn has_errors(&self) -> bool {
+        self.diagnostics.iter().any(|diagnostic| diagnostic.severity == Severity::Error)
+    }
+
+    /// The image and listing, or the first error.
+    fn into_result(self) -> Result<(Image, Listing), ParseError> {
+        match (self.image, self.listing) {
+            (Some(image), Some(listing)) => Ok((image, listing)),
+            _ => Err(self
+                .diagnostics
+                .into_iter()
+                .find(|diagnostic| diagnostic.severity == Severity::Error)
+                .map(|diagnostic| diagnostic.message)
+                .expect("an assembly without an image has an error")),
+        }
+    }
+}
+
+/// What a misspelt instruction or symbol was probably meant to be.
+fn suggestion(error: &ErrorKind, symbol_table: &SymbolTable) -> Option<String> {
+    let candidate = match error.root() {
+        ErrorKind::UnknownInstruction(name) if UNDOCUMENTED.contains(&name.as_str()) => {
+            return Some(format!("`{}` is undocumented, set `Options::undocumented` to use it", name));
+        }
+        ErrorKind::UnknownInstruction(name) => closest(name, KEYWORDS.iter().copied()),
+        ErrorKind::UndefinedSymbol(symbol) => closest(symbol, symbol_table.keys().map(String::as_str)),
+        _ => None,
+    };
+    candidate.map(|candidate| format!("did you mean `{}`?", candidate))
+}
+
+fn assemble_tokens(
+    tokens: &mut TokenStream,
+    sources: Vec<SourceLine>,
+    mut diagnostics: Vec<Diagnostic>,
+    options: &Options,
+) -> Assembly {
+    let (pre, symbol_table) = parse_first_pass(&mut tokens.iter(), options, &mut diagnostics);
+    let (image, lines) = second_pass(&symbol_table, &pre, &mut diagnostics);
+    for diagnostic in &mut diagnostics {
+        diagnostic.help = suggestion(&diagnostic.message.error, &symbol_table);
+    }
+    let mut assembly = Assembly { diagnostics, ..Default::default() };
+    if !assembly.has_errors() {
+        assembly.listing = Some(crate::listing::build(sources.clone(), lines, &tokens.tokens, &symbol_table));
+        assembly.image = Some(image);
+    }
+    assembly.sources = sources;
+    assembly
+}
+
+pub fn assemble(source: &str) -> Result<Image, ParseError> {
+    assemble_with(source, &Options::default())
+}
+
+pub fn assemble_with(source: &str, options: &Options) -> Result<Image, ParseError> {
+    assemble_source(source, None, options).into_result().map(|(image, _)| image)
+}
+
+/// Like `assemble_with`, also returning the listing of the program.
+pub fn assemble_listing(source: &str, options: &Options) -> Result<(Image, Listing), ParseError> {
+    assemble_source(source, None, options).into_result()
+}
+
+/// Assembles `source` reporting every error and warning instead of stopping at the first error.
+pub fn assemble_report(source: &str, options: &Options) -> Assembly {
+    assemble_source(source, None, options)
+}
+
+fn assemble_source(source: &str, path: Option<&Path>, options: &Options) -> Assembly {
+    let (tokens, sources, load_errors) = crate::include::load(source, path, &options.include_dirs);
+    let tokens = if options.undocumented { with_undocumented(tokens) } else { tokens };
+    let (mut tokens, macro_errors) = crate::macros::expand(tokens);
+    // The statements in error are left out of the tokens, so the passes report their own errors
+    // after these.
+    let diagnostics: Vec<_> = load_errors
+        .into_iter()
+        .chain(macro_errors)
+        .map(|error| Diagnostic::new(Severity::Error, error))
+        .collect();
+    assemble_tokens(&mut tokens, sources, diagnostics, options)
+}
+
+/// Turns the names of undocumented instructions, which the lexer reads as labels, into operations.
+fn with_undocumented(mut tokens: TokenStream) -> TokenStream {
+    for token in &mut tokens.tokens {
+        if let TokenType::Label(name) = &token.token {
+            if UNDOCUMENTED.contains(&name.as_str()) {
+                token.token = TokenType::Operation(name.clone());
+            }
+        }
+    }
+    tokens
+}
+
+fn read_source(filename: &Path) -> std::io::Result<String> {
     let file = File::open(filename)?;
     let mut reader = BufReader::new(&file);
     let mut buffer = String::new();
     reader.read_to_string(&mut buffer)?;
-    match crate::lexer::tokenize(&buffer) {
-        Ok(mut tokens) => Ok(assemble_tokens(&mut tokens)),
-        Err(parse_error) => Ok(Err(parse_error))
-    }
+    Ok(buffer)
+}
+
+pub fn assemble_file<P>(filename: P) -> std::io::Result<Result<Image, ParseError>>
+where P: AsRef<std::path::Path> {
+    assemble_file_with(filename, &Options::default())
+}
+
+/// Assembles `filename`, resolving its INCLUDE directives against `options.include_dirs`.
+pub fn assemble_file_with<P>(filename: P, options: &Options) -> std::io::Result<Result<Image, ParseError>>
+where P: AsRef<std::path::Path> {
+    let source = read_source(filename.as_ref())?;
+    Ok(assemble_source(&source, Some(filename.as_ref()), options).into_result().map(|(image, _)| image))
+}
+
+/// Like `assemble_file_with`, also returning the listing; its `Display` is the `.lst` text.
+pub fn assemble_file_listing<P>(
+    filename: P,
+    options: &Options,
+) -> std::io::Result<Result<(Image, Listing), ParseError>>
+where P: AsRef<std::path::Path> {
+    let source = read_source(filename.as_ref())?;
+    Ok(assemble_source(&source, Some(filename.as_ref()), options).into_result())
+}
+
+/// Like `assemble_report`, for a file.
+pub fn assemble_file_report<P>(filename: P, options: &Options) -> std::io::Result<Assembly>
+where P: AsRef<std::path::Path> {
+    let source = read_source(filename.as_ref())?;
+    Ok(assemble_source(&source, Some(filename.as_ref()), options))
 }
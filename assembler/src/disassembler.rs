@@ -0,0 +1,366 @@
+use std::collections::{ BTreeMap, HashMap };
+use std::fmt::Display;
+
+use crate::lexer::is_symbol_name;
+
+/// What follows an opcode.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Operand {
+    None,
+    Byte,
+    Word,
+}
+
+const REGISTERS: [&str; 8] = ["B", "C", "D", "E", "H", "L", "M", "A"];
+const PAIRS: [&str; 4] = ["B", "D", "H", "SP"];
+const CONDITIONS: [&str; 8] = ["NZ", "Z", "NC", "C", "PO", "PE", "P", "M"];
+const ARITHMETIC: [&str; 8] = ["ADD", "ADC", "SUB", "SBB", "ANA", "XRA", "ORA", "CMP"];
+const IMMEDIATE: [&str; 8] = ["ADI", "ACI", "SUI", "SBI", "ANI", "XRI", "ORI", "CPI"];
+
+/// The mnemonic with any register operands, and what follows the opcode, for every opcode the
+/// 8085 defines.
+fn decode(opcode: u8) -> Option<(String, Operand)> {
+    let register = |index: u8| REGISTERS[index as usize & 7];
+    let pair = PAIRS[(opcode >> 4) as usize & 3];
+    let condition = CONDITIONS[(opcode >> 3) as usize & 7];
+    let fixed = |mnemonic: &str| Some((mnemonic.to_owned(), Operand::None));
+    match opcode {
+        0x76 => fixed("HLT"),
+        0x40..=0x7F => {
+            Some((format!("MOV {}, {}", register(opcode >> 3), register(opcode)), Operand::None))
+        }
+        0x80..=0xBF => {
+            let operation = ARITHMETIC[(opcode >> 3) as usize & 7];
+            Some((format!("{} {}", operation, register(opcode)), Operand::None))
+        }
+        0x00..=0x3F => match opcode {
+            0x00 => fixed("NOP"),
+            0x20 => fixed("RIM"),
+            0x30 => fixed("SIM"),
+            0x07 => fixed("RLC"),
+            0x0F => fixed("RRC"),
+            0x17 => fixed("RAL"),
+            0x1F => fixed("RAR"),
+            0x27 => fixed("DAA"),
+            0x2F => fixed("CMA"),
+            0x37 => fixed("STC"),
+            0x3F => fixed("CMC"),
+            0x02 | 0x12 => Some((format!("STAX {}", pair), Operand::None)),
+            0x0A | 0x1A => Some((format!("LDAX {}", pair), Operand::None)),
+            0x22 => Some(("SHLD".to_owned(), Operand::Word)),
+            0x2A => Some(("LHLD".to_owned(), Operand::Word)),
+            0x32 => Some(("STA".to_owned(), Operand::Word)),
+            0x3A => Some(("LDA".to_owned(), Operand::Word)),
+            _ => match opcode & 0x0F {
+                0x01 => Some((format!("LXI {}", pair), Operand::Word)),
+                0x03 => Some((format!("INX {}", pair), Operand::None)),
+                0x09 => Some((format!("DAD {}", pair), Operand::None)),
+                0x0B => Some((format!("DCX {}", pair), Operand::None)),
+                _ => match opcode & 7 {
+                    4 => Some((format!("INR {}", register(opcode >> 3)), Operand::None)),
+                    5 => Some((format!("DCR {}", register(opcode >> 3)), Operand::None)),
+                    6 => Some((format!("MVI {}", register(opcode >> 3)), Operand::Byte)),
+                    _ => None,
+                },
+            },
+        },
+        0xC3 => Some(("JMP".to_owned(), Operand::Word)),
+        0xC9 => fixed("RET"),
+        0xCD => Some(("CALL".to_owned(), Operand::Word)),
+        0xD3 => Some(("OUT".to_owned(), Operand::Byte)),
+        0xDB => Some(("IN".to_owned(), Operand::Byte)),
+        0xE3 => fixed("XTHL"),
+        0xE9 => fixed("PCHL"),
+        0xEB => fixed("XCHG"),
+        0xF3 => fixed("DI"),
+        0xF9 => fixed("SPHL"),
+        0xFB => fixed("EI"),
+        0xF1 => fixed("POP PSW"),
+        0xF5 => fixed("PUSH PSW"),
+        0xC1 | 0xD1 | 0xE1 => Some((format!("POP {}", pair), Operand::None)),
+        0xC5 | 0xD5 | 0xE5 => Some((format!("PUSH {}", pair), Operand::None)),
+        _ => match opcode & 7 {
+            0 => Some((format!("R{}", condition), Operand::None)),
+            2 => Some((format!("J{}", condition), Operand::Word)),
+            4 => Some((format!("C{}", condition), Operand::Word)),
+            6 => Some((IMMEDIATE[(opcode >> 3) as usize & 7].to_owned(), Operand::Byte)),
+            7 => Some((format!("RST {}", (opcode >> 3) & 7), Operand::None)),
+            _ => None,
+        },
+    }
+}
+
+/// A number the way the assembler reads it back: hex with an `H` suffix and a leading zero when
+/// it would otherwise start with a letter.
+fn hex(value: u16, digits: usize) -> String {
+    let text = format!("{:0width$X}H", value, width = digits);
+    if text.starts_with(|c: char| c.is_ascii_alphabetic()) {
+        format!("0{}", text)
+    } else {
+        text
+    }
+}
+
+/// Bytes per `DB` line of data.
+const DATA_PER_LINE: usize = 8;
+
+/// The RST, TRAP and RST 5.5/6.5/7.5 vectors and the reset address: where an 8085 ROM is entered.
+pub const VECTORS: [u16; 12] = [
+    0x0000, 0x0008, 0x0010, 0x0018, 0x0020, 0x0024, 0x0028, 0x002C, 0x0030, 0x0034, 0x0038, 0x003C,
+];
+
+/// One instruction, or a `DB` for bytes that are not code.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DisassemblyLine {
+    pub address: u16,
+    pub bytes: Vec<u8>,
+    /// The symbol defined at `address`, if any.
+    pub label: Option<String>,
+    /// The instruction as the assembler reads it, like `MVI A, 05H` or `JNZ LOOP`.
+    pub text: String,
+}
+
+/// Decoded bytes that assemble back to the same bytes. Its `Display` is that source.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Disassembly {
+    pub origin: u16,
+    pub lines: Vec<DisassemblyLine>,
+    /// Symbols used as operands that do not fall on the start of a line, so have to be given
+    /// their value with EQU.
+    pub equates: Vec<(String, u16)>,
+}
+
+/// An instruction decoded from the start of a run of bytes.
+struct Decoded {
+    mnemonic: String,
+    operand: Operand,
+    bytes: Vec<u8>,
+}
+
+impl Decoded {
+    fn value(&self) -> u16 {
+        match self.operand {
+            Operand::None => 0,
+            Operand::Byte => self.bytes[1] as u16,
+            Operand::Word => u16::from_le_bytes([self.bytes[1], self.bytes[2]]),
+        }
+    }
+
+    /// Where a jump, call or RST may send execution.
+    fn target(&self) -> Option<u16> {
+        match self.bytes[0] {
+            0xC3 | 0xCD => Some(self.value()),
+            opcode if opcode & 0xC7 == 0xC2 || opcode & 0xC7 == 0xC4 => Some(self.value()),
+            opcode if opcode & 0xC7 == 0xC7 => Some((opcode & 0x38) as u16),
+            _ => None,
+        }
+    }
+
+    /// Whether execution can go on to the next instruction. Calls and RSTs are assumed to return.
+    fn falls_through(&self) -> bool {
+        !matches!(self.bytes[0], 0xC3 | 0xC9 | 0xE9)
+    }
+
+    /// Whether the operand is most likely the address of data: LXI and direct loads and stores.
+    fn points_at_data(&self) -> bool {
+        matches!(self.bytes[0], 0x01 | 0x11 | 0x21 | 0x31 | 0x22 | 0x2A | 0x32 | 0x3A)
+    }
+
+    /// The instruction with its address operand written as a symbol from `names`, if one matches.
+    fn text(&self, names: &BTreeMap<u16, String>, used: &mut Vec<u16>) -> String {
+        let separator = if self.mnemonic.contains(' ') { ", " } else { " " };
+        let operand = match self.operand {
+            Operand::None => return self.mnemonic.clone(),
+            Operand::Byte => hex(self.value(), 2),
+            Operand::Word => match names.get(&self.value()) {
+                Some(name) => {
+                    used.push(self.value());
+                    name.clone()
+                }
+                None => hex(self.value(), 4),
+            },
+        };
+        format!("{}{}{}", self.mnemonic, separator, operand)
+    }
+}
+
+fn decode_at(bytes: &[u8]) -> Option<Decoded> {
+    let (mnemonic, operand) = decode(*bytes.first()?)?;
+    let size = match operand {
+        Operand::None => 1,
+        Operand::Byte => 2,
+        Operand::Word => 3,
+    };
+    Some(Decoded { mnemonic, operand, bytes: bytes.get(..size)?.to_vec() })
+}
+
+/// What a byte of the input turned out to be.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Data,
+    /// The first byte of an instruction.
+    Code,
+    /// An instruction's operand byte.
+    Operand,
+}
+
+/// Symbol names by value; with several for one value, the one that sorts first. Names the
+/// assembler would not read back as a symbol, such as ones over six characters, are left out.
+fn names(symbols: &HashMap<String, u16>) -> BTreeMap<u16, String> {
+    let mut names = BTreeMap::new();
+    let mut sorted: Vec<(&String, &u16)> = symbols.iter().filter(|(name, _)| is_symbol_name(name)).collect();
+    sorted.sort();
+    for (name, value) in sorted {
+        names.entry(*value).or_insert_with(|| name.clone());
+    }
+    names
+}
+
+/// Writes out `bytes` as instructions where `kinds` marks code and as DB elsewhere. Data runs
+/// are split at labels, so every label can be defined on a line of its own.
+fn build(bytes: &[u8], origin: u16, kinds: &[Kind], names: &BTreeMap<u16, String>) -> Disassembly {
+    let mut lines = vec![];
+    let mut used = vec![];
+    let mut offset = 0;
+    while offset < bytes.len() {
+        let address = origin.wrapping_add(offset as u16);
+        let decoded = decode_at(&bytes[offset..]).filter(|_| kinds[offset] == Kind::Code);
+        let (text, size) = match decoded {
+            Some(decoded) => (decoded.text(names, &mut used), decoded.bytes.len()),
+            None => {
+                let mut size = 1;
+                while size < DATA_PER_LINE
+                    && offset + size < bytes.len()
+                    && kinds[offset + size] == Kind::Data
+                    && !names.contains_key(&address.wrapping_add(size as u16))
+                {
+                    size += 1;
+                }
+                let data: Vec<String> =
+                    bytes[offset..offset + size].iter().map(|byte| hex(*byte as u16, 2)).collect();
+                (format!("DB {}", data.join(", ")), size)
+            }
+        };
+        lines.push(DisassemblyLine {
+            address,
+            bytes: bytes[offset..offset + size].to_vec(),
+            label: names.get(&address).cloned(),
+            text,
+        });
+        offset += size;
+    }
+
+    let mut equates: Vec<(String, u16)> = used
+        .into_iter()
+        .filter(|value| !lines.iter().any(|line| line.address == *value))
+        .map(|value| (names[&value].clone(), value))
+        .collect();
+    equates.sort();
+    equates.dedup();
+    Disassembly { origin, lines, equates }
+}
+
+/// Decodes `bytes`, loaded at `origin`, into instructions one after the other. Address operands
+/// (jumps, calls, direct loads and stores, LXI) that match the value of one of `symbols` are
+/// written as that symbol, unless the assembler could not read its name back. Undefined opcodes
+/// and instructions cut short by the end of `bytes` become `DB`.
+pub fn disassemble(bytes: &[u8], origin: u16, symbols: &HashMap<String, u16>) -> Disassembly {
+    let mut kinds = vec![Kind::Data; bytes.len()];
+    let mut offset = 0;
+    while offset < bytes.len() {
+        match decode_at(&bytes[offset..]) {
+            Some(decoded) => {
+                kinds[offset] = Kind::Code;
+                kinds[offset + 1..offset + decoded.bytes.len()].fill(Kind::Operand);
+                offset += decoded.bytes.len();
+            }
+            None => offset += 1,
+        }
+    }
+    build(bytes, origin, &kinds, &names(symbols))
+}
+
+/// Disassembles a ROM image loaded at `origin` by following the code from `entries` (usually
+/// `VECTORS` plus any routine addresses known from documentation) through every jump, call and
+/// RST. Bytes never reached are written as data. Jump and call targets get `Lxxxx` labels and
+/// addresses loaded with LXI, LDA, STA, LHLD or SHLD get `Dxxxx` labels, unless `symbols` already
+/// names them.
+///
+/// Calls are assumed to return to the instruction after them, and PCHL ends the path as its
+/// target is not known, so code only reached through jump tables needs to be given in `entries`.
+pub fn disassemble_rom(
+    bytes: &[u8],
+    origin: u16,
+    entries: &[u16],
+    symbols: &HashMap<String, u16>,
+) -> Disassembly {
+    let offset_of = |address: u16| {
+        let offset = address.wrapping_sub(origin) as usize;
+        (offset < bytes.len()).then_some(offset)
+    };
+    let mut kinds = vec![Kind::Data; bytes.len()];
+    let mut targets = vec![];
+    let mut data = vec![];
+    let mut pending = entries.to_vec();
+    while let Some(mut address) = pending.pop() {
+        // Follow the path until it ends or runs into code that was already traced.
+        while let Some(offset) = offset_of(address) {
+            let decoded = match decode_at(&bytes[offset..]) {
+                Some(decoded) => decoded,
+                None => break,
+            };
+            let end = offset + decoded.bytes.len();
+            if kinds[offset..end].iter().any(|kind| *kind != Kind::Data) {
+                break;
+            }
+            kinds[offset] = Kind::Code;
+            kinds[offset + 1..end].fill(Kind::Operand);
+            if let Some(target) = decoded.target() {
+                targets.push(target);
+                pending.push(target);
+            } else if decoded.points_at_data() {
+                data.push(decoded.value());
+            }
+            if !decoded.falls_through() {
+                break;
+            }
+            address = address.wrapping_add(decoded.bytes.len() as u16);
+        }
+    }
+
+    let mut names = names(symbols);
+    let labels = targets.into_iter().map(|target| (target, 'L'));
+    let labels = labels.chain(data.into_iter().map(|address| (address, 'D')));
+    for (address, prefix) in labels {
+        // A label in the middle of an instruction could not be defined on a line of its own.
+        if offset_of(address).is_some_and(|offset| kinds[offset] != Kind::Operand) {
+            names.entry(address).or_insert_with(|| format!("{}{:04X}", prefix, address));
+        }
+    }
+    build(bytes, origin, &kinds, &names)
+}
+
+impl Display for Disassembly {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!("{:8}ORG {}\n", "", hex(self.origin, 4)))?;
+        for (name, value) in &self.equates {
+            // The space keeps a long name apart from EQU.
+            f.write_fmt(format_args!("{:7} EQU {}\n", name, hex(*value, 4)))?;
+        }
+        for line in &self.lines {
+            let label = match &line.label {
+                Some(label) => format!("{}:", label),
+                None => String::new(),
+            };
+            let bytes: Vec<String> = line.bytes.iter().map(|byte| format!("{:02X}", byte)).collect();
+            f.write_fmt(format_args!(
+                "{:8}{:19} ; {:04X}  {}\n",
+                label,
+                line.text,
+                line.address,
+                bytes.join(" ")
+            ))?;
+        }
+        Ok(())
+    }
+}
@@ -1,5 +1,6 @@
 use crate::token::TokenType;
 use std::fmt::Display;
+use std::rc::Rc;
 
 
 #[derive(Debug, Clone)]
@@ -8,12 +9,59 @@ pub enum ErrorKind {
     InvalidArguments(String, String),
     UnexpectedLexeme(String),
     UnexpectedToken(Vec<TokenType>, TokenType),
-    Eof,
+    /// A statement starts with a name that is not an instruction, directive or macro.
+    UnknownInstruction(String),
+    UnterminatedString(String),
+    UndefinedSymbol(String),
+    SymbolRedefined(String),
+    ValueOutOfRange(i32, u32),
+    DivisionByZero,
+    UnterminatedMacro(String),
+    MacroArguments(String, usize, usize),
+    MacroRecursion(String),
+    InMacro(String, (usize, usize), Box<ErrorKind>),
+    UnterminatedConditional,
+    UnmatchedConditional(String),
+    /// A second ELSE for the same IF.
+    DuplicateElse,
+    PhaseError(String),
+    IncludeNotFound(String),
+    IncludeCycle(String),
+    IncludeFailed(String, String),
+    PreviousDefinition(String),
+    CodeAfterEnd,
+    /// The location counter would go past FFFFH.
+    AddressOverflow,
 }
 
 impl Display for ErrorKind {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.write_str("ErrorKind: ")?;
+        self.write_message(f)
+    }
+}
+
+impl ErrorKind {
+    /// The message, without the `ErrorKind: ` prefix that `Display` adds.
+    pub fn message(&self) -> String {
+        struct Message<'a>(&'a ErrorKind);
+        impl Display for Message<'_> {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                self.0.write_message(f)
+            }
+        }
+        Message(self).to_string()
+    }
+
+    /// The error a macro expansion error was raised for, or the error itself.
+    pub fn root(&self) -> &ErrorKind {
+        match self {
+            ErrorKind::InMacro(_, _, error) => error.root(),
+            error => error,
+        }
+    }
+
+    fn write_message(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             ErrorKind::NumberError(number) => {
                 f.write_fmt(format_args!("Number out of bounds: {}", number))
@@ -26,24 +74,255 @@ impl Display for ErrorKind {
                 f.write_fmt(format_args!("Unexpected token: {}", found))
             }
             ErrorKind::UnexpectedToken(expected, found) => f.write_fmt(format_args!(
-                "Invalid Arguments: expected {:?}, found {:?}",
-                expected, found
+                "Expected {}, found {}",
+                one_of(expected),
+                found.describe()
+            )),
+            ErrorKind::UnknownInstruction(name) => {
+                f.write_fmt(format_args!("Unknown instruction or directive: {}", name))
+            }
+            ErrorKind::UnterminatedString(found) => {
+                f.write_fmt(format_args!("Unterminated string: {}", found))
+            }
+            ErrorKind::UndefinedSymbol(symbol) => {
+                f.write_fmt(format_args!("Undefined symbol: {}", symbol))
+            }
+            ErrorKind::SymbolRedefined(symbol) => {
+                f.write_fmt(format_args!("Symbol already defined: {}", symbol))
+            }
+            ErrorKind::ValueOutOfRange(value, bits) => {
+                f.write_fmt(format_args!("Value {} does not fit in {} bits", value, bits))
+            }
+            ErrorKind::DivisionByZero => f.write_str("Division by zero"),
+            ErrorKind::UnterminatedMacro(name) => {
+                f.write_fmt(format_args!("Macro {} has no matching ENDM", name))
+            }
+            ErrorKind::MacroArguments(name, expected, found) => f.write_fmt(format_args!(
+                "Macro {} takes {} arguments, found {}",
+                name, expected, found
             )),
-            ErrorKind::Eof => f.write_str("Reached end of file!"),
+            ErrorKind::MacroRecursion(name) => {
+                f.write_fmt(format_args!("Macro {} expands recursively", name))
+            }
+            ErrorKind::InMacro(name, call_site, error) => {
+                f.write_fmt(format_args!(
+                    "in expansion of macro {} invoked at {}:{}: ",
+                    name, call_site.0, call_site.1
+                ))?;
+                error.write_message(f)
+            }
+            ErrorKind::UnterminatedConditional => f.write_str("Conditional block has no matching ENDIF"),
+            ErrorKind::UnmatchedConditional(directive) => {
+                f.write_fmt(format_args!("{} without a matching IF", directive))
+            }
+            ErrorKind::DuplicateElse => f.write_str("IF already has an ELSE"),
+            ErrorKind::PhaseError(symbol) => f.write_fmt(format_args!(
+                "Phase error: {} is tested by a conditional before it is defined",
+                symbol
+            )),
+            ErrorKind::IncludeNotFound(name) => {
+                f.write_fmt(format_args!("Include file not found: {}", name))
+            }
+            ErrorKind::IncludeCycle(name) => {
+                f.write_fmt(format_args!("{} includes itself", name))
+            }
+            ErrorKind::IncludeFailed(name, reason) => {
+                f.write_fmt(format_args!("Could not read include file {}: {}", name, reason))
+            }
+            ErrorKind::PreviousDefinition(symbol) => {
+                f.write_fmt(format_args!("{} was first defined here", symbol))
+            }
+            ErrorKind::CodeAfterEnd => f.write_str("Code after END is ignored"),
+            ErrorKind::AddressOverflow => f.write_str("Code runs past FFFFH"),
         }
     }
 }
 
+/// `a, b or c` out of the kinds of `expected`, leaving out repeats.
+fn one_of(expected: &[TokenType]) -> String {
+    let mut kinds: Vec<&str> = vec![];
+    for token in expected {
+        if !kinds.contains(&token.kind()) {
+            kinds.push(token.kind());
+        }
+    }
+    match kinds.split_last() {
+        Some((last, [])) => last.to_string(),
+        Some((last, rest)) => format!("{} or {}", rest.join(", "), last),
+        None => "nothing".to_owned(),
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct ParseError {
     pub position: (usize, usize),
+    /// The file `position` refers to, `None` for source given as a string.
+    pub file: Option<Rc<str>>,
+    /// How many bytes of the source line the error covers from `position`, 0 when unknown.
+    pub length: usize,
     pub error: ErrorKind,
 }
 
+impl ParseError {
+    /// Attributes the error to `file` unless it already names one.
+    pub(crate) fn in_file(mut self, file: &Option<Rc<str>>) -> ParseError {
+        if self.file.is_none() {
+            self.file = file.clone();
+        }
+        self
+    }
+}
+
 impl Display for ParseError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("\nParse error at ")?;
+        if let Some(file) = &self.file {
+            f.write_fmt(format_args!("{}:", file))?;
+        }
         f.write_fmt(format_args!(
-            "\nParse error at {}:{}\nError kind: {}",
+            "{}:{}\nError kind: {}",
             self.position.0, self.position.1, self.error
         ))
     }
 }
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+    /// Extra context for the diagnostic before it, like where a symbol was first defined.
+    Note,
+}
+
+/// One message from an assembly run. Only errors keep the program from being assembled.
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: ParseError,
+    /// A likely fix, like the instruction or label a misspelt name was probably meant to be.
+    pub help: Option<String>,
+}
+
+impl Diagnostic {
+    pub fn new(severity: Severity, message: ParseError) -> Diagnostic {
+        Diagnostic { severity, message, help: None }
+    }
+
+    fn label(&self) -> &'static str {
+        match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+            Severity::Note => "note",
+        }
+    }
+
+    /// Renders the diagnostic with `line`, the text of the source line it points at, and the
+    /// span it covers underlined:
+    ///
+    /// ```text
+    /// error: Undefined symbol: LOPP
+    ///  --> 5:17
+    ///   |
+    /// 5 |         JMP LOPP
+    ///   |             ^^^^
+    ///   = help: did you mean `LOOP`?
+    /// ```
+    pub fn render(&self, line: Option<&str>) -> String {
+        let (number, column) = self.message.position;
+        let gutter = " ".repeat(number.to_string().len());
+        let mut output = format!("{}: ", self.label());
+        output += &self.message.error.message();
+        output += &format!("\n{}--> ", gutter);
+        if let Some(file) = &self.message.file {
+            output += &format!("{}:", file);
+        }
+        output += &format!("{}:{}\n", number, column);
+        if let Some(text) = line.filter(|_| column > 0) {
+            let mut start = (column - 1).min(text.len());
+            while !text.is_char_boundary(start) {
+                start -= 1;
+            }
+            // Keep tabs in the indentation so the carets line up with the text above them.
+            let indent: String = text[..start].chars().map(|c| if c == '\t' { '\t' } else { ' ' }).collect();
+            let carets = "^".repeat(span_width(&text[start..], self.message.length));
+            output += &format!("{} |\n{} | {}\n", gutter, number, text);
+            output += &format!("{} | {}{}\n", gutter, indent, carets);
+        }
+        if let Some(help) = &self.help {
+            output += &format!("{} = help: {}\n", gutter, help);
+        }
+        output
+    }
+}
+
+/// How many carets to draw under `rest`, the source line from the start of the span. Errors that
+/// do not know their length underline the word they point at.
+fn span_width(rest: &str, length: usize) -> usize {
+    let length = match length {
+        0 => rest.find(|c: char| !(c.is_ascii_alphanumeric() || "?@$_".contains(c))).unwrap_or(rest.len()),
+        length => length,
+    };
+    length.min(rest.len()).max(1)
+}
+
+/// The number of single character insertions, deletions and substitutions that turn `a` into `b`.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, a) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, b) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(a != *b);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+/// The candidate closest to `word`, if any is close enough to be a likely typo: a third of the
+/// word's letters may be wrong, and at least one. Ties go to the candidate that sorts first.
+pub(crate) fn closest<'a>(word: &str, candidates: impl Iterator<Item = &'a str>) -> Option<&'a str> {
+    let limit = (word.len() / 3).max(1);
+    candidates
+        .filter(|candidate| *candidate != word)
+        .map(|candidate| (edit_distance(word, candidate), candidate))
+        .filter(|(distance, _)| *distance <= limit)
+        .min()
+        .map(|(_, candidate)| candidate)
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.label())?;
+        f.write_str(" at ")?;
+        if let Some(file) = &self.message.file {
+            f.write_fmt(format_args!("{}:", file))?;
+        }
+        f.write_fmt(format_args!("{}:{}: ", self.message.position.0, self.message.position.1))?;
+        self.message.error.write_message(f)
+    }
+}
+
+/// Errors from writing an image out in one of the `output` formats.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OutputError {
+    /// The image needs `.0` bytes but the output was limited to `.1`.
+    TooLarge(usize, usize),
+    /// A segment starts at `.0`, before the output's origin `.1`.
+    BelowOrigin(u16, u16),
+}
+
+impl Display for OutputError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OutputError::TooLarge(needed, size) => {
+                f.write_fmt(format_args!("Image needs {} bytes but only {} fit", needed, size))
+            }
+            OutputError::BelowOrigin(address, origin) => f.write_fmt(format_args!(
+                "Code at {:04X}H is below the output origin {:04X}H",
+                address, origin
+            )),
+        }
+    }
+}
@@ -0,0 +1,202 @@
+use crate::assembler::SymbolTable;
+use crate::error::{ ErrorKind, ParseError };
+use crate::token::{ Operator, Token, TokenType };
+
+/// An operand expression. `$` is replaced by the location counter while parsing, so only
+/// symbols are left to resolve later.
+#[derive(Debug, Clone)]
+pub enum Expr {
+    Number(i32),
+    Symbol(String, (usize, usize)),
+    Unary(Operator, Box<Expr>),
+    Binary(Operator, Box<Expr>, Box<Expr>),
+}
+
+fn precedence(operator: &Operator) -> Option<u8> {
+    match operator {
+        Operator::Or => Some(1),
+        Operator::Xor => Some(2),
+        Operator::And => Some(3),
+        Operator::Equal
+        | Operator::NotEqual
+        | Operator::Less
+        | Operator::LessEqual
+        | Operator::Greater
+        | Operator::GreaterEqual => Some(4),
+        Operator::ShiftLeft | Operator::ShiftRight => Some(5),
+        Operator::Plus | Operator::Minus => Some(6),
+        Operator::Multiply | Operator::Divide | Operator::Modulo => Some(7),
+        Operator::Not | Operator::High | Operator::Low => None,
+    }
+}
+
+/// The tokens an operand can start with, for error messages.
+fn primary_tokens() -> Vec<TokenType> {
+    vec![TokenType::Number(0), TokenType::Label("".to_owned()), TokenType::Location, TokenType::OpenParen]
+}
+
+fn parse_primary(iterator: &mut std::slice::Iter<Token>, location: u16) -> Result<Expr, ParseError> {
+    let token = match iterator.next() {
+        Some(token) => token,
+        None => {
+            return Err(ParseError {
+                position: (0, 0),
+                file: None,
+                length: 0,
+                error: ErrorKind::UnexpectedToken(primary_tokens(), TokenType::End),
+            })
+        }
+    };
+    match &token.token {
+        TokenType::Number(number) => Ok(Expr::Number(*number as i32)),
+        TokenType::Location => Ok(Expr::Number(location as i32)),
+        TokenType::Label(symbol) => Ok(Expr::Symbol(symbol.clone(), token.position)),
+        // Character constants: one or two characters, the first one in the high byte.
+        TokenType::Str(text) if !text.is_empty() && text.len() <= 2 => Ok(Expr::Number(
+            text.bytes().fold(0, |value, char| value << 8 | char as i32),
+        )),
+        TokenType::Str(text) => Err(ParseError {
+            position: token.position,
+            file: None,
+            length: token.length,
+            error: ErrorKind::InvalidArguments("Character constant".to_owned(), format!("'{}'", text)),
+        }),
+        TokenType::Operator(operator @ (Operator::Plus | Operator::Minus | Operator::Not
+            | Operator::High | Operator::Low)) => Ok(Expr::Unary(
+            operator.clone(),
+            Box::new(parse_primary(iterator, location)?),
+        )),
+        TokenType::OpenParen => {
+            let expr = parse_expression(iterator, location)?;
+            match iterator.next() {
+                Some(Token { token: TokenType::CloseParen, .. }) => Ok(expr),
+                Some(token) => Err(ParseError {
+                    position: token.position,
+                    file: None,
+                    length: token.length,
+                    error: ErrorKind::UnexpectedToken(vec![TokenType::CloseParen], token.token.clone()),
+                }),
+                None => Err(ParseError {
+                    position: (0, 0),
+                    file: None,
+                    length: 0,
+                    error: ErrorKind::UnexpectedToken(vec![TokenType::CloseParen], TokenType::End),
+                }),
+            }
+        }
+        found => Err(ParseError {
+            position: token.position,
+            file: None,
+            length: token.length,
+            error: ErrorKind::UnexpectedToken(primary_tokens(), found.clone()),
+        }),
+    }
+}
+
+fn parse_binary(
+    iterator: &mut std::slice::Iter<Token>,
+    location: u16,
+    min_precedence: u8,
+) -> Result<Expr, ParseError> {
+    let mut lhs = parse_primary(iterator, location)?;
+    while let Some(Token { token: TokenType::Operator(operator), .. }) = iterator.as_slice().first() {
+        let precedence = match precedence(operator) {
+            Some(precedence) if precedence > min_precedence => precedence,
+            _ => break,
+        };
+        let operator = operator.clone();
+        iterator.next();
+        let rhs = parse_binary(iterator, location, precedence)?;
+        lhs = Expr::Binary(operator, Box::new(lhs), Box::new(rhs));
+    }
+    Ok(lhs)
+}
+
+/// Parses an expression, substituting `location` for `$`.
+pub fn parse_expression(iterator: &mut std::slice::Iter<Token>, location: u16) -> Result<Expr, ParseError> {
+    parse_binary(iterator, location, 0)
+}
+
+/// Whether `value` fits in `bits` bits, either as an unsigned value or as a negative
+/// two's-complement one: -128 to 255 for a byte, so `-1` and `0FFH` are both valid.
+pub fn fits(value: i32, bits: u32) -> bool {
+    (-(1 << (bits - 1))..=(1 << bits) - 1).contains(&value)
+}
+
+fn truth(condition: bool) -> i32 {
+    if condition {
+        -1
+    } else {
+        0
+    }
+}
+
+impl Expr {
+    /// True once every symbol the expression refers to has been defined.
+    pub fn is_resolved(&self, symbol_table: &SymbolTable) -> bool {
+        match self {
+            Expr::Number(_) => true,
+            Expr::Symbol(symbol, _) => symbol_table.contains_key(symbol),
+            Expr::Unary(_, operand) => operand.is_resolved(symbol_table),
+            Expr::Binary(_, lhs, rhs) => lhs.is_resolved(symbol_table) && rhs.is_resolved(symbol_table),
+        }
+    }
+
+    /// Evaluates the expression; `position` is reported for errors that have no better location.
+    pub fn evaluate(&self, symbol_table: &SymbolTable, position: (usize, usize)) -> Result<i32, ParseError> {
+        match self {
+            Expr::Number(number) => Ok(*number),
+            Expr::Symbol(symbol, position) => match symbol_table.get(symbol) {
+                Some(value) => Ok(*value),
+                None => Err(ParseError {
+                    position: *position,
+                    file: None,
+                    length: symbol.len(),
+                    error: ErrorKind::UndefinedSymbol(symbol.clone()),
+                }),
+            },
+            Expr::Unary(operator, operand) => {
+                let value = operand.evaluate(symbol_table, position)?;
+                Ok(match operator {
+                    Operator::Minus => value.wrapping_neg(),
+                    Operator::Not => !value,
+                    Operator::High => (value >> 8) & 0xff,
+                    Operator::Low => value & 0xff,
+                    _ => value,
+                })
+            }
+            Expr::Binary(operator, lhs, rhs) => {
+                let lhs = lhs.evaluate(symbol_table, position)?;
+                let rhs = rhs.evaluate(symbol_table, position)?;
+                Ok(match operator {
+                    Operator::Plus => lhs.wrapping_add(rhs),
+                    Operator::Minus => lhs.wrapping_sub(rhs),
+                    Operator::Multiply => lhs.wrapping_mul(rhs),
+                    Operator::Divide | Operator::Modulo if rhs == 0 => {
+                        return Err(ParseError {
+                            position,
+                            file: None,
+                            length: 0,
+                            error: ErrorKind::DivisionByZero,
+                        });
+                    }
+                    Operator::Divide => lhs.wrapping_div(rhs),
+                    Operator::Modulo => lhs.wrapping_rem(rhs),
+                    Operator::ShiftLeft => lhs.checked_shl(rhs as u32).unwrap_or(0),
+                    Operator::ShiftRight => lhs.checked_shr(rhs as u32).unwrap_or(0),
+                    Operator::And => lhs & rhs,
+                    Operator::Or => lhs | rhs,
+                    Operator::Xor => lhs ^ rhs,
+                    // Comparisons yield 0FFFFH for true so they combine with AND, OR and NOT.
+                    Operator::Equal => truth(lhs == rhs),
+                    Operator::NotEqual => truth(lhs != rhs),
+                    Operator::Less => truth(lhs < rhs),
+                    Operator::LessEqual => truth(lhs <= rhs),
+                    Operator::Greater => truth(lhs > rhs),
+                    Operator::GreaterEqual => truth(lhs >= rhs),
+                    Operator::Not | Operator::High | Operator::Low => unreachable!("unary operator"),
+                })
+            }
+        }
+    }
+}
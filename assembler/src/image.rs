@@ -0,0 +1,58 @@
+/// A contiguous run of assembled bytes that starts at `origin`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Segment {
+    pub origin: u16,
+    pub bytes: Vec<u8>,
+}
+
+impl Segment {
+    pub fn new(origin: u16) -> Segment {
+        Segment { origin, bytes: vec![] }
+    }
+
+    /// Address one past the last byte of the segment.
+    pub fn end(&self) -> u16 {
+        self.origin.wrapping_add(self.bytes.len() as u16)
+    }
+}
+
+/// The output of the assembler: every segment produced by `ORG`/`DS` along with the start
+/// address given to `END`, if any.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Image {
+    pub segments: Vec<Segment>,
+    pub entry: Option<u16>,
+}
+
+impl Image {
+    pub fn push(&mut self, segment: Segment) {
+        if !segment.bytes.is_empty() {
+            self.segments.push(segment);
+        }
+    }
+
+    /// Address execution should begin at: the `END` operand, otherwise the first origin.
+    pub fn start_address(&self) -> u16 {
+        self.entry
+            .or_else(|| self.segments.first().map(|segment| segment.origin))
+            .unwrap_or(0)
+    }
+
+    pub fn lowest_address(&self) -> u16 {
+        self.segments.iter().map(|segment| segment.origin).min().unwrap_or(0)
+    }
+
+    /// Flattens the image into a single buffer starting at `lowest_address`, zero filling gaps.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let base = self.lowest_address() as usize;
+        let mut bytes = vec![];
+        for segment in &self.segments {
+            let offset = segment.origin as usize - base;
+            if bytes.len() < offset + segment.bytes.len() {
+                bytes.resize(offset + segment.bytes.len(), 0);
+            }
+            bytes[offset..offset + segment.bytes.len()].copy_from_slice(&segment.bytes);
+        }
+        bytes
+    }
+}
@@ -0,0 +1,144 @@
+use std::fs;
+use std::path::{ Path, PathBuf };
+use std::rc::Rc;
+
+use crate::error::{ ErrorKind, ParseError };
+use crate::lexer::tokenize;
+use crate::listing::SourceLine;
+use crate::token::{ Token, TokenStream, TokenType };
+
+struct Loader<'a> {
+    include_dirs: &'a [PathBuf],
+    /// Canonical paths of the files currently being read, outermost first.
+    stack: Vec<PathBuf>,
+    /// Every line read so far, for the listing.
+    sources: Vec<SourceLine>,
+    /// Lines that could not be lexed and INCLUDE directives that failed, which are left out of
+    /// the tokens.
+    errors: Vec<ParseError>,
+}
+
+fn error_at(token: &Token, error: ErrorKind) -> ParseError {
+    ParseError {
+        position: token.position,
+        file: token.file.clone(),
+        length: token.length,
+        error,
+    }
+}
+
+impl Loader<'_> {
+    /// Looks for `name` next to the including file first, then in each include directory.
+    fn resolve(&self, name: &str, base: Option<&Path>) -> Option<PathBuf> {
+        let path = Path::new(name);
+        if path.is_absolute() {
+            return path.is_file().then(|| path.to_path_buf());
+        }
+        base.into_iter()
+            .chain(self.include_dirs.iter().map(PathBuf::as_path))
+            .map(|directory| directory.join(path))
+            .find(|candidate| candidate.is_file())
+    }
+
+    /// Records `lines[from..to]` for the listing.
+    fn list(&mut self, lines: &[&str], file: &Option<Rc<str>>, from: usize, to: usize) {
+        self.sources.extend(lines[from..to].iter().enumerate().map(|(index, text)| SourceLine {
+            file: file.clone(),
+            number: from + index + 1,
+            text: text.to_string(),
+        }));
+    }
+
+    fn read(
+        &mut self,
+        source: &str,
+        file: Option<Rc<str>>,
+        base: Option<&Path>,
+        output: &mut Vec<Token>,
+    ) {
+        let (tokens, errors) = tokenize(source);
+        self.errors.extend(errors.into_iter().map(|error| error.in_file(&file)));
+        let tokens = tokens.tokens;
+        let lines: Vec<&str> = source.lines().collect();
+        let mut listed = 0;
+        let mut iterator = tokens.into_iter().map(|token| Token { file: file.clone(), ..token }).peekable();
+        while let Some(token) = iterator.next() {
+            if !matches!(&token.token, TokenType::Operation(operation) if operation == "INCLUDE") {
+                output.push(token);
+                continue;
+            }
+            let line = token.position.0;
+            let result = match iterator.next_if(|next| next.position.0 == line) {
+                Some(Token { token: TokenType::Str(name), .. }) => match self.resolve(&name, base) {
+                    Some(path) => {
+                        let listed_to = line.min(lines.len());
+                        self.list(&lines, &file, listed, listed_to);
+                        listed = listed_to;
+                        self.include(&path, &name, &token, output)
+                    }
+                    None => Err(error_at(&token, ErrorKind::IncludeNotFound(name))),
+                },
+                Some(found) => Err(error_at(
+                    &found,
+                    ErrorKind::UnexpectedToken(vec![TokenType::Str("".to_owned())], found.token.clone()),
+                )),
+                None => Err(error_at(
+                    &token,
+                    ErrorKind::UnexpectedToken(vec![TokenType::Str("".to_owned())], TokenType::End),
+                )),
+            };
+            if let Err(error) = result {
+                // Leave out the rest of the statement, like a line that fails to lex.
+                self.errors.push(error);
+                while iterator.next_if(|next| next.position.0 == line).is_some() {}
+            }
+        }
+        self.list(&lines, &file, listed, lines.len());
+    }
+
+    fn include(
+        &mut self,
+        path: &Path,
+        name: &str,
+        directive: &Token,
+        output: &mut Vec<Token>,
+    ) -> Result<(), ParseError> {
+        let failed = |error: std::io::Error| {
+            error_at(directive, ErrorKind::IncludeFailed(name.to_owned(), error.to_string()))
+        };
+        let canonical = fs::canonicalize(path).map_err(failed)?;
+        if self.stack.contains(&canonical) {
+            return Err(error_at(directive, ErrorKind::IncludeCycle(name.to_owned())));
+        }
+        let source = fs::read_to_string(path).map_err(failed)?;
+        self.stack.push(canonical);
+        self.read(&source, Some(path.display().to_string().into()), path.parent(), output);
+        self.stack.pop();
+        Ok(())
+    }
+}
+
+/// Tokenizes `source` and splices in the tokens of every `INCLUDE "file"` in place of the
+/// directive. Every token remembers the file it came from. `path` is where `source` was read
+/// from, if anywhere; relative includes are looked up next to it before `include_dirs`.
+///
+/// Also returns every source line in the order it was read, for the listing, and the errors of
+/// the lines that could not be lexed or INCLUDE directives that failed. Those lines are left out
+/// of the tokens, and the rest of the files are still read.
+///
+/// Includes are resolved before macros and conditionals, so a file included inside a false IF
+/// block is still read (and still counts towards include cycles).
+pub fn load(
+    source: &str,
+    path: Option<&Path>,
+    include_dirs: &[PathBuf],
+) -> (TokenStream, Vec<SourceLine>, Vec<ParseError>) {
+    let mut loader = Loader { include_dirs, stack: vec![], sources: vec![], errors: vec![] };
+    if let Some(canonical) = path.and_then(|path| fs::canonicalize(path).ok()) {
+        loader.stack.push(canonical);
+    }
+    let file = path.map(|path| Rc::from(path.display().to_string()));
+    let mut tokens = vec![];
+    loader.read(source, file, path.and_then(Path::parent), &mut tokens);
+    (TokenStream { tokens }, loader.sources, loader.errors)
+}
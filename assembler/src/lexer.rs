@@ -1,15 +1,26 @@
-use crate::token::{ TokenType, Token, TokenStream, Register };
+use crate::token::{ TokenType, Token, TokenStream, Register, Operator };
 use crate::error::{ ParseError, ErrorKind };
 
-static KEYWORDS: &[&str] = &[
+pub(crate) static KEYWORDS: &[&str] = &[
     "ADD", "ACI", "ADC", "ADI", "ANA", "ANI", "CALL", "CC", "CM", "CMA", "CMC", "CMP", "CNC",
     "CNZ", "CP", "CPE", "CPI", "CPO", "CZ", "DAA", "DAD", "DCR", "DCX", "DI", "EI", "HLT", "IN",
     "INR", "INX", "JC", "JNC", "JM", "JMP", "JNZ", "JP", "JPE", "JPO", "JZ", "LDA", "LDAX", "LHLD",
-    "LXI", "MOV", "MVI", "NOP", "ORA", "ORI", "OUT", "PCHL", "POP", "POP", "PUSH", "RAL", "RAR",
+    "LXI", "MOV", "MVI", "NOP", "ORA", "ORI", "OUT", "PCHL", "POP", "PUSH", "RAL", "RAR",
     "RC", "RET", "RIM", "RLC", "RM", "RNC", "RNZ", "RP", "RPE", "RPO", "RRC", "RST", "RZ", "SBB",
     "SBI", "SHLD", "SIM", "SPHL", "STA", "STAX", "STC", "SUB", "SUI", "XCHG", "XRA", "XRI", "XTHL",
+    "ORG", "DB", "DW", "DS", "EQU", "SET", "MACRO", "ENDM", "LOCAL", "IF", "IFDEF", "IFNDEF",
+    "ELSE", "ENDIF", "INCLUDE",
 ];
 
+/// Instructions the 8085 executes but Intel never documented. They lex as labels unless
+/// `Options::undocumented` is set.
+pub(crate) static UNDOCUMENTED: &[&str] = &[
+    "DSUB", "ARHL", "RDEL", "LDHI", "LDSI", "RSTV", "SHLX", "JNK", "LHLX", "JK",
+];
+
+// `END` is deliberately left out of KEYWORDS: plenty of existing programs use it as a label
+// (`JZ END`), so the parser only treats it as a directive when it starts a statement.
+
 fn is_valid_identifier(lexeme: &str) -> bool {
     if lexeme.len() > 6 {
         return false;
@@ -30,61 +41,152 @@ fn is_keyword(token: &str) -> bool {
     KEYWORDS.contains(&token)
 }
 
-fn is_numeric(lexeme: &str) -> bool {
-    for char in lexeme.as_bytes() {
-        if *char < b'0' || *char > b'9' {
-            return false;
-        }
+fn word_operator(lexeme: &str) -> Option<Operator> {
+    match lexeme {
+        "MOD" => Some(Operator::Modulo),
+        "SHL" => Some(Operator::ShiftLeft),
+        "SHR" => Some(Operator::ShiftRight),
+        "AND" => Some(Operator::And),
+        "OR" => Some(Operator::Or),
+        "XOR" => Some(Operator::Xor),
+        "NOT" => Some(Operator::Not),
+        "HIGH" => Some(Operator::High),
+        "LOW" => Some(Operator::Low),
+        "EQ" => Some(Operator::Equal),
+        "NE" => Some(Operator::NotEqual),
+        "LT" => Some(Operator::Less),
+        "LE" => Some(Operator::LessEqual),
+        "GT" => Some(Operator::Greater),
+        "GE" => Some(Operator::GreaterEqual),
+        _ => None,
+    }
+}
+
+fn symbol_operator(char: u8) -> Option<TokenType> {
+    match char {
+        b'+' => Some(TokenType::Operator(Operator::Plus)),
+        b'-' => Some(TokenType::Operator(Operator::Minus)),
+        b'*' => Some(TokenType::Operator(Operator::Multiply)),
+        b'/' => Some(TokenType::Operator(Operator::Divide)),
+        b'%' => Some(TokenType::Operator(Operator::Modulo)),
+        b'&' => Some(TokenType::Operator(Operator::And)),
+        b'|' => Some(TokenType::Operator(Operator::Or)),
+        b'^' => Some(TokenType::Operator(Operator::Xor)),
+        b'~' => Some(TokenType::Operator(Operator::Not)),
+        b'(' => Some(TokenType::OpenParen),
+        b')' => Some(TokenType::CloseParen),
+        _ => None,
     }
-    true
+}
+
+/// Operators spelled with `<`, `>`, `=` or `!`, which may take a second character. Returns the
+/// operator and whether `next` was consumed.
+fn compound_operator(char: u8, next: Option<&u8>) -> Option<(Operator, bool)> {
+    match (char, next) {
+        (b'<', Some(b'<')) => Some((Operator::ShiftLeft, true)),
+        (b'<', Some(b'=')) => Some((Operator::LessEqual, true)),
+        (b'<', Some(b'>')) => Some((Operator::NotEqual, true)),
+        (b'<', _) => Some((Operator::Less, false)),
+        (b'>', Some(b'>')) => Some((Operator::ShiftRight, true)),
+        (b'>', Some(b'=')) => Some((Operator::GreaterEqual, true)),
+        (b'>', _) => Some((Operator::Greater, false)),
+        (b'=', Some(b'=')) => Some((Operator::Equal, true)),
+        (b'=', _) => Some((Operator::Equal, false)),
+        (b'!', Some(b'=')) => Some((Operator::NotEqual, true)),
+        _ => None,
+    }
+}
+
+/// Parses a numeric literal. Hex needs an `H` suffix, binary `B`, octal `O` or `Q`, and decimal
+/// may carry an optional `D`. Returns `None` when the lexeme is not a number at all.
+fn parse_number(lexeme: &str) -> Option<Result<u16, ()>> {
+    let (digits, radix) = match lexeme.as_bytes()[lexeme.len() - 1] {
+        b'H' | b'K' if lexeme.len() > 1 => (&lexeme[..lexeme.len() - 1], 16),
+        _ if !lexeme.as_bytes()[0].is_ascii_digit() => return None,
+        b'B' => (&lexeme[..lexeme.len() - 1], 2),
+        b'O' | b'Q' => (&lexeme[..lexeme.len() - 1], 8),
+        b'D' => (&lexeme[..lexeme.len() - 1], 10),
+        _ => (lexeme, 10),
+    };
+    match u16::from_str_radix(digits, radix) {
+        Ok(number) => Some(Ok(number)),
+        // Anything starting with a digit has to be a number; `FFH`-style hex without the leading
+        // zero is only a number if it parses, otherwise it is left for the identifier rules.
+        Err(_) if lexeme.as_bytes()[0].is_ascii_digit() => Some(Err(())),
+        Err(_) => None,
+    }
+}
+
+fn make_string(line_number: usize, col_num: usize, lexeme: &str) -> Token {
+    Token {
+        position: (line_number, col_num),
+        length: lexeme.len(),
+        token: TokenType::Str(lexeme[1..lexeme.len() - 1].to_owned()),
+        expansion: None,
+        file: None,
+    }
+}
+
+fn unterminated_string(line_number: usize, col_num: usize, lexeme: &str) -> ParseError {
+    ParseError {
+        position: (line_number, col_num),
+        file: None,
+        length: lexeme.len(),
+        error: ErrorKind::UnterminatedString(lexeme.to_owned()),
+    }
+}
+
+/// Whether `name` lexes as a label, so source can refer to a symbol by it.
+pub(crate) fn is_symbol_name(name: &str) -> bool {
+    matches!(make_token(0, 0, name), Ok(Token { token: TokenType::Label(_), .. }))
 }
 
 fn make_token(line_number: usize, col_num: usize, lexeme: &str) -> Result<Token, ParseError> {
-    if (lexeme.to_lowercase().ends_with('h') || lexeme.to_lowercase().ends_with('k')) && lexeme.len() > 1 {
-        if let Ok(number) = u16::from_str_radix(&lexeme[..lexeme.len() - 1], 16) {
-            if number < 256 {
-                return Ok(Token {
-                    position: (line_number, col_num),
-                    token: TokenType::U8(number as u8),
-                });
-            } else {
-                return Ok(Token {
-                    position: (line_number, col_num),
-                    token: TokenType::U16(number),
-                });
-            }
-        } else {
-            return Err(ParseError {
-                position: (line_number, col_num),
-                error: ErrorKind::NumberError(lexeme.to_owned()),
-            });
-        }
-    } else if let Ok(number) = u16::from_str_radix(&lexeme, 10) {
-        if number < 256 {
-            return Ok(Token {
+    if let Some(number) = parse_number(lexeme) {
+        return match number {
+            Ok(number) => Ok(Token {
                 position: (line_number, col_num),
-                token: TokenType::U8(number as u8),
-            });
-        } else {
-            return Ok(Token {
+                length: lexeme.len(),
+                token: TokenType::Number(number),
+                expansion: None,
+                file: None,
+            }),
+            Err(()) => Err(ParseError {
                 position: (line_number, col_num),
-                token: TokenType::U16(number),
-            });
-        }
-    } else if is_numeric(lexeme) {
-        return Err(ParseError {
+                file: None,
+                length: lexeme.len(),
+                error: ErrorKind::NumberError(lexeme.to_owned()),
+            }),
+        };
+    } else if lexeme == "$" {
+        return Ok(Token {
             position: (line_number, col_num),
-            error: ErrorKind::NumberError(lexeme.to_owned()),
+            length: lexeme.len(),
+            token: TokenType::Location,
+            expansion: None,
+            file: None,
         });
-    } else if is_keyword(&lexeme) {
+    } else if let Some(operator) = word_operator(lexeme) {
         return Ok(Token {
             position: (line_number, col_num),
+            length: lexeme.len(),
+            token: TokenType::Operator(operator),
+            expansion: None,
+            file: None,
+        });
+    } else if is_keyword(lexeme) {
+        return Ok(Token {
+            position: (line_number, col_num),
+            length: lexeme.len(),
             token: TokenType::Operation(lexeme.to_owned()),
+            expansion: None,
+            file: None,
         });
     } else if ["A", "B", "C", "D", "E", "H", "L", "M", "SP", "PSW"].contains(&lexeme.to_uppercase().as_str())
     {
         return Ok(Token {
             position: (line_number, col_num),
+            length: lexeme.len(),
             token: match lexeme {
                 "A" => TokenType::Register(Register::A),
                 "B" => TokenType::Register(Register::B),
@@ -98,68 +200,155 @@ fn make_token(line_number: usize, col_num: usize, lexeme: &str) -> Result<Token,
                 "PSW" => TokenType::Register(Register::PSW),
                 _ => unreachable!("this is not supposed to happen!"),
             },
+            expansion: None,
+            file: None,
         });
     } else if is_valid_identifier(lexeme) {
         return Ok(Token {
             position: (line_number, col_num),
+            length: lexeme.len(),
             token: TokenType::Label(lexeme.to_owned()),
+            expansion: None,
+            file: None,
         });
     }
     Err(ParseError {
         position: (line_number, col_num),
+        file: None,
+        length: lexeme.len(),
         error: ErrorKind::UnexpectedLexeme(lexeme.to_owned()),
     })
 }
 
-pub fn tokenize(code: &str) -> Result<TokenStream, ParseError> {
+/// Drops the tokens already read from a line that failed to lex, so the parser sees an empty
+/// line rather than half a statement, and records the error.
+fn discard_line(tokens: &mut Vec<Token>, line_start: usize, errors: &mut Vec<ParseError>, error: ParseError) {
+    tokens.truncate(line_start);
+    errors.push(error);
+}
+
+/// Splits `source` into tokens. A line that cannot be lexed is left out and its error returned
+/// alongside the tokens, so every such line in the file gets reported.
+pub fn tokenize(source: &str) -> (TokenStream, Vec<ParseError>) {
     let mut start = 0usize;
     let mut line_number = 1;
     let mut tokens = vec![];
+    let mut errors = vec![];
+    // Where the tokens of the current line start, to drop them if the line fails to lex.
+    let mut line_start = 0usize;
     let mut col_num = 1usize;
     let mut last_col = 0usize;
     let mut comment = false;
-    let code = code.to_uppercase();
+    let mut quote: Option<u8> = None;
+    let mut skip = false;
+    // ASCII-only uppercasing keeps byte offsets identical, so string literals can be sliced
+    // out of the original source with their case intact.
+    let code = source.to_ascii_uppercase();
     for (i, char) in code.as_bytes().iter().enumerate() {
+        if let Some(delimiter) = quote {
+            if *char == delimiter {
+                tokens.push(make_string(line_number, col_num, &source[start..=i]));
+                quote = None;
+                start = i + 1;
+                col_num = (start - last_col) + 1;
+                continue;
+            } else if *char != b'\n' {
+                continue;
+            }
+            let error = unterminated_string(line_number, col_num, source[start..i].trim_end());
+            discard_line(&mut tokens, line_start, &mut errors, error);
+            quote = None;
+            comment = true;
+        }
         if comment {
-            if [b'\n', b'\r'].contains(&char) {
+            if *char == b'\n' {
                 comment = false;
                 line_number += 1;
                 last_col = i + 1;
+                line_start = tokens.len();
                 start = i + 1;
+                col_num = 1;
             }
             continue;
         }
-        if *char == b';' {
-            if start == i {
-                start = i + 1;
-                col_num = (start - last_col) + 1;
-            }
-            else {
-                tokens.push(make_token(line_number, col_num, &code[start..i])?);
-            }
-            comment = true;
+        if (*char == b'\'' || *char == b'"') && start == i {
+            quote = Some(*char);
             continue;
         }
-        if [b' ', b'\t', b',', b':', b'\n', b'\r', b';'].contains(&char) {
-            if start == i {
-                start = i + 1;
-                col_num = (start - last_col) + 1;
-                continue;
+        if skip {
+            skip = false;
+            start = i + 1;
+            col_num = (start - last_col) + 1;
+            continue;
+        }
+        let operator = symbol_operator(*char);
+        if operator.is_some()
+            || [b' ', b'\t', b',', b':', b'\n', b'\r', b';', b'<', b'>', b'=', b'!'].contains(char)
+        {
+            if start != i {
+                match make_token(line_number, col_num, &code[start..i]) {
+                    Ok(token) => tokens.push(token),
+                    Err(error) => {
+                        discard_line(&mut tokens, line_start, &mut errors, error);
+                        // Skip the rest of the line, unless this was its end.
+                        if *char != b'\n' {
+                            comment = true;
+                            continue;
+                        }
+                    }
+                }
             }
-            tokens.push(make_token(line_number, col_num, &code[start..i])?);
-            if *char == b',' {
+            if let Some(operator) = operator {
+                tokens.push(Token {
+                    position: (line_number, (i - last_col) + 1),
+                    length: 1,
+                    token: operator,
+                    expansion: None,
+                    file: None,
+                });
+            } else if [b'<', b'>', b'=', b'!'].contains(char) {
+                let (operator, consumed) = match compound_operator(*char, code.as_bytes().get(i + 1)) {
+                    Some(operator) => operator,
+                    None => {
+                        let error = ParseError {
+                            position: (line_number, (i - last_col) + 1),
+                            file: None,
+                            length: 1,
+                            error: ErrorKind::UnexpectedLexeme((*char as char).to_string()),
+                        };
+                        discard_line(&mut tokens, line_start, &mut errors, error);
+                        comment = true;
+                        continue;
+                    }
+                };
+                tokens.push(Token {
+                    position: (line_number, (i - last_col) + 1),
+                    length: 1 + consumed as usize,
+                    token: TokenType::Operator(operator),
+                    expansion: None,
+                    file: None,
+                });
+                skip = consumed;
+            } else if *char == b',' {
                 tokens.push(Token {
                     token: TokenType::Comma,
                     position: (line_number, (i - last_col) + 1),
+                    length: 1,
+                    expansion: None,
+                    file: None,
                 });
             } else if *char == b':' {
                 tokens.push(Token {
                     position: (line_number, (i - last_col) + 1),
+                    length: 1,
                     token: TokenType::Colon,
+                    expansion: None,
+                    file: None,
                 });
-            } else if *char == b'\n' || *char == b'\r' {
+            } else if *char == b'\n' {
                 line_number += 1;
                 last_col = i + 1;
+                line_start = tokens.len();
             } else if *char == b';' {
                 comment = true;
             }
@@ -167,5 +356,14 @@ pub fn tokenize(code: &str) -> Result<TokenStream, ParseError> {
             col_num = (start - last_col) + 1;
         }
     }
-    Ok(TokenStream { tokens })
+    if quote.is_some() {
+        let error = unterminated_string(line_number, col_num, &source[start..]);
+        discard_line(&mut tokens, line_start, &mut errors, error);
+    } else if !comment && start < code.len() {
+        match make_token(line_number, col_num, &code[start..]) {
+            Ok(token) => tokens.push(token),
+            Err(error) => discard_line(&mut tokens, line_start, &mut errors, error),
+        }
+    }
+    (TokenStream { tokens }, errors)
 }
@@ -1,25 +1,693 @@
-mod error;
+pub mod disassembler;
+pub mod error;
+mod expression;
+mod include;
 mod lexer;
+pub mod listing;
+mod macros;
+pub mod output;
 pub mod assembler;
+pub mod image;
+pub mod timing;
 mod token;
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    static TEST_LOC: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/../tests/");
+
     #[test]
     fn test_assembler() -> std::io::Result<()> {
-        let result = assembler::assemble_file("code.asm")?;
+        let result = assembler::assemble_file(TEST_LOC.to_owned() + "add.asm")?;
         match result {
-            Ok(bytes) => {
+            Ok(image) => {
                 print!("{{");
-                for byte in bytes {
+                for byte in image.to_bytes() {
                     print!(" {:0x} ", byte);
                 }
                 println!("}}");
             }
-            Err(parse_error) => println!("{}", parse_error)
+            Err(parse_error) => panic!("{parse_error}")
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_directives() {
+        let image = match assembler::assemble("
+            PORT    EQU 05H
+            COUNT   SET 2
+            COUNT   SET 3
+                    ORG 2000H
+            START:  MVI A, 3
+                    OUT 05H
+                    JMP START
+                    ORG 2050H
+            TABLE:  DB 1, 2, 'Hi', COUNT, PORT
+                    DS 2
+                    DW TABLE, 1234H
+                    END START
+                    HLT
+        ") {
+            Ok(image) => image,
+            Err(parse_error) => panic!("{parse_error}")
+        };
+        assert_eq!(image.entry, Some(0x2000));
+        assert_eq!(image.segments.len(), 3);
+        assert_eq!(image.segments[0].origin, 0x2000);
+        assert_eq!(image.segments[0].bytes, [0x3e, 0x03, 0xd3, 0x05, 0xc3, 0x00, 0x20]);
+        assert_eq!(image.segments[1].origin, 0x2050);
+        assert_eq!(image.segments[1].bytes, [1, 2, b'H', b'i', 3, 5]);
+        assert_eq!(image.segments[2].origin, 0x2058);
+        assert_eq!(image.segments[2].bytes, [0x50, 0x20, 0x34, 0x12]);
+    }
+
+    #[test]
+    fn test_address_overflow() {
+        for source in ["ORG 0FFFFH\nJMP 0", "DS 0FFFFH\nDS 2", "ORG 0FFFFH\nDB 1,2,3", "ORG 0FFFFH\nDW 0"] {
+            assert!(matches!(
+                assembler::assemble(source),
+                Err(error::ParseError { position: (2, _), error: error::ErrorKind::AddressOverflow, .. })
+            ), "{source}");
+        }
+        // Filling memory right up to FFFFH is fine.
+        for source in ["ORG 0FFFEH\nNOP", "ORG 0FFFFH\nNOP", "ORG 0FFF0H\nDS 16", "ORG 0FFFEH\nDW 1234H"] {
+            assert!(assembler::assemble(source).is_ok(), "{source}");
+        }
+    }
+
+    #[test]
+    fn test_equ_redefinition() {
+        let result = assembler::assemble("
+            PORT EQU 05H
+            PORT EQU 06H
+        ");
+        assert!(matches!(
+            result,
+            Err(error::ParseError { error: error::ErrorKind::SymbolRedefined(_), .. })
+        ));
+    }
+
+    #[test]
+    fn test_expressions() {
+        let image = match assembler::assemble("
+            BUF     EQU 2050H
+                    ORG 2000H
+            START:  LXI H, TABLE+2
+                    MVI A, LOW(BUF)
+                    MVI B, HIGH BUF + 1
+                    ADI 'A'-20H
+                    LDA BUF+1
+                    DB 'A'-20H, 'AB', -1, 1 SHL 3 OR 1, 1 << 2 | 1
+                    DW $, (2 + 3) * 4, NOT 0, 10 MOD 3
+            TABLE:  DB 0
+        ") {
+            Ok(image) => image,
+            Err(parse_error) => panic!("{parse_error}")
+        };
+        assert_eq!(image.segments[0].bytes, [
+            0x21, 0x1c, 0x20, 0x3e, 0x50, 0x06, 0x21, 0xc6, 0x21, 0x3a, 0x51, 0x20,
+            0x21, b'A', b'B', 0xff, 0x09, 0x05, 0x12, 0x20, 0x14, 0x00, 0xff, 0xff, 0x01, 0x00,
+            0x00,
+        ]);
+    }
+
+    #[test]
+    fn test_expression_range() {
+        let result = assembler::assemble("MVI A, 300");
+        assert!(matches!(
+            result,
+            Err(error::ParseError { error: error::ErrorKind::ValueOutOfRange(300, 8), .. })
+        ));
+        for (source, value, bits) in [("MVI A, -129", -129, 8), ("LXI H, -32769", -32769, 16)] {
+            assert!(matches!(
+                assembler::assemble(source),
+                Err(error::ParseError { error: error::ErrorKind::ValueOutOfRange(found, size), .. })
+                    if found == value && size == bits
+            ), "{source}");
+        }
+        assert!(assembler::assemble("MVI A, -128\nLXI H, -32768").is_ok());
+        // A negative EQU, before or after its use, fits a byte just like the number itself.
+        let image = assembler::assemble("OFF EQU -2\nMVI A, OFF\nLXI H, OFF\nMVI B, LATE\nLATE EQU -3")
+            .unwrap_or_else(|parse_error| panic!("{parse_error}"));
+        assert_eq!(image.segments[0].bytes, [0x3e, 0xfe, 0x21, 0xfe, 0xff, 0x06, 0xfd]);
+        // Forward references are range checked once the second pass knows their value.
+        let result = assembler::assemble("MVI A, LATER\nORG 100H\nLATER: DB 0");
+        assert!(matches!(
+            result,
+            Err(error::ParseError { error: error::ErrorKind::ValueOutOfRange(256, 8), .. })
+        ));
+    }
+
+    #[test]
+    fn test_address_operands() {
+        let image = match assembler::assemble("
+                    ORG 2000H
+                    LXI H, TABLE
+                    LDA COUNT
+                    MOV C, A
+            LOOP:   CALL DELAY
+                    JNZ 2003H
+                    JMP $+3
+                    RST 7
+                    STA 2060H
+                    HLT
+            DELAY:  RET
+            COUNT   EQU 2050H
+            TABLE:  DB 1
+        ") {
+            Ok(image) => image,
+            Err(parse_error) => panic!("{parse_error}")
+        };
+        assert_eq!(image.segments[0].bytes, [
+            0x21, 0x16, 0x20, 0x3a, 0x50, 0x20, 0x4f, 0xcd, 0x15, 0x20, 0xc2, 0x03, 0x20,
+            0xc3, 0x10, 0x20, 0xff, 0x32, 0x60, 0x20, 0x76, 0xc9, 0x01,
+        ]);
+    }
+
+    #[test]
+    fn test_macros() {
+        let image = match assembler::assemble("
+            DELAY   MACRO COUNT=0FFH
+                    LOCAL AGAIN
+                    MVI C, COUNT
+            AGAIN:  DCR C
+                    JNZ AGAIN
+                    ENDM
+            ADD16   MACRO X, Y
+                    LHLD X
+                    XCHG
+                    LHLD Y
+                    DAD D
+                    ENDM
+            TWICE   MACRO N
+                    DELAY N
+                    DELAY
+                    ENDM
+                    ORG 2000H
+                    DELAY 10
+                    TWICE 5
+                    ADD16 3000H, 3002H
+                    HLT
+        ") {
+            Ok(image) => image,
+            Err(parse_error) => panic!("{parse_error}")
+        };
+        assert_eq!(image.segments[0].bytes, [
+            0x0e, 0x0a, 0x0d, 0xc2, 0x02, 0x20,
+            0x0e, 0x05, 0x0d, 0xc2, 0x08, 0x20,
+            0x0e, 0xff, 0x0d, 0xc2, 0x0e, 0x20,
+            0x2a, 0x00, 0x30, 0xeb, 0x2a, 0x02, 0x30, 0x19, 0x76,
+        ]);
+    }
+
+    #[test]
+    fn test_macro_errors() {
+        let result = assembler::assemble("LOAD MACRO R\n  MVI R, 1\n  ENDM\n  LOAD SP");
+        match result {
+            Err(error::ParseError {
+                position: (2, 7),
+                file: None,
+                error: error::ErrorKind::InMacro(name, (4, 3), _),
+                ..
+            }) => assert_eq!(name, "LOAD"),
+            Err(parse_error) => panic!("{parse_error}"),
+            Ok(_) => panic!("expected an error"),
+        }
+        let result = assembler::assemble("LOOP MACRO\n  LOOP\n  ENDM\n  LOOP");
+        assert!(matches!(
+            result,
+            Err(error::ParseError { error: error::ErrorKind::MacroRecursion(_), .. })
+        ));
+        let result = assembler::assemble("LOAD MACRO R\n  MVI R, 1");
+        assert!(matches!(
+            result,
+            Err(error::ParseError { error: error::ErrorKind::UnterminatedMacro(_), .. })
+        ));
+
+        // Each bad invocation is reported and left out, and the rest of the program assembles.
+        let assembly = assembler::assemble_report("
+            PAIR    MACRO X, Y
+                    MVI A, X
+                    MVI B, Y
+                    ENDM
+                    PAIR 1, 2, 3
+                    PAIR 4, 5
+                    PAIR
+                    ENDM
+                    MVI C, 300
+        ", &assembler::Options::default());
+        let found: Vec<_> = assembly
+            .diagnostics
+            .iter()
+            .map(|diagnostic| (diagnostic.message.position.0, &diagnostic.message.error))
+            .collect();
+        assert!(matches!(found[..], [
+            (6, error::ErrorKind::MacroArguments(_, 2, 3)),
+            (8, error::ErrorKind::MacroArguments(_, 2, 0)),
+            (9, error::ErrorKind::UnexpectedToken(..)),
+            (10, error::ErrorKind::ValueOutOfRange(300, 8)),
+        ]), "{found:?}");
+    }
+
+    #[test]
+    fn test_conditionals() {
+        let source = "
+            DEBUG   EQU 0
+                    ORG 2000H
+                    IF DEBUG
+                    NOP
+                    ELSE
+                    IF BOARD >= 2 AND BOARD <> 3
+                    MVI A, 1
+                    ENDIF
+                    IFNDEF BOARD
+                    MVI A, 2
+                    ENDIF
+                    ENDIF
+                    IFDEF DEBUG
+                    HLT
+                    ENDIF
+        ";
+        let mut options = assembler::Options::default();
+        options.defines.insert("board".to_owned(), 2);
+        let image = match assembler::assemble_with(source, &options) {
+            Ok(image) => image,
+            Err(parse_error) => panic!("{parse_error}")
+        };
+        assert_eq!(image.segments[0].bytes, [0x3e, 0x01, 0x76]);
+
+        options.defines.insert("board".to_owned(), 3);
+        let image = match assembler::assemble_with(source, &options) {
+            Ok(image) => image,
+            Err(parse_error) => panic!("{parse_error}")
+        };
+        assert_eq!(image.segments[0].bytes, [0x76]);
+    }
+
+    #[test]
+    fn test_conditional_errors() {
+        let result = assembler::assemble("  IF 1\n  NOP\n  ELSE\n  HLT");
+        assert!(matches!(
+            result,
+            Err(error::ParseError { position: (1, 3), error: error::ErrorKind::UnterminatedConditional, .. })
+        ));
+        let result = assembler::assemble("  NOP\n  ENDIF");
+        assert!(matches!(
+            result,
+            Err(error::ParseError { error: error::ErrorKind::UnmatchedConditional(_), .. })
+        ));
+        for condition in [0, 1] {
+            let result = assembler::assemble(&format!("  IF {condition}\n  ELSE\n  ELSE\n  ENDIF"));
+            assert!(matches!(
+                result,
+                Err(error::ParseError { position: (3, 3), error: error::ErrorKind::DuplicateElse, .. })
+            ), "IF {condition}");
+        }
+        // A condition that cannot be evaluated still opens a block for its ENDIF to close.
+        let assembly = assembler::assemble_report("  IF LATER\n  NOP\n  ENDIF\nLATER: NOP", &Default::default());
+        let errors: Vec<_> = assembly.diagnostics.iter().map(|diagnostic| &diagnostic.message.error).collect();
+        assert!(matches!(errors[..], [error::ErrorKind::PhaseError(_)]), "{errors:?}");
+        let result = assembler::assemble("  IF LATER\n  ENDIF\nLATER: NOP");
+        assert!(matches!(
+            result,
+            Err(error::ParseError { error: error::ErrorKind::PhaseError(_), .. })
+        ));
+        let result = assembler::assemble("  IFNDEF LATER\n  ENDIF\nLATER EQU 1");
+        assert!(matches!(
+            result,
+            Err(error::ParseError { position: (3, 1), error: error::ErrorKind::PhaseError(_), .. })
+        ));
+    }
+
+    #[test]
+    fn test_include() -> std::io::Result<()> {
+        let options = assembler::Options {
+            include_dirs: vec![(TEST_LOC.to_owned() + "include/lib").into()],
+            ..Default::default()
+        };
+        let image = match assembler::assemble_file_with(TEST_LOC.to_owned() + "include/main.asm", &options)? {
+            Ok(image) => image,
+            Err(parse_error) => panic!("{parse_error}")
+        };
+        assert_eq!(image.segments[0].bytes, [
+            0x3e, 0x03, 0x06, 0x04, 0xcd, 0x09, 0x20, 0x76, 0x00,
+            0x4f, 0xaf, 0x81, 0x05, 0xc2, 0x0b, 0x20, 0xc9,
+        ]);
+
+        let result = assembler::assemble_file(TEST_LOC.to_owned() + "include/main.asm")?;
+        assert!(matches!(
+            result,
+            Err(error::ParseError { position: (7, 9), error: error::ErrorKind::IncludeNotFound(_), .. })
+        ));
+        // A failed INCLUDE is left out, and the files after it are still read.
+        let assembly = assembler::assemble_report(
+            "  INCLUDE \"nothere.asm\"\n  INCLUDE 5\n  INCLUDE \"mul.asm\"\n  MVI A, 300",
+            &options,
+        );
+        let found: Vec<_> = assembly
+            .diagnostics
+            .iter()
+            .map(|diagnostic| (diagnostic.message.position.0, &diagnostic.message.error))
+            .collect();
+        assert!(matches!(found[..], [
+            (1, error::ErrorKind::IncludeNotFound(_)),
+            (2, error::ErrorKind::UnexpectedToken(..)),
+            (4, error::ErrorKind::ValueOutOfRange(300, 8)),
+        ]), "{found:?}");
+        let result = assembler::assemble_file(TEST_LOC.to_owned() + "include/cycle.asm")?;
+        match result {
+            Err(error::ParseError { file: Some(file), error: error::ErrorKind::IncludeCycle(_), .. }) => {
+                assert!(file.ends_with("cycle2.asm"))
+            }
+            Err(parse_error) => panic!("{parse_error}"),
+            Ok(_) => panic!("expected an error"),
+        }
+        let result = assembler::assemble_file_with(TEST_LOC.to_owned() + "include/broken.asm", &options)?;
+        match result {
+            Err(error::ParseError { position: (2, 16), file: Some(file), .. }) => {
+                assert!(file.ends_with("bad.asm"))
+            }
+            Err(parse_error) => panic!("{parse_error}"),
+            Ok(_) => panic!("expected an error"),
         }
         Ok(())
     }
+
+    #[test]
+    fn test_listing() {
+        let (_, listing) = match assembler::assemble_listing("
+            DELAY   MACRO N
+                    LOCAL LP
+                    MVI C, N
+            LP:     DCR C
+                    JNZ LP
+                    ENDM
+                    ORG 100H
+            START:  DELAY 3
+                    DB 'HELLO', 0
+                    JMP START
+        ", &assembler::Options::default()) {
+            Ok(result) => result,
+            Err(parse_error) => panic!("{parse_error}")
+        };
+        let line = |number: usize| &listing.lines[number - 1];
+        assert_eq!(line(4).address, None);
+        assert_eq!(line(8).address, Some(0x100));
+        assert_eq!(line(9).address, Some(0x100));
+        assert_eq!(line(9).bytes, [0x0e, 0x03, 0x0d, 0xc2, 0x02, 0x01]);
+        assert_eq!(line(9).t_states, Some((18, 21)));
+        assert_eq!(line(9).machine_cycles, Some((5, 6)));
+        assert_eq!(line(10).bytes, b"HELLO\0");
+        assert_eq!(line(10).t_states, None);
+        assert_eq!(line(11).t_states, Some((10, 10)));
+
+        let start = listing.symbols.iter().find(|symbol| symbol.name == "START").unwrap();
+        assert_eq!(start.value, 0x100);
+        assert_eq!(start.definition, Some((None, 9)));
+        assert_eq!(start.references, [(None, 11)]);
+        let text = listing.to_string();
+        assert!(text.contains("    9  0100  0E 03 0D C2  18/21"));
+        assert!(text.contains("       0104  02 01"));
+    }
+
+    #[test]
+    fn test_output_formats() {
+        let image = match assembler::assemble("
+                    ORG 2000H
+            START:  MVI A, 3
+                    ORG 2004H
+                    HLT
+                    END START
+        ") {
+            Ok(image) => image,
+            Err(parse_error) => panic!("{parse_error}")
+        };
+        assert_eq!(
+            output::intel_hex(&image),
+            ":022000003E039D\n:012004007665\n:0400000300002000D9\n:00000001FF\n"
+        );
+        assert_eq!(
+            output::s_records(&image, output::SRecordFormat::S19),
+            "S0030000FC\nS10520003E0399\nS10420047661\nS9032000DC\n"
+        );
+        assert!(output::s_records(&image, output::SRecordFormat::S28).starts_with("S0030000FC\nS2060020003E0398\n"));
+
+        let bytes = output::binary(&image, &output::BinaryOptions::default());
+        assert_eq!(bytes, Ok(vec![0x3e, 0x03, 0xff, 0xff, 0x76]));
+        let options = output::BinaryOptions { origin: Some(0x2000), size: Some(8), fill: 0 };
+        assert_eq!(output::binary(&image, &options), Ok(vec![0x3e, 0x03, 0, 0, 0x76, 0, 0, 0]));
+        let options = output::BinaryOptions { size: Some(4), ..Default::default() };
+        assert_eq!(output::binary(&image, &options), Err(error::OutputError::TooLarge(5, 4)));
+        let options = output::BinaryOptions { origin: Some(0x2002), ..Default::default() };
+        assert_eq!(output::binary(&image, &options), Err(error::OutputError::BelowOrigin(0x2000, 0x2002)));
+
+        let array = output::byte_array(&image, "PROGRAM", output::ArrayLanguage::C);
+        assert!(array.contains("const unsigned char PROGRAM[5] = {\n    0x3e, 0x03, 0x00, 0x00, 0x76,\n};"));
+        let array = output::byte_array(&image, "PROGRAM", output::ArrayLanguage::Rust);
+        assert!(array.contains("pub static PROGRAM: [u8; 5] = [\n"));
+    }
+
+    #[test]
+    fn test_diagnostics() {
+        let assembly = assembler::assemble_report("
+                    ORG 2000H
+            LOOP:   MVI A, 300
+                    MOV A, Q
+            LOOP:   JMP LOPP
+                    FOO 1
+                    HLT
+                    END
+                    NOP
+        ", &assembler::Options::default());
+        assert!(assembly.image.is_none());
+        let found: Vec<_> = assembly
+            .diagnostics
+            .iter()
+            .map(|diagnostic| (diagnostic.severity, diagnostic.message.position.0))
+            .collect();
+        assert_eq!(found, [
+            (error::Severity::Error, 3),
+            (error::Severity::Error, 4),
+            (error::Severity::Error, 5),
+            (error::Severity::Note, 3),
+            (error::Severity::Error, 6),
+            (error::Severity::Warning, 9),
+            (error::Severity::Error, 5),
+        ]);
+        assert!(matches!(assembly.diagnostics[0].message.error, error::ErrorKind::ValueOutOfRange(300, 8)));
+        assert_eq!(assembly.diagnostics[2].to_string(), "error at 5:13: Symbol already defined: LOOP");
+        assert_eq!(assembly.diagnostics[3].to_string(), "note at 3:13: LOOP was first defined here");
+        assert!(matches!(&assembly.diagnostics[6].message.error, error::ErrorKind::UndefinedSymbol(symbol) if symbol == "LOPP"));
+
+        // Lines that fail to lex are reported first, and the rest of the program still assembles.
+        let assembly = assembler::assemble_report(
+            "  MVI A,300\n  DB 'abc\n  JMP NOWHR\n  MVI B, 12G\n  LXI H, TOOLONG\n  MVI C, 1",
+            &assembler::Options::default(),
+        );
+        let found: Vec<_> = assembly
+            .diagnostics
+            .iter()
+            .map(|diagnostic| (diagnostic.message.position.0, &diagnostic.message.error))
+            .collect();
+        assert!(matches!(found[..], [
+            (2, error::ErrorKind::UnterminatedString(_)),
+            (4, error::ErrorKind::NumberError(_)),
+            (5, error::ErrorKind::UnexpectedLexeme(_)),
+            (1, error::ErrorKind::ValueOutOfRange(300, 8)),
+            (3, error::ErrorKind::UndefinedSymbol(_)),
+        ]), "{found:?}");
+
+        // Running out of tokens is reported at the last one, saying what should have followed.
+        for (source, expected) in [
+            ("  MVI A, 1\n  MOV A", "error at 2:7: Expected `,`, found the end of the file"),
+            ("  NOP\nFOO", "error at 2:1: Expected `:`, found the end of the file"),
+            ("  FOO\nFOO MACRO\n  NOP\n  ENDM", "error at 1:3: Expected `:`, found the end of the file"),
+            ("  MVI A, (1", "error at 1:11: Expected `)`, found the end of the file"),
+        ] {
+            let assembly = assembler::assemble_report(source, &assembler::Options::default());
+            assert_eq!(assembly.diagnostics.len(), 1, "{source}");
+            assert_eq!(assembly.diagnostics[0].to_string(), expected);
+        }
+
+        let assembly = assembler::assemble_report("  NOP\n  END\n  HLT", &assembler::Options::default());
+        assert!(!assembly.has_errors());
+        assert_eq!(assembly.image.unwrap().segments[0].bytes, [0x00]);
+        assert_eq!(assembly.diagnostics.len(), 1);
+    }
+
+    #[test]
+    fn test_rendered_diagnostics() {
+        let assembly = assembler::assemble_report(
+            "LOOP:   MVI A, 5\n        JMPP LOOP\n        JNZ LOPP\n        MOV A, 5\n\tNOPP\n",
+            &assembler::Options::default(),
+        );
+        let help: Vec<_> = assembly.diagnostics.iter().map(|diagnostic| diagnostic.help.as_deref()).collect();
+        assert_eq!(help, [Some("did you mean `JMP`?"), None, Some("did you mean `NOP`?"), Some("did you mean `LOOP`?")]);
+        assert_eq!(assembly.diagnostics[1].message.error.message(), "Expected a register, found the number 5");
+        let rendered = assembly.render();
+        assert!(rendered.starts_with(
+            "error: Unknown instruction or directive: JMPP\n --> 2:9\n  |\n2 |         JMPP LOOP\n  |         ^^^^\n  = help: did you mean `JMP`?\n\n"
+        ));
+        assert!(rendered.contains("5 | \tNOPP\n  | \t^^^^\n"));
+        assert!(rendered.ends_with("3 |         JNZ LOPP\n  |             ^^^^\n  = help: did you mean `LOOP`?\n"));
+
+        let assembly = assembler::assemble_report("  MVI A, 'AB' + 1", &assembler::Options::default());
+        assert!(assembly.render().contains("1 |   MVI A, 'AB' + 1\n  |          ^^^^^^^^\n"));
+    }
+
+    #[test]
+    fn test_disassembler() {
+        // Every opcode followed by two operand bytes, which are themselves disassembled as code
+        // when the opcode takes fewer.
+        let bytes: Vec<u8> = (0..=255u8).flat_map(|opcode| [opcode, 0x34, 0x12]).collect();
+        let disassembly = disassembler::disassemble(&bytes, 0x1000, &Default::default());
+        let source = disassembly.to_string();
+        let image = assembler::assemble(&source).unwrap_or_else(|error| panic!("{error}\n{source}"));
+        assert_eq!(image.segments[0].origin, 0x1000);
+        assert_eq!(image.segments[0].bytes, bytes);
+
+        let (image, listing) = assembler::assemble_listing("
+                    ORG 2000H
+            COUNT   EQU 3000H
+            START:  LXI H, COUNT
+                    MVI A, 0FFH
+            LOOP:   DCR A
+                    JNZ LOOP
+                    CALL COUNT + 1
+                    DB 08H
+                    POP B
+                    CMC
+        ", &assembler::Options::default()).unwrap();
+        let symbols = listing.symbols.iter().map(|symbol| (symbol.name.clone(), symbol.value)).collect();
+        let bytes = &image.segments[0].bytes;
+        assert_eq!(bytes[bytes.len() - 2..], [0xC1, 0x3F]);
+        let disassembly = disassembler::disassemble(bytes, 0x2000, &symbols);
+        let text: Vec<_> = disassembly.lines.iter().map(|line| line.text.as_str()).collect();
+        assert_eq!(text, ["LXI H, COUNT", "MVI A, 0FFH", "DCR A", "JNZ LOOP", "CALL 3001H", "DB 08H", "POP B", "CMC"]);
+        assert_eq!(disassembly.lines[2].label.as_deref(), Some("LOOP"));
+        assert_eq!(disassembly.equates, [("COUNT".to_owned(), 0x3000)]);
+        let source = disassembly.to_string();
+        assert!(source.contains("LOOP:   DCR A               ; 2005  3D\n"));
+        assert_eq!(assembler::assemble(&source).unwrap().segments[0].bytes, *bytes);
+
+        // A six-character name into the middle of MVI reads back, with space before its EQU. A
+        // longer one would not lex, so the operand is left as a number.
+        let bytes = [0x21, 0x04, 0x20, 0x3E, 0x05, 0x2A, 0x00, 0x30];
+        let symbols = std::collections::HashMap::from([
+            ("BUFFER".to_owned(), 0x2004),
+            ("DATABUFFER".to_owned(), 0x3000),
+        ]);
+        let mut disassembly = disassembler::disassemble(&bytes, 0x2000, &symbols);
+        assert_eq!(disassembly.equates, [("BUFFER".to_owned(), 0x2004)]);
+        assert_eq!(disassembly.lines[2].text, "LHLD 3000H");
+        let source = disassembly.to_string();
+        assert!(source.contains("BUFFER  EQU 2004H\n"), "{source}");
+        let image = assembler::assemble(&source).unwrap_or_else(|error| panic!("{error}\n{source}"));
+        assert_eq!(image.segments[0].bytes, bytes);
+        disassembly.equates.push(("DATABUFFER".to_owned(), 0x3000));
+        assert!(disassembly.to_string().contains("DATABUFFER EQU 3000H\n"));
+
+        let disassembly = disassembler::disassemble(&[0xC3, 0x00], 0, &Default::default());
+        assert_eq!(disassembly.lines.len(), 2);
+        assert_eq!(disassembly.lines[0].text, "DB 0C3H");
+    }
+
+    #[test]
+    fn test_rom_disassembly() {
+        let image = assembler::assemble("
+                    ORG 0
+                    JMP START
+            MSG:    DB 'HI', 0
+            START:  LXI H, MSG
+                    CALL PRINT
+                    HLT
+            PRINT:  MOV A, M
+                    ORA A
+                    RZ
+                    OUT 1
+                    INX H
+                    JMP PRINT
+                    DB 1, 2, 3
+        ").unwrap();
+        let bytes = &image.segments[0].bytes;
+        let disassembly = disassembler::disassemble_rom(bytes, 0, &[0], &Default::default());
+        let lines: Vec<_> = disassembly
+            .lines
+            .iter()
+            .map(|line| (line.label.as_deref().unwrap_or(""), line.text.as_str()))
+            .collect();
+        assert_eq!(lines, [
+            ("", "JMP L0006"),
+            ("D0003", "DB 48H, 49H, 00H"),
+            ("L0006", "LXI H, D0003"),
+            ("", "CALL L000D"),
+            ("", "HLT"),
+            ("L000D", "MOV A, M"),
+            ("", "ORA A"),
+            ("", "RZ"),
+            ("", "OUT 01H"),
+            ("", "INX H"),
+            ("", "JMP L000D"),
+            ("", "DB 01H, 02H, 03H"),
+        ]);
+        assert_eq!(assembler::assemble(&disassembly.to_string()).unwrap().segments[0].bytes, *bytes);
+
+        // Symbols win over generated labels, RSTs are followed and entries outside the ROM are ignored.
+        let image = assembler::assemble("
+                    ORG 0
+                    JMP START
+                    ORG 8
+                    JMP PRINT
+                    ORG 10H
+            START:  RST 1
+                    HLT
+            PRINT:  RET
+        ").unwrap();
+        let rom = output::binary(&image, &output::BinaryOptions::default()).unwrap();
+        let symbols = std::collections::HashMap::from([("PRINT".to_owned(), 0x0012)]);
+        let disassembly = disassembler::disassemble_rom(&rom, 0, &[0x0000, 0x0100], &symbols);
+        let source = disassembly.to_string();
+        assert!(source.contains("L0008:  JMP PRINT"));
+        assert!(source.contains("L0010:  RST 1"));
+        assert!(source.contains("PRINT:  RET"));
+        assert!(source.contains("        DB 0FFH, 0FFH, 0FFH, 0FFH, 0FFH ; 0003  FF FF FF FF FF\n"));
+        let reassembled = assembler::assemble(&source).unwrap();
+        assert_eq!(output::binary(&reassembled, &output::BinaryOptions::default()).unwrap(), rom);
+    }
+
+    #[test]
+    fn test_undocumented_instructions() {
+        let source = "
+                    DSUB
+                    ARHL
+                    RDEL
+                    LDHI 12H
+                    LDSI 34H
+                    RSTV
+                    SHLX
+            LOOP:   JNK LOOP
+                    LHLX
+                    JK LOOP
+        ";
+        let options = assembler::Options { undocumented: true, ..Default::default() };
+        let (image, listing) = assembler::assemble_listing(source, &options).unwrap();
+        assert_eq!(image.segments[0].bytes, [
+            0x08, 0x10, 0x18, 0x28, 0x12, 0x38, 0x34, 0xcb, 0xd9, 0xdd, 0x09, 0x00, 0xed, 0xfd, 0x09, 0x00,
+        ]);
+        assert_eq!(listing.lines[8].t_states, Some((7, 10)));
+
+        // Without the option the names are free for labels, and using one as an instruction says why.
+        assert!(assembler::assemble("ARHL: JMP ARHL").is_ok());
+        let assembly = assembler::assemble_report(source, &assembler::Options::default());
+        assert!(matches!(
+            &assembly.diagnostics[0].message.error,
+            error::ErrorKind::UnknownInstruction(name) if name == "DSUB"
+        ));
+        assert_eq!(
+            assembly.diagnostics[0].help.as_deref(),
+            Some("`DSUB` is undocumented, set `Options::undocumented` to use it"),
+        );
+    }
 }
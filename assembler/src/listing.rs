@@ -0,0 +1,203 @@
+use std::collections::{ BTreeMap, HashMap };
+use std::fmt::Display;
+use std::rc::Rc;
+
+use crate::assembler::SymbolTable;
+use crate::token::{ Token, TokenType };
+
+/// A source line is identified by the file it is in and its line number.
+pub type LineKey = (Option<Rc<str>>, usize);
+
+/// One line of source text, in the order the assembler read it with INCLUDEs spliced in.
+#[derive(Debug, Clone)]
+pub struct SourceLine {
+    pub file: Option<Rc<str>>,
+    pub number: usize,
+    pub text: String,
+}
+
+/// What the second pass produced for one source line.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Emitted {
+    pub address: Option<u16>,
+    pub bytes: Vec<u8>,
+    pub t_states: Option<(u32, u32)>,
+    pub machine_cycles: Option<(u32, u32)>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ListingLine {
+    pub file: Option<Rc<str>>,
+    pub number: usize,
+    /// The location counter at the start of the line, `None` for lines that were not assembled
+    /// (comments, macro bodies and the inactive branch of an IF).
+    pub address: Option<u16>,
+    pub bytes: Vec<u8>,
+    /// T-states of the instructions on the line, as `(not taken, taken)` for conditionals.
+    pub t_states: Option<(u32, u32)>,
+    /// Machine cycles of the instructions on the line, paired the same way as `t_states`.
+    pub machine_cycles: Option<(u32, u32)>,
+    pub text: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct CrossReference {
+    pub name: String,
+    pub value: u16,
+    /// Where the symbol is first defined, `None` for symbols given in `Options::defines`.
+    pub definition: Option<LineKey>,
+    pub references: Vec<LineKey>,
+}
+
+/// An assembly listing: every source line next to the code generated for it, followed by the
+/// symbol table with a cross-reference of where each symbol is defined and used.
+#[derive(Debug, Clone, Default)]
+pub struct Listing {
+    pub lines: Vec<ListingLine>,
+    pub symbols: Vec<CrossReference>,
+}
+
+/// The line a token is listed under. Code generated by a macro belongs to the line that invoked
+/// it (the outermost invocation, when macros call each other).
+pub(crate) fn line_of(token: &Token) -> LineKey {
+    let mut key = (token.file.clone(), token.position.0);
+    let mut expansion = token.expansion.clone();
+    while let Some(current) = expansion {
+        key = (current.file.clone(), current.call_site.0);
+        expansion = current.parent.clone();
+    }
+    key
+}
+
+fn is_definition(tokens: &[Token], index: usize) -> bool {
+    match tokens.get(index + 1).map(|token| &token.token) {
+        Some(TokenType::Colon) => true,
+        Some(TokenType::Operation(operation)) => operation == "EQU" || operation == "SET",
+        _ => false,
+    }
+}
+
+pub(crate) fn build(
+    sources: Vec<SourceLine>,
+    mut emitted: HashMap<LineKey, Emitted>,
+    tokens: &[Token],
+    symbol_table: &SymbolTable,
+) -> Listing {
+    let lines = sources
+        .into_iter()
+        .map(|source| {
+            let emitted = emitted.remove(&(source.file.clone(), source.number)).unwrap_or_default();
+            ListingLine {
+                file: source.file,
+                number: source.number,
+                address: emitted.address,
+                bytes: emitted.bytes,
+                t_states: emitted.t_states,
+                machine_cycles: emitted.machine_cycles,
+                text: source.text,
+            }
+        })
+        .collect();
+
+    let mut symbols: BTreeMap<&str, CrossReference> = symbol_table
+        .iter()
+        .map(|(name, value)| {
+            (name.as_str(), CrossReference {
+                name: name.clone(),
+                // A negative EQU is listed as the word it assembles to.
+                value: *value as u16,
+                definition: None,
+                references: vec![],
+            })
+        })
+        .collect();
+    for (index, token) in tokens.iter().enumerate() {
+        let symbol = match &token.token {
+            TokenType::Label(name) => match symbols.get_mut(name.as_str()) {
+                Some(symbol) => symbol,
+                None => continue,
+            },
+            _ => continue,
+        };
+        let line = line_of(token);
+        if is_definition(tokens, index) {
+            symbol.definition.get_or_insert(line);
+        } else if !symbol.references.contains(&line) {
+            symbol.references.push(line);
+        }
+    }
+    Listing { lines, symbols: symbols.into_values().collect() }
+}
+
+impl Listing {
+    /// The file the listing was assembled from, which is left out of line references.
+    fn main_file(&self) -> Option<&Rc<str>> {
+        self.lines.first().and_then(|line| line.file.as_ref())
+    }
+
+    fn location(&self, (file, line): &LineKey) -> String {
+        match file {
+            Some(file) if Some(file) != self.main_file() => format!("{}:{}", file, line),
+            _ => line.to_string(),
+        }
+    }
+}
+
+/// Bytes shown per row; longer lines (mostly DB) continue on the rows below.
+const BYTES_PER_ROW: usize = 4;
+
+impl Display for Listing {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut file = self.main_file();
+        for line in &self.lines {
+            if line.file.as_ref() != file {
+                file = line.file.as_ref();
+                if let Some(file) = file {
+                    f.write_fmt(format_args!("{:27}; {}\n", "", file))?;
+                }
+            }
+            let mut rows = line.bytes.chunks(BYTES_PER_ROW);
+            let address = match line.address {
+                Some(address) => format!("{:04X}", address),
+                None => String::new(),
+            };
+            let t_states = match line.t_states {
+                Some((low, high)) if low == high => low.to_string(),
+                Some((low, high)) => format!("{}/{}", low, high),
+                None => String::new(),
+            };
+            f.write_fmt(format_args!(
+                "{:5}  {:4}  {:11}  {:>5}  {}\n",
+                line.number,
+                address,
+                hex(rows.next().unwrap_or_default()),
+                t_states,
+                line.text
+            ))?;
+            let mut next = line.address.unwrap_or(0);
+            for row in rows {
+                next = next.wrapping_add(BYTES_PER_ROW as u16);
+                f.write_fmt(format_args!("{:5}  {:04X}  {}\n", "", next, hex(row)))?;
+            }
+        }
+
+        f.write_str("\nSymbol  Value  Defined  References\n")?;
+        for symbol in &self.symbols {
+            f.write_fmt(format_args!("{:6}  {:04X}   ", symbol.name, symbol.value))?;
+            let definition = match &symbol.definition {
+                Some(definition) => self.location(definition),
+                None => "-".to_owned(),
+            };
+            f.write_fmt(format_args!("{:7} ", definition))?;
+            for reference in &symbol.references {
+                f.write_fmt(format_args!(" {}", self.location(reference)))?;
+            }
+            f.write_str("\n")?;
+        }
+        Ok(())
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02X}", byte)).collect::<Vec<_>>().join(" ")
+}
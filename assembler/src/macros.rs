@@ -0,0 +1,369 @@
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use crate::error::{ ErrorKind, ParseError };
+use crate::token::{ Expansion, Operator, Token, TokenStream, TokenType };
+
+/// How deep macros may invoke each other before we assume the expansion never terminates.
+const MAX_DEPTH: usize = 64;
+
+struct Parameter {
+    name: String,
+    default: Option<Vec<Token>>,
+}
+
+struct Macro {
+    parameters: Vec<Parameter>,
+    locals: Vec<String>,
+    body: Vec<Token>,
+}
+
+struct Expander {
+    macros: HashMap<String, Macro>,
+    expansions: usize,
+    /// Errors so far. The statement in error is left out and expansion carries on after it.
+    errors: Vec<ParseError>,
+}
+
+pub(crate) fn same_source(a: &Token, b: &Token) -> bool {
+    let same_expansion = match (&a.expansion, &b.expansion) {
+        (Some(a), Some(b)) => Rc::ptr_eq(a, b),
+        (None, None) => true,
+        _ => false,
+    };
+    same_expansion && a.file == b.file
+}
+
+/// Tokens carry no line terminators, so a statement is the run of tokens sharing a line (and
+/// the same file and expansion, since expanded tokens keep the line numbers of the macro body).
+fn line_end(tokens: &[Token], start: usize) -> usize {
+    let first = &tokens[start];
+    let mut end = start + 1;
+    while end < tokens.len()
+        && tokens[end].position.0 == first.position.0
+        && same_source(&tokens[end], first)
+    {
+        end += 1;
+    }
+    end
+}
+
+fn is_statement_start(tokens: &[Token], index: usize) -> bool {
+    if index == 0 {
+        return true;
+    }
+    let previous = &tokens[index - 1];
+    previous.token == TokenType::Colon
+        || previous.position.0 != tokens[index].position.0
+        || !same_source(previous, &tokens[index])
+}
+
+fn is_operation(token: Option<&Token>, operation: &str) -> bool {
+    matches!(token, Some(Token { token: TokenType::Operation(found), .. }) if found == operation)
+}
+
+/// Splits the tokens of an argument or parameter list on the commas outside parentheses.
+fn split_arguments(tokens: &[Token]) -> Vec<Vec<Token>> {
+    let mut arguments = vec![];
+    if tokens.is_empty() {
+        return arguments;
+    }
+    let mut current = vec![];
+    let mut depth = 0usize;
+    for token in tokens {
+        match token.token {
+            TokenType::OpenParen => depth += 1,
+            TokenType::CloseParen => depth = depth.saturating_sub(1),
+            TokenType::Comma if depth == 0 => {
+                arguments.push(std::mem::take(&mut current));
+                continue;
+            }
+            _ => {}
+        }
+        current.push(token.clone());
+    }
+    arguments.push(current);
+    arguments
+}
+
+fn expect_label(tokens: &[Token], position: (usize, usize)) -> Result<String, ParseError> {
+    match tokens.first() {
+        Some(Token { token: TokenType::Label(name), .. }) => Ok(name.clone()),
+        Some(token) => Err(ParseError {
+            position: token.position,
+            file: token.file.clone(),
+            length: token.length,
+            error: ErrorKind::UnexpectedToken(vec![TokenType::Label("".to_owned())], token.token.clone()),
+        }),
+        None => Err(ParseError {
+            position,
+            file: None,
+            length: 0,
+            error: ErrorKind::UnexpectedToken(vec![TokenType::Label("".to_owned())], TokenType::End),
+        }),
+    }
+}
+
+/// The index of the `ENDM` closing the definition at `start`, skipping over nested definitions.
+fn find_endm(tokens: &[Token], start: usize) -> Option<usize> {
+    let mut depth = 0usize;
+    for (index, token) in tokens.iter().enumerate().skip(line_end(tokens, start)) {
+        if is_operation(Some(token), "ENDM") {
+            if depth == 0 {
+                return Some(index);
+            }
+            depth -= 1;
+        } else if is_operation(Some(token), "MACRO") {
+            depth += 1;
+        }
+    }
+    None
+}
+
+/// Reads the parameters, locals and body of the definition at `start`, whose `ENDM` is just past
+/// the end of `tokens`.
+fn parse_definition(tokens: &[Token], start: usize) -> Result<Macro, ParseError> {
+    let header_end = line_end(tokens, start);
+    let mut parameters = vec![];
+    for parameter in split_arguments(&tokens[start + 2..header_end]) {
+        let position = tokens[start + 1].position;
+        let parameter_name = expect_label(&parameter, position)?;
+        let default = match parameter.get(1) {
+            None => None,
+            Some(Token { token: TokenType::Operator(Operator::Equal), .. }) => {
+                Some(parameter[2..].to_vec())
+            }
+            Some(token) => {
+                return Err(ParseError {
+                    position: token.position,
+                    file: token.file.clone(),
+                    length: token.length,
+                    error: ErrorKind::UnexpectedToken(
+                        vec![TokenType::Comma, TokenType::Operator(Operator::Equal)],
+                        token.token.clone(),
+                    ),
+                })
+            }
+        };
+        parameters.push(Parameter { name: parameter_name, default });
+    }
+
+    let mut locals = vec![];
+    let mut body = vec![];
+    let mut depth = 0usize;
+    let mut index = header_end;
+    while let Some(token) = tokens.get(index) {
+        if is_operation(Some(token), "ENDM") {
+            depth -= 1;
+        } else if is_operation(Some(token), "MACRO") {
+            depth += 1;
+        } else if depth == 0 && is_operation(Some(token), "LOCAL") {
+            let end = line_end(tokens, index);
+            for local in split_arguments(&tokens[index + 1..end]) {
+                locals.push(expect_label(&local, token.position)?);
+            }
+            index = end;
+            continue;
+        }
+        body.push(token.clone());
+        index += 1;
+    }
+    Ok(Macro { parameters, locals, body })
+}
+
+impl Expander {
+    /// Reads a `NAME MACRO params` definition starting at `start` and returns the index just
+    /// past its `ENDM`. A definition with an error is left undefined, and one without an `ENDM`
+    /// takes the rest of the tokens with it.
+    fn define(&mut self, tokens: &[Token], start: usize) -> usize {
+        let name = match &tokens[start].token {
+            TokenType::Label(name) => name.clone(),
+            _ => unreachable!("should never happen!"),
+        };
+        let Some(end) = find_endm(tokens, start) else {
+            self.errors.push(ParseError {
+                position: tokens[start].position,
+                file: tokens[start].file.clone(),
+                length: tokens[start].length,
+                error: ErrorKind::UnterminatedMacro(name),
+            });
+            return tokens.len();
+        };
+        match parse_definition(&tokens[..end], start) {
+            Ok(definition) => {
+                self.macros.insert(name, definition);
+            }
+            Err(error) => self.errors.push(error),
+        }
+        end + 1
+    }
+
+    /// Expands the invocation at `start` into `output` and returns the index past its arguments.
+    fn invoke(
+        &mut self,
+        tokens: &[Token],
+        start: usize,
+        depth: usize,
+        output: &mut Vec<Token>,
+    ) -> Result<usize, ParseError> {
+        let call = &tokens[start];
+        let name = match &call.token {
+            TokenType::Label(name) => name.clone(),
+            _ => unreachable!("should never happen!"),
+        };
+        if depth >= MAX_DEPTH {
+            return Err(ParseError {
+                position: call.position,
+                file: call.file.clone(),
+                length: call.length,
+                error: ErrorKind::MacroRecursion(name),
+            });
+        }
+        let end = line_end(tokens, start);
+        let arguments = split_arguments(&tokens[start + 1..end]);
+        let definition = &self.macros[&name];
+        if arguments.len() > definition.parameters.len() {
+            return Err(ParseError {
+                position: call.position,
+                file: call.file.clone(),
+                length: call.length,
+                error: ErrorKind::MacroArguments(name, definition.parameters.len(), arguments.len()),
+            });
+        }
+
+        let mut substitutions: HashMap<String, Vec<Token>> = HashMap::new();
+        for (i, parameter) in definition.parameters.iter().enumerate() {
+            let value = match (arguments.get(i), &parameter.default) {
+                (Some(argument), _) if !argument.is_empty() => argument.clone(),
+                (_, Some(default)) => default.clone(),
+                _ => {
+                    return Err(ParseError {
+                        position: call.position,
+                        file: call.file.clone(),
+                        length: call.length,
+                        error: ErrorKind::MacroArguments(
+                            name,
+                            definition.parameters.len(),
+                            arguments.len(),
+                        ),
+                    })
+                }
+            };
+            substitutions.insert(parameter.name.clone(), value);
+        }
+        for local in &definition.locals {
+            self.expansions += 1;
+            let unique = format!("??{:04}", self.expansions);
+            substitutions.insert(
+                local.clone(),
+                vec![Token {
+                    position: call.position,
+                    length: call.length,
+                    token: TokenType::Label(unique),
+                    expansion: None,
+                    file: None,
+                }],
+            );
+        }
+
+        let expansion = Rc::new(Expansion {
+            name,
+            call_site: call.position,
+            file: call.file.clone(),
+            parent: call.expansion.clone(),
+        });
+        let mut body = vec![];
+        for token in &definition.body {
+            let replacement = match &token.token {
+                TokenType::Label(label) => substitutions.get(label),
+                _ => None,
+            };
+            match replacement {
+                Some(replacement) => body.extend(replacement.iter().map(|argument| Token {
+                    // Substituted tokens take the parameter's place in the body, which also keeps
+                    // them on the line of the statement they belong to.
+                    position: token.position,
+                    length: token.length,
+                    token: argument.token.clone(),
+                    expansion: Some(expansion.clone()),
+                    file: token.file.clone(),
+                })),
+                None => body.push(Token { expansion: Some(expansion.clone()), ..token.clone() }),
+            }
+        }
+        self.expand(&body, depth + 1, output);
+        Ok(end)
+    }
+
+    fn expand(&mut self, tokens: &[Token], depth: usize, output: &mut Vec<Token>) {
+        let mut index = 0;
+        while index < tokens.len() {
+            let token = &tokens[index];
+            if let TokenType::Label(name) = &token.token {
+                if is_statement_start(tokens, index) {
+                    if is_operation(tokens.get(index + 1), "MACRO") {
+                        index = self.define(tokens, index);
+                        continue;
+                    }
+                    let next = tokens.get(index + 1);
+                    let defines_label = matches!(next, Some(Token { token: TokenType::Colon, .. }))
+                        || is_operation(next, "EQU")
+                        || is_operation(next, "SET");
+                    if self.macros.contains_key(name) && !defines_label {
+                        index = match self.invoke(tokens, index, depth, output) {
+                            Ok(end) => end,
+                            Err(error) => {
+                                self.errors.push(error);
+                                line_end(tokens, index)
+                            }
+                        };
+                        continue;
+                    }
+                }
+            }
+            if ["MACRO", "ENDM", "LOCAL"].iter().any(|operation| is_operation(Some(token), operation)) {
+                self.errors.push(ParseError {
+                    position: token.position,
+                    file: token.file.clone(),
+                    length: token.length,
+                    error: ErrorKind::UnexpectedToken(
+                        vec![TokenType::Label("".to_owned())],
+                        token.token.clone(),
+                    ),
+                });
+                // Leave out the rest of the statement.
+                index = line_end(tokens, index);
+                continue;
+            }
+            output.push(token.clone());
+            index += 1;
+        }
+    }
+}
+
+/// Records MACRO ... ENDM definitions and replaces every invocation with its body, so the
+/// passes that follow only ever see plain instructions and directives. This runs before IF
+/// blocks are decided, so a macro defined inside a false branch is still defined.
+///
+/// Also returns every error found. A statement in error is left out of the tokens, and a
+/// definition in error is skipped up to its ENDM, so the rest of the program still expands.
+pub fn expand(tokens: TokenStream) -> (TokenStream, Vec<ParseError>) {
+    let mut expander = Expander {
+        macros: HashMap::new(),
+        expansions: 0,
+        errors: vec![],
+    };
+    let mut output = vec![];
+    expander.expand(&tokens.tokens, 0, &mut output);
+    (TokenStream { tokens: output }, expander.errors)
+}
+
+/// Wraps `error` with the chain of macro invocations that produced the offending token.
+pub fn in_expansion(mut error: ParseError, expansion: &Option<Rc<Expansion>>) -> ParseError {
+    let mut expansion = expansion.clone();
+    while let Some(current) = expansion {
+        error.error = ErrorKind::InMacro(current.name.clone(), current.call_site, Box::new(error.error));
+        expansion = current.parent.clone();
+    }
+    error
+}
@@ -0,0 +1,149 @@
+use crate::error::OutputError;
+use crate::image::{ Image, Segment };
+
+/// Data bytes per Intel HEX or S-record line.
+const RECORD_LENGTH: usize = 16;
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02X}", byte)).collect()
+}
+
+/// Splits every segment into `(address, bytes)` chunks of at most `RECORD_LENGTH` bytes.
+fn records(image: &Image) -> impl Iterator<Item = (u16, &[u8])> {
+    image.segments.iter().flat_map(|segment: &Segment| {
+        segment.bytes.chunks(RECORD_LENGTH).enumerate().map(move |(i, chunk)| {
+            (segment.origin.wrapping_add((i * RECORD_LENGTH) as u16), chunk)
+        })
+    })
+}
+
+fn intel_hex_record(address: u16, kind: u8, data: &[u8]) -> String {
+    let mut record = vec![data.len() as u8, (address >> 8) as u8, address as u8, kind];
+    record.extend_from_slice(data);
+    let checksum = record.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)).wrapping_neg();
+    record.push(checksum);
+    format!(":{}\n", hex(&record))
+}
+
+/// Writes the image as Intel HEX: data records for every segment, a start segment address
+/// record (CS = 0, IP = entry) when the program has an `END` address, and the end of file record.
+pub fn intel_hex(image: &Image) -> String {
+    let mut output = String::new();
+    for (address, data) in records(image) {
+        output += &intel_hex_record(address, 0x00, data);
+    }
+    if let Some(entry) = image.entry {
+        output += &intel_hex_record(0, 0x03, &[0, 0, (entry >> 8) as u8, entry as u8]);
+    }
+    output += &intel_hex_record(0, 0x01, &[]);
+    output
+}
+
+/// Which flavour of Motorola S-records to write.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SRecordFormat {
+    /// S1 data records with 16-bit addresses, terminated by S9.
+    S19,
+    /// S2 data records with 24-bit addresses, terminated by S8, for programmers that want them.
+    S28,
+}
+
+fn s_record(kind: u8, address: u32, address_length: usize, data: &[u8]) -> String {
+    let mut record = vec![(address_length + data.len() + 1) as u8];
+    record.extend_from_slice(&address.to_be_bytes()[4 - address_length..]);
+    record.extend_from_slice(data);
+    let checksum = !record.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+    record.push(checksum);
+    format!("S{}{}\n", kind, hex(&record))
+}
+
+/// Writes the image as Motorola S-records: an empty S0 header, the data records and a
+/// termination record holding the start address.
+pub fn s_records(image: &Image, format: SRecordFormat) -> String {
+    let (data_kind, end_kind, address_length) = match format {
+        SRecordFormat::S19 => (1, 9, 2),
+        SRecordFormat::S28 => (2, 8, 3),
+    };
+    let mut output = s_record(0, 0, 2, &[]);
+    for (address, data) in records(image) {
+        output += &s_record(data_kind, address as u32, address_length, data);
+    }
+    output += &s_record(end_kind, image.start_address() as u32, address_length, &[]);
+    output
+}
+
+/// Layout of a raw binary.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BinaryOptions {
+    /// Address of the first byte of the file. Defaults to the lowest address in the image.
+    pub origin: Option<u16>,
+    /// Pads the file to exactly this many bytes, e.g. 2048 for a 2716 or 8192 for a 2764 EPROM.
+    pub size: Option<usize>,
+    /// Value for gaps between segments and padding.
+    pub fill: u8,
+}
+
+impl Default for BinaryOptions {
+    /// No fixed origin or size, filling with 0FFH like an erased EPROM.
+    fn default() -> BinaryOptions {
+        BinaryOptions { origin: None, size: None, fill: 0xFF }
+    }
+}
+
+/// Writes the image as a flat binary starting at `options.origin`.
+pub fn binary(image: &Image, options: &BinaryOptions) -> Result<Vec<u8>, OutputError> {
+    let base = options.origin.unwrap_or_else(|| image.lowest_address());
+    let mut bytes = vec![];
+    for segment in &image.segments {
+        if segment.origin < base {
+            return Err(OutputError::BelowOrigin(segment.origin, base));
+        }
+        let offset = (segment.origin - base) as usize;
+        let end = offset + segment.bytes.len();
+        if let Some(size) = options.size {
+            if end > size {
+                return Err(OutputError::TooLarge(end, size));
+            }
+        }
+        if bytes.len() < end {
+            bytes.resize(end, options.fill);
+        }
+        bytes[offset..end].copy_from_slice(&segment.bytes);
+    }
+    if let Some(size) = options.size {
+        bytes.resize(size, options.fill);
+    }
+    Ok(bytes)
+}
+
+/// Language of a byte-array include.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArrayLanguage {
+    C,
+    Rust,
+}
+
+/// Writes the image as a byte array named `name`, starting at its lowest address with gaps
+/// zero filled, to be included in a C or Rust program.
+pub fn byte_array(image: &Image, name: &str, language: ArrayLanguage) -> String {
+    let bytes = image.to_bytes();
+    let mut output = format!(
+        "/* Origin {:04X}H, start {:04X}H, {} bytes */\n",
+        image.lowest_address(),
+        image.start_address(),
+        bytes.len()
+    );
+    output += &match language {
+        ArrayLanguage::C => format!("const unsigned char {}[{}] = {{\n", name, bytes.len()),
+        ArrayLanguage::Rust => format!("pub static {}: [u8; {}] = [\n", name, bytes.len()),
+    };
+    for row in bytes.chunks(12) {
+        let row: Vec<String> = row.iter().map(|byte| format!("0x{:02x},", byte)).collect();
+        output += &format!("    {}\n", row.join(" "));
+    }
+    output += match language {
+        ArrayLanguage::C => "};\n",
+        ArrayLanguage::Rust => "];\n",
+    };
+    output
+}
@@ -0,0 +1,58 @@
+/// T-states taken by the 8085 instruction with the given opcode as `(not taken, taken)`. Both
+/// values are the same except for conditional jumps, calls and returns (RSTV and JNK/JK among
+/// the undocumented ones). `None` for opcodes the 8085 does not define.
+pub fn t_states(opcode: u8) -> Option<(u8, u8)> {
+    let fixed = |t_states| Some((t_states, t_states));
+    let uses_memory = |register: u8| register & 7 == 6;
+    match opcode {
+        0x76 => fixed(5), // HLT
+        0x40..=0x7F if uses_memory(opcode) || uses_memory(opcode >> 3) => fixed(7),
+        0x40..=0x7F => fixed(4),
+        0x80..=0xBF if uses_memory(opcode) => fixed(7),
+        0x80..=0xBF => fixed(4),
+        0x00 | 0x20 | 0x30 => fixed(4),                     // NOP, RIM, SIM
+        0x01 | 0x11 | 0x21 | 0x31 => fixed(10),             // LXI
+        0x09 | 0x19 | 0x29 | 0x39 => fixed(10),             // DAD
+        0x02 | 0x12 | 0x0A | 0x1A => fixed(7),              // STAX, LDAX
+        0x22 | 0x2A => fixed(16),                           // SHLD, LHLD
+        0x32 | 0x3A => fixed(13),                           // STA, LDA
+        0x03 | 0x13 | 0x23 | 0x33 => fixed(6),              // INX
+        0x0B | 0x1B | 0x2B | 0x3B => fixed(6),              // DCX
+        0x34 | 0x35 => fixed(10),                           // INR M, DCR M
+        0x36 => fixed(10),                                  // MVI M
+        0x08 | 0x18 => fixed(10),                           // DSUB, RDEL
+        0x10 => fixed(7),                                   // ARHL
+        0x28 | 0x38 => fixed(10),                           // LDHI, LDSI
+        0x00..=0x3F => match opcode & 7 {
+            4 | 5 => fixed(4),                              // INR, DCR
+            6 => fixed(7),                                  // MVI
+            7 => fixed(4),                                  // rotates, DAA, CMA, STC, CMC
+            _ => None,
+        },
+        0xC9 => fixed(10),                                  // RET
+        0xC3 | 0xD3 | 0xDB => fixed(10),                    // JMP, OUT, IN
+        0xCD => fixed(18),                                  // CALL
+        0xE3 => fixed(16),                                  // XTHL
+        0xE9 | 0xF9 => fixed(6),                            // PCHL, SPHL
+        0xEB | 0xF3 | 0xFB => fixed(4),                     // XCHG, DI, EI
+        0xC1 | 0xD1 | 0xE1 | 0xF1 => fixed(10),             // POP
+        0xC5 | 0xD5 | 0xE5 | 0xF5 => fixed(12),             // PUSH
+        0xCB => Some((6, 12)),                              // RSTV
+        0xD9 | 0xED => fixed(10),                           // SHLX, LHLX
+        0xDD | 0xFD => Some((7, 10)),                       // JNK, JK
+        _ => match opcode & 7 {
+            0 => Some((6, 12)),                             // conditional returns
+            2 => Some((7, 10)),                             // conditional jumps
+            4 => Some((9, 18)),                             // conditional calls
+            6 => fixed(7),                                  // immediate arithmetic
+            7 => fixed(12),                                 // RST
+            _ => None,
+        },
+    }
+}
+
+/// Machine cycles making up an instruction that takes `t_states`. The opcode fetch takes 4
+/// T-states, or 6 for instructions that work on register pairs, and every other cycle takes 3.
+pub fn machine_cycles(t_states: u8) -> u8 {
+    1 + (t_states - 4) / 3
+}
@@ -1,3 +1,6 @@
+use std::rc::Rc;
+
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Eq, PartialEq, Debug, Clone)]
 pub enum Register {
     A,
@@ -12,12 +15,39 @@ pub enum Register {
     PSW,
 }
 
+#[derive(Eq, PartialEq, Debug, Clone)]
+pub enum Operator {
+    Plus,
+    Minus,
+    Multiply,
+    Divide,
+    Modulo,
+    ShiftLeft,
+    ShiftRight,
+    And,
+    Or,
+    Xor,
+    Not,
+    High,
+    Low,
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+}
+
 #[derive(Debug, Clone)]
 pub enum TokenType {
     Operation(String),
-    U8(u8),
-    U16(u16),
+    Number(u16),
     Label(String),
+    Str(String),
+    Operator(Operator),
+    OpenParen,
+    CloseParen,
+    Location,
     Comma,
     Colon,
     Register(Register),
@@ -32,10 +62,58 @@ impl PartialEq for TokenType {
 
 impl Eq for TokenType {}
 
+impl TokenType {
+    /// The kind of token, as it reads in an error message: `a register`, `a number`, ...
+    pub fn kind(&self) -> &'static str {
+        match self {
+            TokenType::Operation(_) => "an instruction or directive",
+            TokenType::Number(_) => "a number",
+            TokenType::Label(_) => "a label",
+            TokenType::Str(_) => "a string",
+            TokenType::Operator(_) => "an operator",
+            TokenType::OpenParen => "`(`",
+            TokenType::CloseParen => "`)`",
+            TokenType::Location => "`$`",
+            TokenType::Comma => "`,`",
+            TokenType::Colon => "`:`",
+            TokenType::Register(_) => "a register",
+            TokenType::End => "the end of the file",
+        }
+    }
+
+    /// This token, as it reads in an error message: `the number 5`, `register B`, ...
+    pub fn describe(&self) -> String {
+        match self {
+            TokenType::Operation(operation) => format!("`{}`", operation),
+            TokenType::Number(number) => format!("the number {}", number),
+            TokenType::Label(label) => format!("the label `{}`", label),
+            TokenType::Str(text) => format!("the string \"{}\"", text),
+            TokenType::Operator(operator) => format!("the operator `{}`", operator),
+            TokenType::Register(register) => format!("register {:?}", register),
+            other => other.kind().to_owned(),
+        }
+    }
+}
+
+/// Records which macro invocation produced a token. `call_site` is the position of the macro
+/// name in the invoking code (in `file`), which may itself be inside another expansion (`parent`).
+#[derive(Debug)]
+pub struct Expansion {
+    pub name: String,
+    pub call_site: (usize, usize),
+    pub file: Option<Rc<str>>,
+    pub parent: Option<Rc<Expansion>>,
+}
+
 #[derive(Debug, Clone)]
 pub struct Token {
     pub position: (usize, usize),
+    /// Length of the lexeme in bytes, so the token spans `position.1..position.1 + length`.
+    pub length: usize,
     pub token: TokenType,
+    pub expansion: Option<Rc<Expansion>>,
+    /// The source file the token was read from, `None` for source given as a string.
+    pub file: Option<Rc<str>>,
 }
 
 pub struct TokenStream {
@@ -43,13 +121,39 @@ pub struct TokenStream {
 }
 
 impl TokenStream {
-    pub fn iter(&mut self) -> std::slice::Iter<Token> {
+    pub fn iter(&mut self) -> std::slice::Iter<'_, Token> {
         self.tokens.iter()
     }
 }
 
 use std::fmt::Display;
 
+impl Display for Operator {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Operator::Plus => "+",
+            Operator::Minus => "-",
+            Operator::Multiply => "*",
+            Operator::Divide => "/",
+            Operator::Modulo => "MOD",
+            Operator::ShiftLeft => "SHL",
+            Operator::ShiftRight => "SHR",
+            Operator::And => "AND",
+            Operator::Or => "OR",
+            Operator::Xor => "XOR",
+            Operator::Not => "NOT",
+            Operator::High => "HIGH",
+            Operator::Low => "LOW",
+            Operator::Equal => "=",
+            Operator::NotEqual => "<>",
+            Operator::Less => "<",
+            Operator::LessEqual => "<=",
+            Operator::Greater => ">",
+            Operator::GreaterEqual => ">=",
+        })
+    }
+}
+
 impl Display for TokenStream {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.write_str("\nTokens: {\n")?;
@@ -0,0 +1,168 @@
+use std::fmt::Display;
+use std::time::{ Duration, Instant };
+
+use crate::error::ExecError;
+use crate::simulator::Microcontroller;
+
+/// How fast a paced run goes compared to the real chip.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Speed {
+    /// A multiple of the real clock rate: 0.5 is half speed, 10.0 ten times faster.
+    Times(f64),
+    /// As fast as the host allows, like `Microcontroller::start`.
+    Unlimited,
+}
+
+/// The clock a paced run keeps to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ClockConfig {
+    /// Frequency of the crystal on X1/X2 in Hz. The 8085 divides it by two, so one T-state takes
+    /// two crystal periods.
+    pub crystal_hz: u64,
+    pub speed: Speed,
+    /// How much simulated time runs between comparisons with the host clock. Shorter intervals
+    /// keep closer to real time, longer ones spend less time sleeping and waking.
+    pub resync_every: Duration,
+}
+
+/// The slowest and fastest clocks a paced run keeps to, in T-states per second. Outside them the
+/// simulated time of a run no longer fits a `Duration`, or a resync interval no longer fits a
+/// T-state count.
+const T_STATES_PER_SECOND: std::ops::RangeInclusive<f64> = 1.0..=1e12;
+
+/// A clock a paced run cannot keep to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClockError {
+    /// A `Speed::Times` factor that is zero, negative, infinite or NaN, or that takes the clock
+    /// below 1 or above 10^12 T-states a second.
+    InvalidSpeed,
+    /// A crystal of 0 Hz, which never ticks.
+    NoCrystal,
+}
+
+impl Display for ClockError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ClockError::InvalidSpeed => f.write_str("The speed must give between 1 and 10^12 T-states a second"),
+            ClockError::NoCrystal => f.write_str("The crystal frequency must not be 0 Hz"),
+        }
+    }
+}
+
+impl std::error::Error for ClockError {}
+
+impl ClockConfig {
+    /// A clock with a `crystal_hz` crystal going at `speed`, resyncing every 10 ms.
+    pub fn new(crystal_hz: u64, speed: Speed) -> Result<ClockConfig, ClockError> {
+        let clock = ClockConfig { crystal_hz, speed, resync_every: Duration::from_millis(10) };
+        clock.validate()?;
+        Ok(clock)
+    }
+
+    /// An SDK-85: a 6.144 MHz crystal for a 3.072 MHz clock.
+    pub fn sdk85() -> ClockConfig {
+        ClockConfig {
+            crystal_hz: 6_144_000,
+            speed: Speed::Times(1.0),
+            resync_every: Duration::from_millis(10),
+        }
+    }
+
+    /// Checks that the clock has a rate a run can keep to, for a config built field by field.
+    pub fn validate(&self) -> Result<(), ClockError> {
+        if self.crystal_hz == 0 {
+            return Err(ClockError::NoCrystal);
+        }
+        match self.t_states_per_second() {
+            // NaN falls outside the range too.
+            Some(rate) if !T_STATES_PER_SECOND.contains(&rate) => Err(ClockError::InvalidSpeed),
+            _ => Ok(()),
+        }
+    }
+
+    /// T-states per second of host time, `None` when the speed is unlimited.
+    pub fn t_states_per_second(&self) -> Option<f64> {
+        match self.speed {
+            Speed::Times(factor) => Some(self.crystal_hz as f64 / 2.0 * factor),
+            Speed::Unlimited => None,
+        }
+    }
+}
+
+impl Default for ClockConfig {
+    fn default() -> ClockConfig {
+        ClockConfig::sdk85()
+    }
+}
+
+/// How closely a paced run kept to its clock.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct DriftStats {
+    /// T-states executed by the run.
+    pub t_states: u64,
+    /// Simulated time the run covered, at the configured speed.
+    pub simulated: Duration,
+    /// Host time the run took.
+    pub elapsed: Duration,
+    /// Times the run compared simulated time with the host clock.
+    pub resyncs: u64,
+    /// Resyncs that found the host behind the simulated clock, with nothing to sleep off.
+    pub late: u64,
+    /// The furthest the host fell behind the simulated clock.
+    pub max_lag: Duration,
+    /// Total time spent sleeping to let the host clock catch up.
+    pub slept: Duration,
+}
+
+impl Microcontroller {
+    /// Like `start`, but paced to `clock` so that software delay loops take as long as on the
+    /// real chip. Every `clock.resync_every` of simulated time the run sleeps until the host clock
+    /// catches up; when the host is the one behind, it carries on and records the lag instead.
+    ///
+    /// A clock that fails `ClockConfig::validate` is refused before anything runs. Like `start`,
+    /// this ignores breakpoints.
+    pub fn start_paced(&mut self, clock: &ClockConfig) -> Result<DriftStats, ExecError> {
+        clock.validate()?;
+        let rate = clock.t_states_per_second();
+        let interval = rate.map(|rate| (clock.resync_every.as_secs_f64() * rate).max(1.0) as u64);
+        let start = Instant::now();
+        let first = self.cycles;
+        let mut next_resync = first.saturating_add(interval.unwrap_or(0));
+        let mut stats = DriftStats::default();
+
+        self.running = true;
+        self.halted_by = None;
+        while self.running {
+            self.tick()?;
+            if let (Some(rate), Some(interval)) = (rate, interval) {
+                if self.cycles >= next_resync || !self.running {
+                    resync(&mut stats, start, (self.cycles - first) as f64 / rate);
+                    next_resync = self.cycles.saturating_add(interval);
+                }
+            }
+        }
+
+        stats.t_states = self.cycles - first;
+        stats.elapsed = start.elapsed();
+        stats.simulated = match rate {
+            Some(rate) => Duration::from_secs_f64(stats.t_states as f64 / rate),
+            None => stats.elapsed,
+        };
+        Ok(stats)
+    }
+}
+
+/// Sleeps until `simulated` seconds have passed since `start` on the host clock, or records how
+/// late the host already is.
+fn resync(stats: &mut DriftStats, start: Instant, simulated: f64) {
+    stats.resyncs += 1;
+    let target = Duration::from_secs_f64(simulated);
+    let elapsed = start.elapsed();
+    if let Some(ahead) = target.checked_sub(elapsed) {
+        std::thread::sleep(ahead);
+        stats.slept += ahead;
+    } else {
+        stats.late += 1;
+        stats.max_lag = stats.max_lag.max(elapsed - target);
+    }
+}
@@ -0,0 +1,483 @@
+use std::cell::RefCell;
+use std::collections::{ BTreeMap, HashMap };
+use std::fmt::Display;
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+
+use crate::simulator::{ Flag, Microcontroller, Register };
+
+/// Names a breakpoint for as long as it exists.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct BreakpointId(pub u32);
+
+impl Display for BreakpointId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!("#{}", self.0))
+    }
+}
+
+/// Which accesses a watchpoint is interested in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+    ReadWrite,
+}
+
+impl Access {
+    fn covers(self, access: Access) -> bool {
+        self == Access::ReadWrite || self == access
+    }
+}
+
+/// What makes a breakpoint fire.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Trigger {
+    /// Execution reaching the instruction at an address. Fires before the instruction runs.
+    Address(u16),
+    /// Loads or stores in a range of memory, not counting instruction fetches. Fires once the
+    /// instruction making them has run, as do the other watchpoints.
+    Memory(RangeInclusive<u16>, Access),
+    /// IN or OUT on a range of ports.
+    Port(RangeInclusive<u8>, Access),
+    /// An instruction changing a register: A to L, M for the byte HL points at, or SP or PSW.
+    Register(Register),
+}
+
+/// A breakpoint or watchpoint, built up from one of its constructors.
+#[derive(Debug, Clone)]
+pub struct Breakpoint {
+    pub trigger: Trigger,
+    /// Only counts a hit when this holds too.
+    pub condition: Option<Condition>,
+    pub enabled: bool,
+    /// Hits to let pass before stopping.
+    pub ignore: u64,
+    /// Times the trigger fired with the condition holding, whether the run stopped or not.
+    pub hits: u64,
+}
+
+impl Breakpoint {
+    pub fn new(trigger: Trigger) -> Breakpoint {
+        Breakpoint { trigger, condition: None, enabled: true, ignore: 0, hits: 0 }
+    }
+
+    pub fn at(address: u16) -> Breakpoint {
+        Breakpoint::new(Trigger::Address(address))
+    }
+
+    pub fn memory(range: RangeInclusive<u16>, access: Access) -> Breakpoint {
+        Breakpoint::new(Trigger::Memory(range, access))
+    }
+
+    pub fn port(range: RangeInclusive<u8>, access: Access) -> Breakpoint {
+        Breakpoint::new(Trigger::Port(range, access))
+    }
+
+    pub fn register(register: Register) -> Breakpoint {
+        Breakpoint::new(Trigger::Register(register))
+    }
+
+    pub fn when(mut self, condition: Condition) -> Breakpoint {
+        self.condition = Some(condition);
+        self
+    }
+
+    /// Stops on hit `ignore + 1` and every hit after it.
+    pub fn ignoring(mut self, ignore: u64) -> Breakpoint {
+        self.ignore = ignore;
+        self
+    }
+}
+
+/// The breakpoints, and what the instruction being run did that watchpoints look at.
+#[derive(Default)]
+pub(crate) struct Debugger {
+    breakpoints: BTreeMap<BreakpointId, Breakpoint>,
+    next_id: u32,
+    /// Whether an enabled watchpoint needs accesses recorded, worked out at the start of a run and
+    /// cleared at its end.
+    pub(crate) recording: bool,
+    /// Memory accesses made by the instruction being run. Reads only borrow the simulator.
+    pub(crate) memory: RefCell<Vec<(u16, Access)>>,
+    pub(crate) ports: Vec<(u8, Access)>,
+    /// Register watchpoints with the value of their register before the instruction.
+    registers: Vec<(BreakpointId, u16)>,
+    /// Where a run last stopped on an address breakpoint, so that resuming does not stop again
+    /// before the instruction has run.
+    resume_at: Option<u16>,
+}
+
+impl Microcontroller {
+    pub fn add_breakpoint(&mut self, breakpoint: Breakpoint) -> BreakpointId {
+        self.debugger.next_id += 1;
+        let id = BreakpointId(self.debugger.next_id);
+        self.debugger.breakpoints.insert(id, breakpoint);
+        id
+    }
+
+    /// Adds a breakpoint at the address `symbol` has in `symbols`.
+    pub fn break_at_symbol(
+        &mut self,
+        symbol: &str,
+        symbols: &HashMap<String, u16>,
+    ) -> Result<BreakpointId, String> {
+        match symbols.get(symbol) {
+            Some(address) => Ok(self.add_breakpoint(Breakpoint::at(*address))),
+            None => Err(format!("Unknown symbol `{symbol}`")),
+        }
+    }
+
+    pub fn remove_breakpoint(&mut self, id: BreakpointId) -> Option<Breakpoint> {
+        self.debugger.breakpoints.remove(&id)
+    }
+
+    pub fn breakpoint(&self, id: BreakpointId) -> Option<&Breakpoint> {
+        self.debugger.breakpoints.get(&id)
+    }
+
+    /// For enabling and disabling a breakpoint, changing its condition or resetting its hits.
+    pub fn breakpoint_mut(&mut self, id: BreakpointId) -> Option<&mut Breakpoint> {
+        self.debugger.breakpoints.get_mut(&id)
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = (BreakpointId, &Breakpoint)> {
+        self.debugger.breakpoints.iter().map(|(id, breakpoint)| (*id, breakpoint))
+    }
+
+    /// Gets ready for a run, working out which accesses need recording.
+    pub(crate) fn start_debugging(&mut self) {
+        self.debugger.recording = self.debugger.breakpoints.values().any(|breakpoint| {
+            breakpoint.enabled && matches!(breakpoint.trigger, Trigger::Memory(..) | Trigger::Port(..))
+        });
+    }
+
+    /// Stops recording accesses once a run is over, so that `start`, `step` and `tick` outside a
+    /// run do not pay for it.
+    pub(crate) fn stop_debugging(&mut self) {
+        self.debugger.recording = false;
+        self.debugger.memory.get_mut().clear();
+        self.debugger.ports.clear();
+    }
+
+    /// Checks the address breakpoints before the instruction at the program counter runs.
+    pub(crate) fn break_before(&mut self) -> Option<BreakpointId> {
+        self.debugger.memory.get_mut().clear();
+        self.debugger.ports.clear();
+        let registers = self.debugger.breakpoints.iter()
+            .filter(|(_, breakpoint)| breakpoint.enabled)
+            .filter_map(|(id, breakpoint)| match breakpoint.trigger {
+                Trigger::Register(register) => Some((*id, self.register_value(register))),
+                _ => None,
+            })
+            .collect();
+        self.debugger.registers = registers;
+
+        let pc = self.program_counter;
+        if self.debugger.resume_at.take() == Some(pc) {
+            return None;
+        }
+        let hit = self.hit(|_, _, trigger| *trigger == Trigger::Address(pc));
+        if hit.is_some() {
+            self.debugger.resume_at = Some(pc);
+        }
+        hit
+    }
+
+    /// Checks the watchpoints after an instruction has run.
+    pub(crate) fn break_after(&mut self) -> Option<BreakpointId> {
+        let registers = std::mem::take(&mut self.debugger.registers);
+        let memory = std::mem::take(self.debugger.memory.get_mut());
+        let ports = std::mem::take(&mut self.debugger.ports);
+        self.hit(|sim, id, trigger| match trigger {
+            Trigger::Address(_) => false,
+            Trigger::Memory(range, watched) => {
+                memory.iter().any(|(address, access)| range.contains(address) && watched.covers(*access))
+            }
+            Trigger::Port(range, watched) => {
+                ports.iter().any(|(port, access)| range.contains(port) && watched.covers(*access))
+            }
+            Trigger::Register(register) => registers.iter().any(|(watched, before)| {
+                *watched == id && *before != sim.register_value(*register)
+            }),
+        })
+    }
+
+    /// Counts a hit on every enabled breakpoint whose trigger fired and whose condition holds,
+    /// returning the first that has let enough hits pass.
+    fn hit(
+        &mut self,
+        fired: impl Fn(&Microcontroller, BreakpointId, &Trigger) -> bool,
+    ) -> Option<BreakpointId> {
+        let mut hits = vec![];
+        for (id, breakpoint) in &self.debugger.breakpoints {
+            let condition = || breakpoint.condition.as_ref().is_none_or(|condition| condition.holds(self));
+            if breakpoint.enabled && fired(self, *id, &breakpoint.trigger) && condition() {
+                hits.push(*id);
+            }
+        }
+        let mut stop = None;
+        for id in hits {
+            let breakpoint = self.debugger.breakpoints.get_mut(&id).expect("hits are breakpoints");
+            breakpoint.hits += 1;
+            if breakpoint.hits > breakpoint.ignore && stop.is_none() {
+                stop = Some(id);
+            }
+        }
+        stop
+    }
+
+    fn register_value(&self, register: Register) -> u16 {
+        match register {
+            Register::M => self.peek(self.get_register_pair(Register::H).unwrap_or_default()) as u16,
+            Register::SP | Register::PSW => self.get_register_pair(register).unwrap_or_default(),
+            register => self.get_register(register).unwrap_or_default() as u16,
+        }
+    }
+}
+
+/// A condition over registers, flags and memory, like `A == 0 && CY` or `[HL] != 0FFH`.
+///
+/// Registers are `A` to `L`, `BC`, `DE`, `HL`, `SP`, `PSW` and `PC`, and `M` is the byte at HL.
+/// Flags are `CY`, `Z`, `S`, `P`, `AC`, `V` and `K`, and read as 1 or 0. `[address]` reads a
+/// byte of memory. Numbers are decimal, or hexadecimal with an `H` suffix or a `0x` prefix. The
+/// operators are `||`, `&&`, the comparisons, `|`, `&`, `+`, `-` and `!`, loosest first. Any
+/// value other than 0 counts as true.
+#[derive(Debug, Clone)]
+pub struct Condition {
+    source: String,
+    expression: Expression,
+}
+
+#[derive(Debug, Clone)]
+enum Expression {
+    Number(u16),
+    Register(Register),
+    Pair(Register),
+    ProgramCounter,
+    Flag(Flag),
+    Memory(Box<Expression>),
+    Not(Box<Expression>),
+    Binary(&'static str, Box<Expression>, Box<Expression>),
+}
+
+/// Binary operators by how tightly they bind, loosest first.
+static PRECEDENCE: [&[&str]; 6] = [
+    &["||"],
+    &["&&"],
+    &["==", "!=", "<=", ">=", "<", ">"],
+    &["|"],
+    &["&"],
+    &["+", "-"],
+];
+
+/// Every operator and bracket, longest first so that `<=` is not read as `<`.
+static SYMBOLS: [&str; 17] = [
+    "==", "!=", "<=", ">=", "&&", "||", "<", ">", "!", "&", "|", "+", "-", "(", ")", "[", "]",
+];
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(u16),
+    Name(String),
+    Symbol(&'static str),
+}
+
+impl Condition {
+    pub fn parse(source: &str) -> Result<Condition, String> {
+        let tokens = tokenize(source)?;
+        let mut parser = Parser { tokens: &tokens, position: 0 };
+        let expression = parser.expression(0)?;
+        if let Some(token) = parser.tokens.get(parser.position) {
+            return Err(format!("Unexpected {} in condition `{source}`", describe(token)));
+        }
+        Ok(Condition { source: source.trim().to_owned(), expression })
+    }
+
+    pub fn evaluate(&self, sim: &Microcontroller) -> u16 {
+        evaluate(&self.expression, sim)
+    }
+
+    pub fn holds(&self, sim: &Microcontroller) -> bool {
+        self.evaluate(sim) != 0
+    }
+}
+
+impl FromStr for Condition {
+    type Err = String;
+
+    fn from_str(source: &str) -> Result<Condition, String> {
+        Condition::parse(source)
+    }
+}
+
+impl Display for Condition {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+fn tokenize(source: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = vec![];
+    let mut rest = source.trim_start();
+    while let Some(first) = rest.chars().next() {
+        if first.is_ascii_alphanumeric() || first == '_' {
+            let end = rest.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_')).unwrap_or(rest.len());
+            let word = rest[..end].to_ascii_uppercase();
+            tokens.push(match first.is_ascii_digit() {
+                true => {
+                    let value = number(&word).ok_or(format!("Bad number `{}` in condition", &rest[..end]))?;
+                    Token::Number(value)
+                }
+                false => Token::Name(word),
+            });
+            rest = &rest[end..];
+        } else {
+            let symbol = SYMBOLS.iter().find(|symbol| rest.starts_with(**symbol))
+                .ok_or(format!("Unexpected `{first}` in condition `{}`", source.trim()))?;
+            tokens.push(Token::Symbol(symbol));
+            rest = &rest[symbol.len()..];
+        }
+        rest = rest.trim_start();
+    }
+    Ok(tokens)
+}
+
+fn number(word: &str) -> Option<u16> {
+    if let Some(hex) = word.strip_prefix("0X") {
+        u16::from_str_radix(hex, 16).ok()
+    } else if let Some(hex) = word.strip_suffix('H') {
+        u16::from_str_radix(hex, 16).ok()
+    } else {
+        word.parse().ok()
+    }
+}
+
+fn describe(token: &Token) -> String {
+    match token {
+        Token::Number(value) => format!("`{value}`"),
+        Token::Name(name) => format!("`{name}`"),
+        Token::Symbol(symbol) => format!("`{symbol}`"),
+    }
+}
+
+struct Parser<'a> {
+    tokens: &'a [Token],
+    position: usize,
+}
+
+impl Parser<'_> {
+    fn next(&mut self) -> Result<&Token, String> {
+        let token = self.tokens.get(self.position).ok_or("Condition ends too early")?;
+        self.position += 1;
+        Ok(token)
+    }
+
+    fn expect(&mut self, symbol: &'static str) -> Result<(), String> {
+        match self.next()? {
+            Token::Symbol(found) if *found == symbol => Ok(()),
+            token => Err(format!("Expected `{symbol}` in condition, found {}", describe(token))),
+        }
+    }
+
+    /// Parses operators from `PRECEDENCE[level]` on, left to right.
+    fn expression(&mut self, level: usize) -> Result<Expression, String> {
+        let Some(operators) = PRECEDENCE.get(level) else {
+            return self.unary();
+        };
+        let mut left = self.expression(level + 1)?;
+        while let Some(Token::Symbol(symbol)) = self.tokens.get(self.position) {
+            if !operators.contains(symbol) {
+                break;
+            }
+            self.position += 1;
+            let right = self.expression(level + 1)?;
+            left = Expression::Binary(symbol, Box::new(left), Box::new(right));
+        }
+        Ok(left)
+    }
+
+    fn unary(&mut self) -> Result<Expression, String> {
+        match self.next()?.clone() {
+            Token::Number(value) => Ok(Expression::Number(value)),
+            Token::Symbol("!") => Ok(Expression::Not(Box::new(self.unary()?))),
+            Token::Symbol("(") => {
+                let expression = self.expression(0)?;
+                self.expect(")")?;
+                Ok(expression)
+            }
+            Token::Symbol("[") => {
+                let address = self.expression(0)?;
+                self.expect("]")?;
+                Ok(Expression::Memory(Box::new(address)))
+            }
+            Token::Name(name) => {
+                name_to_expression(&name).ok_or(format!("Unknown name `{name}` in condition"))
+            }
+            token => Err(format!("Unexpected {} in condition", describe(&token))),
+        }
+    }
+}
+
+fn name_to_expression(name: &str) -> Option<Expression> {
+    use Expression::{ Flag as F, Pair, Register as R };
+    Some(match name {
+        "A" => R(Register::A),
+        "B" => R(Register::B),
+        "C" => R(Register::C),
+        "D" => R(Register::D),
+        "E" => R(Register::E),
+        "H" => R(Register::H),
+        "L" => R(Register::L),
+        "M" => Expression::Memory(Box::new(Pair(Register::H))),
+        "BC" => Pair(Register::B),
+        "DE" => Pair(Register::D),
+        "HL" => Pair(Register::H),
+        "SP" => Pair(Register::SP),
+        "PSW" => Pair(Register::PSW),
+        "PC" => Expression::ProgramCounter,
+        "CY" => F(Flag::Carry),
+        "Z" => F(Flag::Zero),
+        "S" => F(Flag::Sign),
+        "P" => F(Flag::Parity),
+        "AC" => F(Flag::AuxCarry),
+        "V" => F(Flag::Overflow),
+        "K" => F(Flag::Underflow),
+        _ => return None,
+    })
+}
+
+fn evaluate(expression: &Expression, sim: &Microcontroller) -> u16 {
+    match expression {
+        Expression::Number(value) => *value,
+        Expression::Register(register) => sim.get_register(*register).unwrap_or_default() as u16,
+        Expression::Pair(register) => sim.get_register_pair(*register).unwrap_or_default(),
+        Expression::ProgramCounter => sim.program_counter,
+        Expression::Flag(flag) => sim.check_flag(*flag) as u16,
+        Expression::Memory(address) => sim.peek(evaluate(address, sim)) as u16,
+        Expression::Not(operand) => (evaluate(operand, sim) == 0) as u16,
+        Expression::Binary(operator, left, right) => {
+            let left = evaluate(left, sim);
+            // `&&` and `||` only look at the right when they need to.
+            match *operator {
+                "&&" => return (left != 0 && evaluate(right, sim) != 0) as u16,
+                "||" => return (left != 0 || evaluate(right, sim) != 0) as u16,
+                _ => {}
+            }
+            let right = evaluate(right, sim);
+            match *operator {
+                "==" => (left == right) as u16,
+                "!=" => (left != right) as u16,
+                "<=" => (left <= right) as u16,
+                ">=" => (left >= right) as u16,
+                "<" => (left < right) as u16,
+                ">" => (left > right) as u16,
+                "|" => left | right,
+                "&" => left & right,
+                "+" => left.wrapping_add(right),
+                "-" => left.wrapping_sub(right),
+                _ => unreachable!("`{operator}` is not a binary operator"),
+            }
+        }
+    }
+}
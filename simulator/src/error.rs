@@ -0,0 +1,60 @@
+use std::fmt::Display;
+
+use crate::clock::ClockError;
+use crate::fault::Fault;
+
+/// Why the simulator could not carry on running a program.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExecError {
+    /// An opcode with no instruction behind it, fetched from `pc`.
+    IllegalOpcode { opcode: u8, pc: u16 },
+    /// A fault the `FaultPolicy` says to halt on.
+    MemoryFault(Fault),
+    /// A push or pop at `sp` that ran off the RAM: into ROM, into addresses with nothing there, or
+    /// round the end of the address space. The instruction starts at `pc`.
+    StackFault { sp: u16, pc: u16 },
+    /// The program ran HLT, or was never started.
+    Halted,
+    /// A paced run was given a clock it cannot keep to.
+    InvalidClock(ClockError),
+    /// A register used where it cannot be. A bug in the simulator rather than in the program.
+    Register(&'static str),
+}
+
+impl From<&'static str> for ExecError {
+    fn from(message: &'static str) -> ExecError {
+        ExecError::Register(message)
+    }
+}
+
+impl From<Fault> for ExecError {
+    fn from(fault: Fault) -> ExecError {
+        ExecError::MemoryFault(fault)
+    }
+}
+
+impl From<ClockError> for ExecError {
+    fn from(error: ClockError) -> ExecError {
+        ExecError::InvalidClock(error)
+    }
+}
+
+impl Display for ExecError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ExecError::IllegalOpcode { opcode, pc } => {
+                f.write_fmt(format_args!("Illegal opcode {:02X}H at {:04X}H", opcode, pc))
+            }
+            ExecError::MemoryFault(fault) => fault.fmt(f),
+            ExecError::StackFault { sp, pc } => f.write_fmt(format_args!(
+                "The stack ran out of RAM at {:04X}H, in the instruction at {:04X}H",
+                sp, pc
+            )),
+            ExecError::Halted => f.write_str("Microcontroller not started!"),
+            ExecError::InvalidClock(error) => error.fmt(f),
+            ExecError::Register(message) => f.write_str(message),
+        }
+    }
+}
+
+impl std::error::Error for ExecError {}
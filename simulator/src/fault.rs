@@ -0,0 +1,100 @@
+use std::fmt::Display;
+
+/// An access a correct program would not make.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultKind {
+    /// A store to ROM, which the ROM ignores.
+    RomWrite,
+    /// A load from an address nothing is mapped to, which reads the open bus.
+    UnmappedRead,
+    /// A store to an address nothing is mapped to, which goes nowhere.
+    UnmappedWrite,
+    /// An instruction fetched from memory marked as data.
+    ExecuteData,
+    /// The program counter running past FFFFH back to 0000H.
+    PcWrap,
+}
+
+/// What to do about one kind of fault.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FaultAction {
+    /// Carry on as the hardware would.
+    #[default]
+    Ignore,
+    /// Carry on, but add the fault to `Microcontroller::faults`.
+    Log,
+    /// Log the fault and stop running. A fault found fetching an instruction stops before it runs.
+    Halt,
+}
+
+/// What to do about each kind of fault. Everything is ignored by default, like on real hardware.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct FaultPolicy {
+    pub rom_write: FaultAction,
+    pub unmapped_read: FaultAction,
+    pub unmapped_write: FaultAction,
+    pub execute_data: FaultAction,
+    pub pc_wrap: FaultAction,
+}
+
+impl FaultPolicy {
+    /// Halts on every fault, for running programs that are expected to be correct.
+    pub fn strict() -> FaultPolicy {
+        FaultPolicy {
+            rom_write: FaultAction::Halt,
+            unmapped_read: FaultAction::Halt,
+            unmapped_write: FaultAction::Halt,
+            execute_data: FaultAction::Halt,
+            pc_wrap: FaultAction::Halt,
+        }
+    }
+
+    pub fn action(&self, kind: FaultKind) -> FaultAction {
+        match kind {
+            FaultKind::RomWrite => self.rom_write,
+            FaultKind::UnmappedRead => self.unmapped_read,
+            FaultKind::UnmappedWrite => self.unmapped_write,
+            FaultKind::ExecuteData => self.execute_data,
+            FaultKind::PcWrap => self.pc_wrap,
+        }
+    }
+}
+
+/// A fault, with where it happened.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Fault {
+    pub kind: FaultKind,
+    /// The address accessed.
+    pub address: u16,
+    /// Where the instruction making the access starts.
+    pub pc: u16,
+    /// That instruction, disassembled.
+    pub instruction: String,
+}
+
+impl Display for Fault {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let Fault { kind, address, pc, instruction } = self;
+        match kind {
+            FaultKind::RomWrite => f.write_fmt(format_args!(
+                "`{}` at {:04X}H wrote to ROM at {:04X}H",
+                instruction, pc, address
+            )),
+            FaultKind::UnmappedRead => f.write_fmt(format_args!(
+                "`{}` at {:04X}H read from {:04X}H, where there is no memory",
+                instruction, pc, address
+            )),
+            FaultKind::UnmappedWrite => f.write_fmt(format_args!(
+                "`{}` at {:04X}H wrote to {:04X}H, where there is no memory",
+                instruction, pc, address
+            )),
+            FaultKind::ExecuteData => {
+                f.write_fmt(format_args!("The program jumped into data at {:04X}H", address))
+            }
+            FaultKind::PcWrap => f.write_fmt(format_args!(
+                "The program ran past FFFFH after `{}` at {:04X}H",
+                instruction, pc
+            )),
+        }
+    }
+}
@@ -1,6 +1,7 @@
 use crate::simulator::Register;
 use crate::simulator::Microcontroller;
 use crate::simulator::Flag;
+use crate::error::ExecError;
 
 trait Arith<T> {
     fn sub(&self, other: T) -> T;
@@ -22,323 +23,362 @@ impl Arith<u8> for u8 {
 
 impl Arith<u16> for u16 {
     fn sub(&self, other: Self) -> Self {
-        (*self as u32 + (!other + 1) as u32) as u16
+        self.wrapping_sub(other)
     }
     fn add(&self, other: Self) -> Self {
-        (*self as u32 + other as u32) as u16
+        self.wrapping_add(other)
     }
 }
 
-fn _add(controller: &mut Microcontroller, other: u8, carry: u8) {
-    let a = controller.get_register(Register::A).unwrap();
+/// Sets V when a signed result did not fit, and K to the true sign of that result.
+fn update_overflow(controller: &mut Microcontroller, overflow: bool) {
+    let sign = controller.check_flag(Flag::Sign);
+    controller.set_flag(Flag::Overflow, overflow);
+    controller.set_flag(Flag::Underflow, sign ^ overflow);
+}
+
+fn _add(controller: &mut Microcontroller, other: u8, carry: u8) -> Result<(), ExecError> {
+    let a = controller.get_register(Register::A)?;
     let c = controller.check_flag(Flag::Carry) as u8 * carry;
     let sum = a.add(other).add(c);
     let ac = (((a & 0b00001111).add(c)).add(other & 0b00001111)) > 0b00001001;
     let c = (a as u16 + other as u16 + c as u16) > 255;
-    controller.set_register(Register::A, sum).unwrap();
+    controller.set_register(Register::A, sum)?;
     controller.update_flags(ac, c);
+    update_overflow(controller, (a ^ sum) & (other ^ sum) & 0b10000000 != 0);
+    Ok(())
 }
 
 #[allow(dead_code)]
-fn dadd(controller : &mut Microcontroller, reg: Register) {
-    let a = controller.get_register_pair(Register::H).unwrap();
-    let b = controller.get_register_pair(reg).unwrap();
+fn dadd(controller : &mut Microcontroller, reg: Register) -> Result<(), ExecError> {
+    let a = controller.get_register_pair(Register::H)?;
+    let b = controller.get_register_pair(reg)?;
     let c = controller.check_flag(Flag::Carry) as u8;
     let sum = a.add(b).add(c as u16);
     let ac = (((a & 0b00001111) + c as u16) + (b & 0b00001111)) > 0b00001001;
     let c = (a as u32 + b as u32 + c as u32) > 65535;
     controller.set_flag(Flag::Carry, c);
     controller.set_flag(Flag::AuxCarry, ac);
-    controller.set_register_pair(Register::H, sum).unwrap();
+    controller.set_register_pair(Register::H, sum)?;
+    Ok(())
 }
 
 #[allow(dead_code)]
-fn add(controller: &mut Microcontroller, reg: Register) {
-    let b = controller.get_register(reg).unwrap();
-    _add(controller, b, 0);
+fn add(controller: &mut Microcontroller, reg: Register) -> Result<(), ExecError> {
+    let b = controller.get_register(reg)?;
+    _add(controller, b, 0)?;
+    Ok(())
 }
 
 #[allow(dead_code)]
-fn adc(controller: &mut Microcontroller, reg: Register) {
-    let b = controller.get_register(reg).unwrap();
-    _add(controller, b, 1);
+fn adc(controller: &mut Microcontroller, reg: Register) -> Result<(), ExecError> {
+    let b = controller.get_register(reg)?;
+    _add(controller, b, 1)?;
+    Ok(())
 }
 
 #[allow(dead_code)]
-fn adi(controller : &mut Microcontroller) {
+fn adi(controller : &mut Microcontroller) -> Result<(), ExecError> {
     controller.fetch();
     let b = controller.instruction_register;
-    _add(controller, b, 0);
+    _add(controller, b, 0)?;
+    Ok(())
 }
 
 #[allow(dead_code)]
-fn aci(controller : &mut Microcontroller) {
+fn aci(controller : &mut Microcontroller) -> Result<(), ExecError> {
     controller.fetch();
     let b = controller.instruction_register;
-    _add(controller, b, 1);
+    _add(controller, b, 1)?;
+    Ok(())
 }
 
 #[allow(dead_code)]
-fn _sub(controller : &mut Microcontroller, other: u8, carry: u8) {
-    let a = controller.get_register(Register::A).unwrap();
+fn _sub(controller : &mut Microcontroller, other: u8, carry: u8) -> Result<(), ExecError> {
+    let a = controller.get_register(Register::A)?;
     let c = controller.check_flag(Flag::Carry) as u8 * carry;
     let sum = a.sub(other).sub(c);
     let ac = (((a & 0b00001111).sub(c)).sub(other & 0b00001111)) > 0b00001001;
-    let c = (a.sub(other).sub(c)) > 127;
-    controller.set_register(Register::A, sum).unwrap();
-    controller.update_flags(ac, c);
+    let borrow = (a as u16) < other as u16 + c as u16;
+    controller.set_register(Register::A, sum)?;
+    controller.update_flags(ac, borrow);
+    update_overflow(controller, (a ^ other) & (a ^ sum) & 0b10000000 != 0);
+    Ok(())
 }
 
 #[allow(dead_code)]
-fn sub(controller: &mut Microcontroller, reg: Register, carry: u8) {
-    let b = controller.get_register(reg).unwrap();
-    _sub(controller, b, carry);
+fn sub(controller: &mut Microcontroller, reg: Register, carry: u8) -> Result<(), ExecError> {
+    let b = controller.get_register(reg)?;
+    _sub(controller, b, carry)?;
+    Ok(())
 }
 
 #[allow(dead_code)]
-fn sbi(controller : &mut Microcontroller) {
+fn sbi(controller : &mut Microcontroller) -> Result<(), ExecError> {
     controller.fetch();
     let b = controller.instruction_register;
-    _sub(controller, b, 1);
+    _sub(controller, b, 1)?;
+    Ok(())
 }
 
 #[allow(dead_code)]
-fn cmp(controller : &mut Microcontroller, reg: Register) {
-    let a = controller.get_register(Register::A).unwrap();
-    sub(controller, reg, 0);
-    controller.set_register(Register::A, a).unwrap();
+fn cmp(controller : &mut Microcontroller, reg: Register) -> Result<(), ExecError> {
+    let a = controller.get_register(Register::A)?;
+    sub(controller, reg, 0)?;
+    controller.set_register(Register::A, a)?;
+    Ok(())
 }
 
 #[allow(dead_code)]
-fn cpi(controller : &mut Microcontroller) {
-    let a = controller.get_register(Register::A).unwrap();
-    sbi(controller);
-    controller.set_register(Register::A, a).unwrap();
+fn cpi(controller : &mut Microcontroller) -> Result<(), ExecError> {
+    let a = controller.get_register(Register::A)?;
+    sbi(controller)?;
+    controller.set_register(Register::A, a)?;
+    Ok(())
 }
 
 #[allow(dead_code)]
-fn mov(controller: &mut Microcontroller, to: Register, from: Register) {
-    let data = controller.get_register(from).unwrap();
-    controller.set_register(to, data).unwrap();
+fn mov(controller: &mut Microcontroller, to: Register, from: Register) -> Result<(), ExecError> {
+    let data = controller.get_register(from)?;
+    controller.set_register(to, data)?;
+    Ok(())
 }
 
 #[allow(dead_code)]
-fn mvi(controller: &mut Microcontroller, to: Register) {
+fn mvi(controller: &mut Microcontroller, to: Register) -> Result<(), ExecError> {
     controller.fetch();
     let data = controller.instruction_register;
-    controller.set_register(to, data).unwrap();
+    controller.set_register(to, data)?;
+    Ok(())
 }
 
 #[allow(dead_code)]
-fn ora(controller : &mut Microcontroller, other : Register) {
-    let mut val = controller.get_register(Register::A).unwrap();
-    val |= controller.get_register(other).unwrap();
-    controller.set_register(Register::A, val).unwrap();
+fn ora(controller : &mut Microcontroller, other : Register) -> Result<(), ExecError> {
+    let mut val = controller.get_register(Register::A)?;
+    val |= controller.get_register(other)?;
+    controller.set_register(Register::A, val)?;
     controller.update_flags_logical();
+    Ok(())
 }
 
 #[allow(dead_code)]
-fn ori(controller : &mut Microcontroller) {
-    let mut val = controller.get_register(Register::A).unwrap();
+fn ori(controller : &mut Microcontroller) -> Result<(), ExecError> {
+    let mut val = controller.get_register(Register::A)?;
     controller.fetch();
     val |= controller.instruction_register;
-    controller.set_register(Register::A, val).unwrap();
+    controller.set_register(Register::A, val)?;
     controller.update_flags_logical();
+    Ok(())
 }
 
 #[allow(dead_code)]
-fn ana(controller : &mut Microcontroller, other : Register) {
-    let mut val = controller.get_register(Register::A).unwrap();
-    val &= controller.get_register(other).unwrap();
-    controller.set_register(Register::A, val).unwrap();
+fn ana(controller : &mut Microcontroller, other : Register) -> Result<(), ExecError> {
+    let mut val = controller.get_register(Register::A)?;
+    val &= controller.get_register(other)?;
+    controller.set_register(Register::A, val)?;
     controller.update_flags_logical();
+    Ok(())
 }
 
 #[allow(dead_code)]
-fn ani(controller : &mut Microcontroller) {
-    let mut val = controller.get_register(Register::A).unwrap();
+fn ani(controller : &mut Microcontroller) -> Result<(), ExecError> {
+    let mut val = controller.get_register(Register::A)?;
     controller.fetch();
     val &= controller.instruction_register;
-    controller.set_register(Register::A, val).unwrap();
+    controller.set_register(Register::A, val)?;
     controller.update_flags_logical();
+    Ok(())
 }
 
 #[allow(dead_code)]
-fn xra(controller : &mut Microcontroller, other : Register) {
-    let mut val = controller.get_register(Register::A).unwrap();
-    val ^= controller.get_register(other).unwrap();
-    controller.set_register(Register::A, val).unwrap();
+fn xra(controller : &mut Microcontroller, other : Register) -> Result<(), ExecError> {
+    let mut val = controller.get_register(Register::A)?;
+    val ^= controller.get_register(other)?;
+    controller.set_register(Register::A, val)?;
     controller.update_flags_logical();
+    Ok(())
 }
 
 #[allow(dead_code)]
-fn xri(controller : &mut Microcontroller) {
+fn xri(controller : &mut Microcontroller) -> Result<(), ExecError> {
     controller.fetch();
-    let mut val = controller.get_register(Register::A).unwrap();
+    let mut val = controller.get_register(Register::A)?;
     val ^= controller.instruction_register;
-    controller.set_register(Register::A, val).unwrap();
+    controller.set_register(Register::A, val)?;
     controller.update_flags_logical();
+    Ok(())
 }
 
 #[allow(dead_code)]
-fn inr(controller: &mut Microcontroller, reg: Register) {
-    let val = controller.get_register(reg).unwrap();
+fn inr(controller: &mut Microcontroller, reg: Register) -> Result<(), ExecError> {
+    let val = controller.get_register(reg)?;
     let new_val = val.add(1);
     let c = val == 255;
-    controller.set_register(reg, new_val).unwrap();
+    controller.set_register(reg, new_val)?;
     controller.set_flag(Flag::Zero, new_val == 0);
     controller.set_flag(Flag::Carry, c);
     controller.set_flag(Flag::Sign, new_val > 127);
     controller.set_flag(Flag::Parity, Microcontroller::check_parity(new_val));
+    update_overflow(controller, val == 0x7F);
+    Ok(())
 }
 
 #[allow(dead_code)]
-fn dcr(controller: &mut Microcontroller, reg: Register) {
-    let val = controller.get_register(reg).unwrap();
+fn dcr(controller: &mut Microcontroller, reg: Register) -> Result<(), ExecError> {
+    let val = controller.get_register(reg)?;
     let new_val = val.sub(1);
     let c = val == 0;
-    controller.set_register(reg, new_val).unwrap();
+    controller.set_register(reg, new_val)?;
     controller.set_flag(Flag::Zero, new_val == 0);
     controller.set_flag(Flag::Carry, c);
     controller.set_flag(Flag::Sign, new_val > 127);
     controller.set_flag(Flag::Parity, Microcontroller::check_parity(new_val));
+    update_overflow(controller, val == 0x80);
+    Ok(())
 }
 
 #[allow(dead_code)]
-fn inx(controller: &mut Microcontroller, reg: Register) {
-    let val = controller.get_register_pair(reg).unwrap();
+fn inx(controller: &mut Microcontroller, reg: Register) -> Result<(), ExecError> {
+    let val = controller.get_register_pair(reg)?;
     let new_val = val.add(1);
-    let c = val == 65535;
-    controller.set_register_pair(reg, new_val).unwrap();
-    controller.set_flag(Flag::Zero, new_val == 0);
-    controller.set_flag(Flag::Carry, c);
-    controller.set_flag(Flag::Sign, new_val > 32768);
-    controller.set_flag(Flag::Parity, Microcontroller::check_parity_16(new_val));
+    controller.set_register_pair(reg, new_val)?;
+    controller.set_flag(Flag::Underflow, new_val == 0);
+    Ok(())
 }
 
 #[allow(dead_code)]
-fn dcx(controller: &mut Microcontroller, reg: Register) {
-    let val = controller.get_register_pair(reg).unwrap();
+fn dcx(controller: &mut Microcontroller, reg: Register) -> Result<(), ExecError> {
+    let val = controller.get_register_pair(reg)?;
     let new_val = val.sub(1);
-    let c = val == 65535;
-    controller.set_register_pair(reg, new_val).unwrap();
-    controller.set_flag(Flag::Zero, new_val == 0);
-    controller.set_flag(Flag::Carry, c);
-    controller.set_flag(Flag::Sign, new_val > 32768);
-    controller.set_flag(Flag::Parity, Microcontroller::check_parity_16(new_val));
+    controller.set_register_pair(reg, new_val)?;
+    controller.set_flag(Flag::Underflow, new_val == 0xFFFF);
+    Ok(())
 }
 
 #[allow(dead_code)]
-fn lxi(controller: &mut Microcontroller, reg: Register) {
+fn lxi(controller: &mut Microcontroller, reg: Register) -> Result<(), ExecError> {
     let low = controller.fetch();
     let high = controller.fetch();
     let val = (high as u16) << 8 | low as u16;
-    controller.set_register_pair(reg, val).unwrap();
+    controller.set_register_pair(reg, val)?;
+    Ok(())
 }
 
 #[allow(dead_code)]
-fn lda(controller: &mut Microcontroller) {
+fn lda(controller: &mut Microcontroller) -> Result<(), ExecError> {
     let low = controller.fetch();
     let high = controller.fetch();
     let addr = (high as u16) << 8 | low as u16;
-    controller.set_register(Register::A, controller.get_data_at(Some(addr))).unwrap();
+    controller.set_register(Register::A, controller.get_data_at(Some(addr)))?;
+    Ok(())
 }
 
 #[allow(dead_code)]
-fn ldax(controller: &mut Microcontroller, reg: Register) {
-    let addr = controller.get_register_pair(reg).unwrap();
-    controller.set_register(Register::A, controller.get_data_at(Some(addr))).unwrap();
+fn ldax(controller: &mut Microcontroller, reg: Register) -> Result<(), ExecError> {
+    let addr = controller.get_register_pair(reg)?;
+    controller.set_register(Register::A, controller.get_data_at(Some(addr)))?;
+    Ok(())
 }
 
 #[allow(dead_code)]
-fn lhld(controller: &mut Microcontroller) {
+fn lhld(controller: &mut Microcontroller) -> Result<(), ExecError> {
     let low = controller.fetch();
     let high = controller.fetch();
     let addr = (high as u16) << 8 | low as u16;
-    controller.set_register(Register::L, controller.get_data_at(Some(addr))).unwrap();
-    controller.set_register(Register::H, controller.get_data_at(Some(addr.add(1)))).unwrap();
+    controller.set_register(Register::L, controller.get_data_at(Some(addr)))?;
+    controller.set_register(Register::H, controller.get_data_at(Some(addr.add(1))))?;
+    Ok(())
 }
 
 #[allow(dead_code)]
-fn sta(controller: &mut Microcontroller) {
+fn sta(controller: &mut Microcontroller) -> Result<(), ExecError> {
     let low = controller.fetch();
     let high = controller.fetch();
     let addr = (high as u16) << 8 | low as u16;
-    controller.set_data_at(Some(addr), controller.get_register(Register::A).unwrap());
+    controller.set_data_at(Some(addr), controller.get_register(Register::A)?);
+    Ok(())
 }
 
 #[allow(dead_code)]
-fn stax(controller: &mut Microcontroller, reg: Register) {
-    let addr = controller.get_register_pair(reg).unwrap();
-    controller.set_data_at(Some(addr), controller.get_register(Register::A).unwrap());
+fn stax(controller: &mut Microcontroller, reg: Register) -> Result<(), ExecError> {
+    let addr = controller.get_register_pair(reg)?;
+    controller.set_data_at(Some(addr), controller.get_register(Register::A)?);
+    Ok(())
 }
 
 #[allow(dead_code)]
-fn shld(controller: &mut Microcontroller) {
+fn shld(controller: &mut Microcontroller) -> Result<(), ExecError> {
     let low = controller.fetch();
     let high = controller.fetch();
     let addr = (high as u16) << 8 | low as u16;
-    controller.set_data_at(Some(addr), controller.get_register(Register::L).unwrap());
-    controller.set_data_at(Some(addr.add(1)), controller.get_register(Register::H).unwrap());
+    controller.set_data_at(Some(addr), controller.get_register(Register::L)?);
+    controller.set_data_at(Some(addr.add(1)), controller.get_register(Register::H)?);
+    Ok(())
 }
 
 #[allow(dead_code)]
-fn call(controller: &mut Microcontroller, skip: bool) {
-    if skip {
-        controller.fetch();
-        controller.fetch();
+fn call(controller: &mut Microcontroller, skip: bool) -> Result<(), ExecError> {
+    let low = controller.fetch();
+    let high = controller.fetch();
+    if !skip {
+        push_word(controller, controller.program_counter)?;
+        controller.program_counter = (high as u16) << 8 | low as u16;
+        controller.branch_taken = true;
     }
-    let stp = controller.get_register_pair(Register::SP).unwrap();
-    controller.set_register_pair(Register::SP, stp.add(2)).unwrap();
-    controller.set_data_at(Some(stp), (controller.program_counter >> 8) as u8);
-    controller.set_data_at(Some(stp.sub(1)), (controller.program_counter << 8 >> 8) as u8);
-    let dest = (controller.fetch() as u16) << 8 | controller.fetch() as u16;
-    controller.program_counter = dest;
+    Ok(())
 }
 
 #[allow(dead_code)]
-fn ret(controller : &mut Microcontroller) {
-    let addr = controller.get_register_pair(Register::SP).unwrap();
+fn ret(controller : &mut Microcontroller) -> Result<(), ExecError> {
+    let addr = controller.get_register_pair(Register::SP)?;
+    controller.check_stack(addr, false)?;
     let pc = ((controller.get_data_at(Some(addr.add(1))) as u16) << 8)
                 | controller.get_data_at(Some(addr)) as u16;
     controller.program_counter = pc;
-    controller.set_register_pair(Register::SP, addr + 2).unwrap();
+    controller.set_register_pair(Register::SP, addr.add(2))?;
+    controller.branch_taken = true;
+    Ok(())
 }
 
 #[allow(dead_code)]
-fn jmp(controller: &mut Microcontroller, skip: bool) {
+fn jmp(controller: &mut Microcontroller, skip: bool) -> Result<(), ExecError> {
     let low = controller.fetch();
     let high = controller.fetch();
     let addr = (high as u16) << 8 | low as u16;
     if !skip {
         controller.program_counter = addr;
+        controller.branch_taken = true;
     }
+    Ok(())
 }
 
 #[allow(dead_code)]
-fn sphl(controller: &mut Microcontroller) {
+fn sphl(controller: &mut Microcontroller) -> Result<(), ExecError> {
     controller.set_register_pair(
         Register::SP,
-        controller.get_register_pair(Register::H).unwrap()
-    ).unwrap();
+        controller.get_register_pair(Register::H)?
+    )?;
+    Ok(())
 }
 
 #[allow(dead_code)]
-fn pchl(controller: &mut Microcontroller) {
-    controller.program_counter = controller.get_register_pair(Register::H).unwrap();
+fn pchl(controller: &mut Microcontroller) -> Result<(), ExecError> {
+    controller.program_counter = controller.get_register_pair(Register::H)?;
+    Ok(())
 }
 
 #[allow(dead_code)]
-fn xchg(controller: &mut Microcontroller) {
-    let d = controller.get_register_pair(Register::D).unwrap();
-    let h = controller.get_register_pair(Register::H).unwrap();
-    controller.set_register_pair(Register::H, d).unwrap();
-    controller.set_register_pair(Register::D, h).unwrap();
+fn xchg(controller: &mut Microcontroller) -> Result<(), ExecError> {
+    let d = controller.get_register_pair(Register::D)?;
+    let h = controller.get_register_pair(Register::H)?;
+    controller.set_register_pair(Register::H, d)?;
+    controller.set_register_pair(Register::D, h)?;
+    Ok(())
 }
 
 #[allow(dead_code)]
-fn rar(controller: &mut Microcontroller) {
-    let mut val = controller.get_register(Register::A).unwrap();
+fn rar(controller: &mut Microcontroller) -> Result<(), ExecError> {
+    let mut val = controller.get_register(Register::A)?;
     let carry = val & 0b00000001 == 0b00000001;
     let prev_carry = controller.check_flag(Flag::Carry);
     controller.set_flag(Flag::Carry, carry);
@@ -347,12 +387,13 @@ fn rar(controller: &mut Microcontroller) {
         true => 0b10000000,
         false => 0b00000000
     };
-    controller.set_register(Register::A, val).unwrap();
+    controller.set_register(Register::A, val)?;
+    Ok(())
 }
 
 #[allow(dead_code)]
-fn ral(controller: &mut Microcontroller) {
-    let mut val = controller.get_register(Register::A).unwrap();
+fn ral(controller: &mut Microcontroller) -> Result<(), ExecError> {
+    let mut val = controller.get_register(Register::A)?;
     let carry = val & 0b10000000 == 0b10000000;
     let prev_carry = controller.check_flag(Flag::Carry);
     controller.set_flag(Flag::Carry, carry);
@@ -361,52 +402,62 @@ fn ral(controller: &mut Microcontroller) {
         true => 0b00000001,
         false => 0b00000000
     };
-    controller.set_register(Register::A, val).unwrap();
+    controller.set_register(Register::A, val)?;
+    Ok(())
 }
 
 #[allow(dead_code)]
-fn reset(controller: &mut Microcontroller, x: u8) {
-    let stp = controller.get_register_pair(Register::SP).unwrap();
-    controller.set_register_pair(Register::SP, stp.add(2)).unwrap();
-    controller.set_data_at(Some(stp.sub(1)), (controller.program_counter << 8 >> 8) as u8);
-    controller.set_data_at(Some(stp), (controller.program_counter >> 8) as u8);
-    controller.program_counter = (x * 8) as u16;
+fn reset(controller: &mut Microcontroller, x: u8) -> Result<(), ExecError> {
+    push_word(controller, controller.program_counter)?;
+    controller.program_counter = x as u16 * 8;
+    controller.branch_taken = true;
+    Ok(())
+}
+
+pub(crate) fn push_word(controller: &mut Microcontroller, value: u16) -> Result<(), ExecError> {
+    let addr = controller.get_register_pair(Register::SP)?.sub(2);
+    controller.check_stack(addr, true)?;
+    controller.set_register_pair(Register::SP, addr)?;
+    controller.set_data_at(Some(addr.add(1)), (value >> 8) as u8);
+    controller.set_data_at(Some(addr), (value << 8 >> 8) as u8);
+    Ok(())
 }
 
 #[allow(dead_code)]
-fn pop(controller: &mut Microcontroller, reg: Register) {
-    let addr = controller.get_register_pair(Register::SP).unwrap();
-    let val = ((controller.get_data_at(Some(addr.add(1))) as u16) << 8) 
-                + controller.get_data_at(Some(addr)) as u16;
-    controller.set_register_pair(Register::SP, addr + 2).unwrap();
-    controller.set_register_pair(reg, val).unwrap();
+fn pop(controller: &mut Microcontroller, reg: Register) -> Result<(), ExecError> {
+    let addr = controller.get_register_pair(Register::SP)?;
+    controller.check_stack(addr, false)?;
+    let val = ((controller.get_data_at(Some(addr.add(1))) as u16) << 8)
+                | controller.get_data_at(Some(addr)) as u16;
+    controller.set_register_pair(Register::SP, addr.add(2))?;
+    controller.set_register_pair(reg, val)?;
+    Ok(())
 }
 
 #[allow(dead_code)]
-fn push(controller: &mut Microcontroller, reg: Register) {
-    let addr = controller.get_register_pair(Register::SP).unwrap();
-    controller.set_register_pair(Register::SP, addr - 2).unwrap();
-    let value = controller.get_register_pair(reg).unwrap();
-    controller.set_data_at(Some(addr.sub(1)), (value >> 8) as u8);
-    controller.set_data_at(Some(addr), (value << 8 >> 8) as u8);
+fn push(controller: &mut Microcontroller, reg: Register) -> Result<(), ExecError> {
+    let value = controller.get_register_pair(reg)?;
+    push_word(controller, value)?;
+    Ok(())
 }
 
 #[allow(dead_code)]
-pub fn xthl(controller : &mut Microcontroller) {
-    let addr = controller.get_register_pair(Register::SP).unwrap();
-    let xl = controller.get_register(Register::L).unwrap();
-    let xh = controller.get_register(Register::H).unwrap();
+pub fn xthl(controller : &mut Microcontroller) -> Result<(), ExecError> {
+    let addr = controller.get_register_pair(Register::SP)?;
+    let xl = controller.get_register(Register::L)?;
+    let xh = controller.get_register(Register::H)?;
     let l = controller.get_data_at(Some(addr));
     let h = controller.get_data_at(Some(addr.add(1)));
-    controller.set_register(Register::H, h).unwrap();
-    controller.set_register(Register::L, l).unwrap();
+    controller.set_register(Register::H, h)?;
+    controller.set_register(Register::L, l)?;
     controller.set_data_at(Some(addr), xh);
     controller.set_data_at(Some(addr.add(1)), xl);
+    Ok(())
 }
 
 #[allow(dead_code)]
-fn rlc(controller : &mut Microcontroller) {
-    let mut val = controller.get_register(Register::A).unwrap();
+fn rlc(controller : &mut Microcontroller) -> Result<(), ExecError> {
+    let mut val = controller.get_register(Register::A)?;
     let carry = val & 0b10000000 == 0b10000000;
     controller.set_flag(Flag::Carry, carry);
     val <<= 1;
@@ -414,12 +465,13 @@ fn rlc(controller : &mut Microcontroller) {
         true => 0b00000001,
         false => 0b00000000
     };
-    controller.set_register(Register::A, val).unwrap();
+    controller.set_register(Register::A, val)?;
+    Ok(())
 }
 
 #[allow(dead_code)]
-fn rrc(controller : &mut Microcontroller) {
-    let mut val = controller.get_register(Register::A).unwrap();
+fn rrc(controller : &mut Microcontroller) -> Result<(), ExecError> {
+    let mut val = controller.get_register(Register::A)?;
     let carry = val & 0b00000001 == 0b00000001;
     controller.set_flag(Flag::Carry, carry);
     val >>= 1;
@@ -427,45 +479,105 @@ fn rrc(controller : &mut Microcontroller) {
         true => 0b10000000,
         false => 0b00000000
     };
-    controller.set_register(Register::A, val).unwrap();
+    controller.set_register(Register::A, val)?;
+    Ok(())
 }
 
 #[allow(dead_code)]
-fn daa(controller : &mut Microcontroller) {
+fn daa(controller : &mut Microcontroller) -> Result<(), ExecError> {
     if controller.check_flag(Flag::AuxCarry) {
-        _add(controller, 6, 0);
+        _add(controller, 6, 0)?;
     }
     if controller.check_flag(Flag::Carry) {
-        _add(controller, 0b01100000, 0);
+        _add(controller, 0b01100000, 0)?;
     }
+    Ok(())
+}
+
+fn dsub(controller: &mut Microcontroller) -> Result<(), ExecError> {
+    let hl = controller.get_register_pair(Register::H)?;
+    let bc = controller.get_register_pair(Register::B)?;
+    let result = hl.sub(bc);
+    controller.set_register_pair(Register::H, result)?;
+    controller.set_flag(Flag::Carry, hl < bc);
+    controller.set_flag(Flag::AuxCarry, (hl & 0x0F) < (bc & 0x0F));
+    controller.set_flag(Flag::Zero, result == 0);
+    controller.set_flag(Flag::Sign, result & 0x8000 != 0);
+    controller.set_flag(Flag::Parity, Microcontroller::check_parity_16(result));
+    update_overflow(controller, (hl ^ bc) & (hl ^ result) & 0x8000 != 0);
+    Ok(())
+}
+
+fn arhl(controller: &mut Microcontroller) -> Result<(), ExecError> {
+    let hl = controller.get_register_pair(Register::H)?;
+    controller.set_flag(Flag::Carry, hl & 1 == 1);
+    controller.set_register_pair(Register::H, (hl >> 1) | (hl & 0x8000))?;
+    Ok(())
+}
+
+fn rdel(controller: &mut Microcontroller) -> Result<(), ExecError> {
+    let de = controller.get_register_pair(Register::D)?;
+    let carry = controller.check_flag(Flag::Carry) as u16;
+    controller.set_flag(Flag::Carry, de & 0x8000 != 0);
+    controller.set_flag(Flag::Overflow, (de ^ (de << 1)) & 0x8000 != 0);
+    controller.set_register_pair(Register::D, de << 1 | carry)?;
+    Ok(())
+}
+
+/// LDHI and LDSI: DE gets the pair plus an unsigned byte offset.
+fn load_offset(controller: &mut Microcontroller, base: Register) -> Result<(), ExecError> {
+    let offset = controller.fetch();
+    let addr = controller.get_register_pair(base)?.add(offset as u16);
+    controller.set_register_pair(Register::D, addr)?;
+    Ok(())
+}
+
+fn shlx(controller: &mut Microcontroller) -> Result<(), ExecError> {
+    let addr = controller.get_register_pair(Register::D)?;
+    controller.set_data_at(Some(addr), controller.get_register(Register::L)?);
+    controller.set_data_at(Some(addr.add(1)), controller.get_register(Register::H)?);
+    Ok(())
+}
+
+fn lhlx(controller: &mut Microcontroller) -> Result<(), ExecError> {
+    let addr = controller.get_register_pair(Register::D)?;
+    controller.set_register(Register::L, controller.get_data_at(Some(addr)))?;
+    controller.set_register(Register::H, controller.get_data_at(Some(addr.add(1))))?;
+    Ok(())
 }
 
 #[allow(dead_code)]
-fn rim(_controller: &mut Microcontroller) {
-    // TODO: Implement
+fn rim(controller: &mut Microcontroller) -> Result<(), ExecError> {
+    let value = controller.read_interrupt_mask();
+    controller.set_register(Register::A, value)?;
+    Ok(())
 }
 
 #[allow(dead_code)]
-fn sim(_controller: &mut Microcontroller) {
-    // TODO: Implement
+fn sim(controller: &mut Microcontroller) -> Result<(), ExecError> {
+    let value = controller.get_register(Register::A)?;
+    controller.set_interrupt_mask(value);
+    Ok(())
 }
 
 #[allow(dead_code)]
-fn output(controller: &mut Microcontroller) {
-    let addr = (controller.fetch() as u16) << 8 | controller.fetch() as u16;
-    let val = controller.get_register(Register::A).unwrap();
-    controller.write_io(addr, val);
+fn output(controller: &mut Microcontroller) -> Result<(), ExecError> {
+    let port = controller.fetch();
+    let val = controller.get_register(Register::A)?;
+    controller.write_io(port, val);
+    Ok(())
 }
 
 #[allow(dead_code)]
-fn input(controller: &mut Microcontroller) {
-    let addr = (controller.fetch() as u16) << 8 | controller.fetch() as u16;
-    let val = controller.read_io(addr);
-    controller.set_register(Register::A, val).unwrap();
+fn input(controller: &mut Microcontroller) -> Result<(), ExecError> {
+    let port = controller.fetch();
+    let val = controller.read_io(port);
+    controller.set_register(Register::A, val)?;
+    Ok(())
 }
 
 #[allow(dead_code)]
-pub type Instruction = fn(&mut Microcontroller);
+pub type Instruction = fn(&mut Microcontroller) -> Result<(), ExecError>;
 
 #[allow(dead_code)]
 pub static SUB_A: Instruction = |controller| sub(controller, Register::A, 0);
@@ -688,14 +800,27 @@ pub static MVI_L: Instruction = |controller| mvi(controller, Register::L);
 #[allow(dead_code)]
 pub static MVI_M: Instruction = |controller| mvi(controller, Register::M);
 #[allow(dead_code)]
-pub static HLT: Instruction = |controller| controller.stop();
+pub static HLT: Instruction = |controller| {
+    // An interrupt can still wake the CPU if one is already waiting, or if they are enabled and
+    // a device is attached to raise one. Otherwise nothing will, and the run ends.
+    if controller.pending_interrupt().is_some()
+        || (controller.interrupts_enabled() && controller.can_raise_interrupts())
+    {
+        controller.halted = true;
+    } else {
+        controller.stop();
+    }
+    Ok(())
+};
 #[allow(dead_code)]
 pub static CMA: Instruction = |controller| {
-    controller.set_register(Register::A, !controller.get_register(Register::A).unwrap()).unwrap();
+    controller.set_register(Register::A, !controller.get_register(Register::A)?)?;
+    Ok(())
 };
 #[allow(dead_code)]
 pub static CMC: Instruction = |controller| {
     controller.set_flag(Flag::Carry, !controller.check_flag(Flag::Carry));
+    Ok(())
 };
 #[allow(dead_code)]
 pub static ORA_A: Instruction = |controller| ora(controller, Register::A);
@@ -836,11 +961,14 @@ pub static LXI_H: Instruction = |controller| lxi(controller, Register::H);
 #[allow(dead_code)]
 pub static LXI_SP: Instruction = |controller| lxi(controller, Register::SP);
 #[allow(dead_code)]
-pub static LDAX_B: Instruction = |controller| lxi(controller, Register::B);
+pub static LDAX_B: Instruction = |controller| ldax(controller, Register::B);
 #[allow(dead_code)]
-pub static LDAX_D: Instruction = |controller| lxi(controller, Register::D);
+pub static LDAX_D: Instruction = |controller| ldax(controller, Register::D);
 #[allow(dead_code)]
-pub static STC: Instruction = |controller| controller.set_flag(Flag::Carry, true);
+pub static STC: Instruction = |controller| {
+    controller.set_flag(Flag::Carry, true);
+    Ok(())
+};
 #[allow(dead_code)]
 pub static JMP: Instruction = |controller| jmp(controller, false);
 #[allow(dead_code)]
@@ -863,76 +991,85 @@ pub static JPE: Instruction = |controller| jmp(controller, !controller.check_fla
 pub static CALL: Instruction = |controller| call(controller, false);
 #[allow(dead_code)]
 pub static CM: Instruction = |controller| {
-    if controller.check_flag(Flag::Sign) { call(controller, false); }
+    if controller.check_flag(Flag::Sign) { call(controller, false) }
     else { call(controller, true) }
 };
 #[allow(dead_code)]
 pub static CP: Instruction = |controller| {
-    if controller.check_flag(Flag::Sign) { call(controller, false); }
+    if !controller.check_flag(Flag::Sign) { call(controller, false) }
     else { call(controller, true) }
 };
 #[allow(dead_code)]
 pub static CC: Instruction = |controller| {
-    if controller.check_flag(Flag::Carry) { call(controller, false); }
+    if controller.check_flag(Flag::Carry) { call(controller, false) }
     else { call(controller, true) }
 };
 #[allow(dead_code)]
 pub static CNC: Instruction = |controller| {
-    if !controller.check_flag(Flag::Zero) { call(controller, false); }
+    if !controller.check_flag(Flag::Carry) { call(controller, false) }
     else { call(controller, true) }
 };
 #[allow(dead_code)]
 pub static CZ: Instruction = |controller| {
-    if controller.check_flag(Flag::Zero) { call(controller, false); }
+    if controller.check_flag(Flag::Zero) { call(controller, false) }
     else { call(controller, true) }
 };
-#[allow(dead_code)] pub static CNZ: Instruction = |controller| {
-    if !controller.check_flag(Flag::Carry) { call(controller, false); }
+#[allow(dead_code)]
+pub static CNZ: Instruction = |controller| {
+    if !controller.check_flag(Flag::Zero) { call(controller, false) }
     else { call(controller, true) }
 };
 #[allow(dead_code)]
 pub static CPE: Instruction = |controller| {
-    if controller.check_flag(Flag::Parity) { call(controller, false); }
+    if controller.check_flag(Flag::Parity) { call(controller, false) }
     else { call(controller, true) }
 };
 #[allow(dead_code)]
 pub static CPO: Instruction = |controller| {
-    if !controller.check_flag(Flag::Parity) { call(controller, false); }
+    if !controller.check_flag(Flag::Parity) { call(controller, false) }
     else { call(controller, true) }
 };
 #[allow(dead_code)]
 pub static RET: Instruction = |controller| ret(controller);
 #[allow(dead_code)]
 pub static RP: Instruction = |controller| {
-    if !controller.check_flag(Flag::Sign) { ret(controller); }
+    if !controller.check_flag(Flag::Sign) { ret(controller)?; }
+    Ok(())
 };
 #[allow(dead_code)]
 pub static RM: Instruction = |controller| {
-    if controller.check_flag(Flag::Sign) { ret(controller); }
+    if controller.check_flag(Flag::Sign) { ret(controller)?; }
+    Ok(())
 };
 #[allow(dead_code)]
 pub static RC: Instruction = |controller| {
-    if controller.check_flag(Flag::Carry) { ret(controller); }
+    if controller.check_flag(Flag::Carry) { ret(controller)?; }
+    Ok(())
 };
 #[allow(dead_code)]
 pub static RNC: Instruction = |controller| {
-    if !controller.check_flag(Flag::Carry) { ret(controller); }
+    if !controller.check_flag(Flag::Carry) { ret(controller)?; }
+    Ok(())
 };
 #[allow(dead_code)]
 pub static RZ: Instruction = |controller| {
-    if controller.check_flag(Flag::Zero) { ret(controller); }
+    if controller.check_flag(Flag::Zero) { ret(controller)?; }
+    Ok(())
 };
 #[allow(dead_code)]
 pub static RNZ: Instruction = |controller| {
-    if !controller.check_flag(Flag::Zero) { ret(controller); }
+    if !controller.check_flag(Flag::Zero) { ret(controller)?; }
+    Ok(())
 };
 #[allow(dead_code)]
 pub static RPE: Instruction = |controller| {
-    if controller.check_flag(Flag::Parity) { ret(controller); }
+    if controller.check_flag(Flag::Parity) { ret(controller)?; }
+    Ok(())
 };
 #[allow(dead_code)]
 pub static RPO: Instruction = |controller| {
-    if !controller.check_flag(Flag::Parity) { ret(controller); }
+    if !controller.check_flag(Flag::Parity) { ret(controller)?; }
+    Ok(())
 };
 #[allow(dead_code)]
 pub static RST_0: Instruction = |controller| reset(controller, 0);
@@ -949,6 +1086,8 @@ pub static RST_5: Instruction = |controller| reset(controller, 5);
 #[allow(dead_code)]
 pub static RST_6: Instruction = |controller| reset(controller, 6);
 #[allow(dead_code)]
+pub static RST_7: Instruction = |controller| reset(controller, 7);
+#[allow(dead_code)]
 pub static RAL: Instruction = |controller| ral(controller);
 #[allow(dead_code)]
 pub static RAR: Instruction = |controller| rar(controller);
@@ -989,9 +1128,15 @@ pub static INPUT: Instruction = |controller| input(controller);
 #[allow(dead_code)]
 pub static OUTPUT: Instruction = |controller| output(controller);
 #[allow(dead_code)]
-pub static DI: Instruction = |controller| controller.disable_interrupts();
+pub static DI: Instruction = |controller| {
+    controller.disable_interrupts();
+    Ok(())
+};
 #[allow(dead_code)]
-pub static EI: Instruction = |controller| controller.enable_interrupts();
+pub static EI: Instruction = |controller| {
+    controller.enable_interrupts();
+    Ok(())
+};
 #[allow(dead_code)]
 pub static SUI: Instruction = |controller| sbi(controller);
 #[allow(dead_code)]
@@ -999,4 +1144,27 @@ pub static CPI: Instruction = |controller| cpi(controller);
 #[allow(dead_code)]
 pub static DAA: Instruction = |controller| daa(controller);
 #[allow(dead_code)]
-pub static NOOP: Instruction = |_| {};
+pub static NOOP: Instruction = |_| Ok(());
+#[allow(dead_code)]
+pub static DSUB: Instruction = |controller| dsub(controller);
+#[allow(dead_code)]
+pub static ARHL: Instruction = |controller| arhl(controller);
+#[allow(dead_code)]
+pub static RDEL: Instruction = |controller| rdel(controller);
+#[allow(dead_code)]
+pub static LDHI: Instruction = |controller| load_offset(controller, Register::H);
+#[allow(dead_code)]
+pub static LDSI: Instruction = |controller| load_offset(controller, Register::SP);
+#[allow(dead_code)]
+pub static RSTV: Instruction = |controller| {
+    if controller.check_flag(Flag::Overflow) { reset(controller, 8)?; }
+    Ok(())
+};
+#[allow(dead_code)]
+pub static SHLX: Instruction = |controller| shlx(controller);
+#[allow(dead_code)]
+pub static JNK: Instruction = |controller| jmp(controller, controller.check_flag(Flag::Underflow));
+#[allow(dead_code)]
+pub static LHLX: Instruction = |controller| lhlx(controller);
+#[allow(dead_code)]
+pub static JK: Instruction = |controller| jmp(controller, !controller.check_flag(Flag::Underflow));
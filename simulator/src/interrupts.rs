@@ -0,0 +1,208 @@
+use std::collections::VecDeque;
+
+use crate::error::ExecError;
+use crate::simulator::{ Cost, Microcontroller };
+
+/// The 8085's interrupt inputs, in priority order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InterruptPin {
+    /// Non-maskable, vectored to 0024H. Needs a rising edge and then the pin held high.
+    Trap,
+    /// Vectored to 003CH. A rising edge is latched until acknowledged, even while masked.
+    Rst75,
+    /// Vectored to 0034H while the pin is high.
+    Rst65,
+    /// Vectored to 002CH while the pin is high.
+    Rst55,
+    /// Runs the instruction the interrupting device puts on the bus during INTA while the pin is high.
+    Intr,
+}
+
+impl InterruptPin {
+    fn index(self) -> usize {
+        self as usize
+    }
+
+    /// Where TRAP and the RST x.5 interrupts jump to. INTR has no fixed vector.
+    pub fn vector(self) -> Option<u16> {
+        match self {
+            InterruptPin::Trap => Some(0x24),
+            InterruptPin::Rst75 => Some(0x3C),
+            InterruptPin::Rst65 => Some(0x34),
+            InterruptPin::Rst55 => Some(0x2C),
+            InterruptPin::Intr => None,
+        }
+    }
+}
+
+/// Mask bits as SIM sets them and RIM reads them back.
+const MASK_55: u8 = 0b00000001;
+const MASK_65: u8 = 0b00000010;
+const MASK_75: u8 = 0b00000100;
+const MASKS: u8 = MASK_55 | MASK_65 | MASK_75;
+/// SIM: only change the masks when this is set.
+const MASK_SET_ENABLE: u8 = 0b00001000;
+/// SIM: clear a latched RST 7.5.
+const RESET_75: u8 = 0b00010000;
+/// SIM: only change SOD when this is set.
+const SERIAL_DATA_ENABLE: u8 = 0b01000000;
+/// SIM: the level for SOD. RIM: the level of SID.
+const SERIAL_DATA: u8 = 0b10000000;
+/// RIM: the interrupt enable flip-flop.
+const INTERRUPT_ENABLE: u8 = 0b00001000;
+/// RIM: interrupts waiting on RST 5.5, 6.5 and 7.5, whether masked or not.
+const PENDING_55: u8 = 0b00010000;
+const PENDING_65: u8 = 0b00100000;
+const PENDING_75: u8 = 0b01000000;
+
+/// The interrupt logic besides the interrupt enable flip-flop.
+#[derive(Debug, Clone)]
+pub(crate) struct Interrupts {
+    /// Level of each pin, indexed by `InterruptPin`.
+    levels: [bool; 5],
+    trap_latch: bool,
+    rst75_latch: bool,
+    /// The interrupt enable flip-flop before the last TRAP, which the next RIM reports instead of
+    /// the current one so a TRAP handler can tell whether to re-enable interrupts.
+    enabled_before_trap: Option<bool>,
+    /// The RST 7.5, 6.5 and 5.5 masks, clear (unmasked) at power on.
+    masks: u8,
+    /// Set by EI: interrupts are only accepted after the instruction that follows it.
+    pub(crate) ei_delay: bool,
+    /// What the device on INTR answers INTA with.
+    intr_instruction: Vec<u8>,
+    /// Bytes of that answer not yet read by the CPU.
+    pub(crate) inta: VecDeque<u8>,
+}
+
+impl Default for Interrupts {
+    fn default() -> Interrupts {
+        Interrupts {
+            levels: [false; 5],
+            trap_latch: false,
+            rst75_latch: false,
+            enabled_before_trap: None,
+            masks: 0,
+            ei_delay: false,
+            // An idle data bus is pulled high, which reads as RST 7.
+            intr_instruction: vec![0xFF],
+            inta: VecDeque::new(),
+        }
+    }
+}
+
+impl Microcontroller {
+    /// Drives `pin` high or low. Interrupts are taken between instructions, the next time the
+    /// microcontroller runs.
+    pub fn set_pin(&mut self, pin: InterruptPin, high: bool) {
+        let rising = high && !self.interrupt_logic.levels[pin.index()];
+        self.interrupt_logic.levels[pin.index()] = high;
+        match pin {
+            InterruptPin::Trap if rising => self.interrupt_logic.trap_latch = true,
+            InterruptPin::Rst75 if rising => self.interrupt_logic.rst75_latch = true,
+            _ => {}
+        }
+    }
+
+    pub fn pin(&self, pin: InterruptPin) -> bool {
+        self.interrupt_logic.levels[pin.index()]
+    }
+
+    /// Sets the instruction, opcode first, that acknowledging INTR reads from the data bus. Usually
+    /// an RST, or a CALL to the device's handler.
+    pub fn set_intr_instruction(&mut self, instruction: &[u8]) {
+        self.interrupt_logic.intr_instruction = instruction.to_vec();
+    }
+
+    /// The interrupt that would be acknowledged at the next instruction boundary, by priority.
+    pub fn pending_interrupt(&self) -> Option<InterruptPin> {
+        use InterruptPin::{ Intr, Rst55, Rst65, Rst75, Trap };
+        let logic = &self.interrupt_logic;
+        let high = |pin: InterruptPin| logic.levels[pin.index()];
+        if logic.trap_latch && high(Trap) {
+            return Some(Trap);
+        }
+        if !self.interrupts_enabled() || logic.ei_delay {
+            return None;
+        }
+        if logic.rst75_latch && logic.masks & MASK_75 == 0 {
+            Some(Rst75)
+        } else if high(Rst65) && logic.masks & MASK_65 == 0 {
+            Some(Rst65)
+        } else if high(Rst55) && logic.masks & MASK_55 == 0 {
+            Some(Rst55)
+        } else if high(Intr) {
+            Some(Intr)
+        } else {
+            None
+        }
+    }
+
+    /// Acknowledges the pending interrupt, if any, in place of fetching the next instruction.
+    pub(crate) fn service_interrupt(&mut self) -> Result<Option<Cost>, ExecError> {
+        let pending = self.pending_interrupt();
+        // EI's delay only lasts one instruction, whether or not anything was waiting.
+        self.interrupt_logic.ei_delay = false;
+        let Some(pin) = pending else {
+            return Ok(None);
+        };
+        let enabled = self.interrupts_enabled();
+        self.disable_interrupts();
+        match pin {
+            InterruptPin::Trap => {
+                self.interrupt_logic.trap_latch = false;
+                self.interrupt_logic.enabled_before_trap = Some(enabled);
+            }
+            InterruptPin::Rst75 => self.interrupt_logic.rst75_latch = false,
+            InterruptPin::Intr => {
+                let instruction = self.interrupt_logic.intr_instruction.clone();
+                self.interrupt_logic.inta.extend(instruction);
+                self.fetch();
+                let cost = self.execute();
+                self.interrupt_logic.inta.clear();
+                return cost.map(Some);
+            }
+            _ => {}
+        }
+        crate::instructions::push_word(self, self.program_counter)?;
+        self.program_counter = pin.vector().expect("only INTR has no vector");
+        // The same machine cycles as an RST.
+        Ok(Some(self.charge(12)))
+    }
+
+    /// SIM: sets the RST masks, clears the RST 7.5 latch and drives SOD, as the bits of `value` say.
+    pub(crate) fn set_interrupt_mask(&mut self, value: u8) {
+        if value & MASK_SET_ENABLE != 0 {
+            self.interrupt_logic.masks = value & MASKS;
+        }
+        if value & RESET_75 != 0 {
+            self.interrupt_logic.rst75_latch = false;
+        }
+        if value & SERIAL_DATA_ENABLE != 0 {
+            self.write_sod(value & SERIAL_DATA != 0);
+        }
+    }
+
+    /// RIM: the masks, interrupt enable, pending interrupts and the level of SID.
+    pub(crate) fn read_interrupt_mask(&mut self) -> u8 {
+        let enabled = self.interrupts_enabled();
+        let logic = &mut self.interrupt_logic;
+        let enabled = logic.enabled_before_trap.take().unwrap_or(enabled);
+        let mut value = logic.masks;
+        let bits = [
+            (enabled, INTERRUPT_ENABLE),
+            (logic.levels[InterruptPin::Rst55.index()], PENDING_55),
+            (logic.levels[InterruptPin::Rst65.index()], PENDING_65),
+            (logic.rst75_latch, PENDING_75),
+        ];
+        for (set, bit) in bits {
+            if set {
+                value |= bit;
+            }
+        }
+        if self.read_sid() {
+            value |= SERIAL_DATA;
+        }
+        value
+    }
+}
@@ -0,0 +1,88 @@
+use std::ops::RangeInclusive;
+
+use crate::debugger::Access;
+use crate::interrupts::InterruptPin;
+use crate::simulator::Microcontroller;
+
+/// A peripheral on the I/O port bus, reached with IN and OUT.
+///
+/// `cycle` is the value of `Microcontroller::cycles` when the IN or OUT started.
+pub trait IoDevice {
+    /// IN from one of the device's ports.
+    fn read(&mut self, port: u8, cycle: u64) -> u8;
+    /// OUT to one of the device's ports.
+    fn write(&mut self, port: u8, value: u8, cycle: u64);
+    /// Called after every instruction, and every machine cycle spent halted, so the device can
+    /// keep time and raise or drop interrupts.
+    fn clock(&mut self, _cycle: u64, _signals: &mut Signals) {}
+}
+
+/// Interrupt pins a device wants driven, applied once it returns.
+#[derive(Debug, Default)]
+pub struct Signals {
+    pins: Vec<(InterruptPin, bool)>,
+}
+
+impl Signals {
+    pub fn set_pin(&mut self, pin: InterruptPin, high: bool) {
+        self.pins.push((pin, high));
+    }
+}
+
+/// Devices by the ports they answer to. Reads from a port nobody answers see the bus pulled
+/// high, FFH, and writes to one are lost.
+#[derive(Default)]
+pub(crate) struct IoBus {
+    devices: Vec<(RangeInclusive<u8>, Box<dyn IoDevice>)>,
+}
+
+impl IoBus {
+    /// The device answering `port`, the one attached last when ranges overlap.
+    fn device(&mut self, port: u8) -> Option<&mut Box<dyn IoDevice>> {
+        self.devices.iter_mut().rev().find(|(ports, _)| ports.contains(&port)).map(|(_, device)| device)
+    }
+}
+
+impl Microcontroller {
+    /// Puts `device` on the ports in `ports`, in front of any device already there.
+    pub fn attach_io(&mut self, ports: RangeInclusive<u8>, device: Box<dyn IoDevice>) {
+        self.io.devices.push((ports, device));
+    }
+
+    /// Whether an attached device could raise an interrupt pin, which only I/O devices can do.
+    pub(crate) fn can_raise_interrupts(&self) -> bool {
+        !self.io.devices.is_empty()
+    }
+
+    pub fn write_io(&mut self, port: u8, value: u8) {
+        if self.debugger.recording {
+            self.debugger.ports.push((port, Access::Write));
+        }
+        let cycle = self.cycles;
+        if let Some(device) = self.io.device(port) {
+            device.write(port, value, cycle);
+        }
+    }
+
+    pub fn read_io(&mut self, port: u8) -> u8 {
+        if self.debugger.recording {
+            self.debugger.ports.push((port, Access::Read));
+        }
+        let cycle = self.cycles;
+        match self.io.device(port) {
+            Some(device) => device.read(port, cycle),
+            None => 0xFF,
+        }
+    }
+
+    pub(crate) fn clock_io(&mut self) {
+        let cycle = self.cycles;
+        let mut signals = Signals::default();
+        for (_, device) in &mut self.io.devices {
+            device.clock(cycle, &mut signals);
+        }
+        for (pin, high) in signals.pins {
+            self.set_pin(pin, high);
+        }
+    }
+}
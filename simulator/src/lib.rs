@@ -1,21 +1,40 @@
-mod simulator;
+pub mod simulator;
+pub mod loader;
+pub mod clock;
+pub mod interrupts;
+pub mod serial;
+pub mod io;
+pub mod memory;
+pub mod fault;
+pub mod error;
+pub mod run;
+pub mod debugger;
 mod instructions;
 
 #[cfg(test)]
+#[allow(clippy::unit_arg)]
 mod tests {
 
     use super::*;
 
-    static TEST_LOC: &str = "/Users/asifzaman/src/rust/intel8085-rs/tests/";
+    static TEST_LOC: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/../tests/");
+
+    /// Runs to HLT, failing rather than hanging when the program never gets there.
+    fn run_to_halt(sim: &mut simulator::Microcontroller) {
+        assert_eq!(sim.run_for(50_000_000), Ok(run::StopReason::Halted));
+    }
 
     fn setup_sim(sim: &mut simulator::Microcontroller, filename: &str) -> std::io::Result<()> {
         sim.clear_memory();
         sim.clear_registers();
-        let code = match assembler::assembler::assemble_file(TEST_LOC.to_owned() + filename)? {
-            Ok(code) => code,
+        let image = match assembler::assembler::assemble_file(TEST_LOC.to_owned() + filename)? {
+            Ok(image) => image,
             Err(parse_error) => panic!("{parse_error}")
         };
-        sim.load_code(&code, 0).unwrap();
+        for segment in &image.segments {
+            sim.load_code(&segment.bytes, segment.origin).unwrap();
+        }
+        sim.program_counter = image.start_address();
         Ok(())
     }
 
@@ -24,7 +43,7 @@ mod tests {
         let mut sim = simulator::Microcontroller::new();
         setup_sim(&mut sim, "fib.asm")?;
         sim.set_data_at(Some(0x3030), 0x33);
-        sim.start();
+        run_to_halt(&mut sim);
         let written = sim.get_data_at(Some(0x3031));
         Ok(assert_eq!(written, 0x37))
     }
@@ -35,7 +54,7 @@ mod tests {
         setup_sim(&mut sim, "add.asm")?;
         sim.set_data_at(Some(0x20), 0x30);
         sim.set_data_at(Some(0x21), 0x31);
-        sim.start();
+        run_to_halt(&mut sim);
         let written = sim.get_data_at(Some(0x22));
         Ok(assert_eq!(written, 0x61))
     }
@@ -49,7 +68,7 @@ mod tests {
         sim.set_data_at(Some(0x32), 0x2);
         sim.set_data_at(Some(0x33), 0x3);
         sim.set_data_at(Some(0x34), 0x4);
-        sim.start();
+        run_to_halt(&mut sim);
         let written = sim.get_data_at(Some(0x70));
         Ok(assert_eq!(written, 0x0A))
     }
@@ -62,7 +81,7 @@ mod tests {
         sim.set_data_at(Some(0x5001), 0x12);
         sim.set_data_at(Some(0x5002), 0x78);
         sim.set_data_at(Some(0x5003), 0x56);
-        sim.start();
+        run_to_halt(&mut sim);
         let written = (sim.get_data_at(Some(0x5005)) as u16) << 8 |
             (sim.get_data_at(Some(0x5004)) as u16);
         Ok(assert_eq!(written, 0x68AC))
@@ -78,7 +97,7 @@ mod tests {
         sim.set_data_at(Some(0x5003), 0x05);
         sim.set_data_at(Some(0x5004), 0x03);
         sim.set_data_at(Some(0x5005), 0x01);
-        sim.start();
+        run_to_halt(&mut sim);
         assert_eq!(sim.get_data_at(Some(0x5001)), 0x01);
         assert_eq!(sim.get_data_at(Some(0x5002)), 0x02);
         assert_eq!(sim.get_data_at(Some(0x5003)), 0x03);
@@ -96,7 +115,7 @@ mod tests {
         sim.set_data_at(Some(0x5003), 0x05);
         sim.set_data_at(Some(0x5004), 0x03);
         sim.set_data_at(Some(0x5005), 0x01);
-        sim.start();
+        run_to_halt(&mut sim);
         assert_eq!(sim.get_data_at(Some(0x5001)), 0x05);
         assert_eq!(sim.get_data_at(Some(0x5002)), 0x04);
         assert_eq!(sim.get_data_at(Some(0x5003)), 0x03);
@@ -109,7 +128,7 @@ mod tests {
         let mut sim = simulator::Microcontroller::new();
         setup_sim(&mut sim, "even_odd.asm")?;
         sim.set_data_at(Some(0x5000), 0x04);
-        sim.start();
+        run_to_halt(&mut sim);
         Ok(assert_eq!(sim.get_data_at(Some(0x5001)), 0x00))
     }
 
@@ -123,7 +142,7 @@ mod tests {
         sim.set_data_at(Some(0x5003), 0x05);
         sim.set_data_at(Some(0x5004), 0x03);
         sim.set_data_at(Some(0x5005), 0x01);
-        sim.start();
+        run_to_halt(&mut sim);
         Ok(assert_eq!(sim.get_data_at(Some(0x4999)), 0x5))
     }
 
@@ -133,7 +152,7 @@ mod tests {
         setup_sim(&mut sim, "mul.asm")?;
         sim.set_data_at(Some(0x5000), 0x0a);
         sim.set_data_at(Some(0x5001), 0x05);
-        sim.start();
+        run_to_halt(&mut sim);
         Ok(assert_eq!(sim.get_data_at(Some(0x5003)), 0x32))
     }
 
@@ -143,9 +162,751 @@ mod tests {
         setup_sim(&mut sim, "div.asm")?;
         sim.set_data_at(Some(0x5000), 0x10);
         sim.set_data_at(Some(0x5001), 0x05);
-        sim.start();
+        run_to_halt(&mut sim);
         assert_eq!(sim.get_data_at(Some(0x5003)), 0x03);
         Ok(assert_eq!(sim.get_data_at(Some(0x5002)), 0x01))
     }
 
+
+    #[test]
+    fn test_loaders() {
+        let image = match assembler::assembler::assemble_file(TEST_LOC.to_owned() + "add.asm").unwrap() {
+            Ok(image) => image,
+            Err(parse_error) => panic!("{parse_error}")
+        };
+        let mut sim = simulator::Microcontroller::new();
+        sim.load_intel_hex(&assembler::output::intel_hex(&image)).unwrap();
+        assert_eq!(sim.program_counter, image.start_address());
+        sim.set_data_at(Some(0x20), 0x30);
+        sim.set_data_at(Some(0x21), 0x31);
+        run_to_halt(&mut sim);
+        assert_eq!(sim.get_data_at(Some(0x22)), 0x61);
+
+        let mut sim = simulator::Microcontroller::new();
+        let records = assembler::output::s_records(&image, assembler::output::SRecordFormat::S28);
+        sim.load_s_records(&records).unwrap();
+        assert_eq!(sim.program_counter, image.start_address());
+        assert_eq!(sim.get_data_at(Some(image.start_address())), image.segments[0].bytes[0]);
+
+        let mut sim = simulator::Microcontroller::new();
+        sim.load_trainer_listing("
+            ; Adds 5 and 3
+            2000: 3E 05     MVI A, 05H
+            2002: C6 03     ADI 03H
+            2004  32 50 20  STA 2050H
+            2007: 76        HLT
+        ").unwrap();
+        assert_eq!(sim.program_counter, 0x2000);
+        run_to_halt(&mut sim);
+        assert_eq!(sim.get_data_at(Some(0x2050)), 0x08);
+    }
+
+    #[test]
+    fn test_loader_errors() {
+        use loader::{ LoadError, LoadErrorKind };
+        let mut sim = simulator::Microcontroller::new();
+        let result = sim.load_intel_hex(":022000003E039D\n:012004007666\n:00000001FF\n");
+        assert_eq!(result, Err(LoadError { line: 2, error: LoadErrorKind::Checksum(0x65, 0x66) }));
+        let result = sim.load_intel_hex(":022000003E039D\n:0320040076E5\n");
+        assert_eq!(result, Err(LoadError { line: 2, error: LoadErrorKind::Length(3, 1) }));
+        let result = sim.load_s_records("S0030000FC\nS10520003E0399\nS5030001FB\nS4030000FC\n");
+        assert_eq!(result, Err(LoadError { line: 4, error: LoadErrorKind::UnsupportedRecord("S4".to_owned()) }));
+        for text in ["S\u{e9}00\n", "S1\u{e9}0\n", "S\n"] {
+            let result = sim.load_s_records(text);
+            assert!(matches!(result, Err(LoadError { line: 1, error: LoadErrorKind::Syntax(_) })), "{text}");
+        }
+        let result = sim.load_intel_hex(":02FFFF003E03BF\n");
+        assert_eq!(result, Err(LoadError { line: 1, error: LoadErrorKind::DoesNotFit(0xffff) }));
+        let result = sim.load_trainer_listing("2000: 3E 05\nMVI A, 05H\n");
+        assert!(matches!(result, Err(LoadError { line: 2, error: LoadErrorKind::Syntax(_) })));
+    }
+
+    #[test]
+    fn test_disassemble_memory() {
+        let mut sim = simulator::Microcontroller::new();
+        sim.load_trainer_listing("2000: 3E 05 C6 03 32 50 20 76").unwrap();
+        let symbols = std::collections::HashMap::from([("RESULT".to_owned(), 0x2050)]);
+        let disassembly = sim.disassemble(0x2000..0x2008, &symbols);
+        let text: Vec<_> = disassembly.lines.iter().map(|line| line.text.as_str()).collect();
+        assert_eq!(text, ["MVI A, 05H", "ADI 03H", "STA RESULT", "HLT"]);
+        assert_eq!(disassembly.lines[2].address, 0x2004);
+    }
+
+    #[test]
+    fn test_undocumented_instructions() {
+        let options = assembler::assembler::Options { undocumented: true, ..Default::default() };
+        let image = assembler::assembler::assemble_with("
+                    ORG 2000H
+                    LXI SP, 3000H
+                    LXI H, 1234H
+                    LXI B, 0234H
+                    DSUB
+                    LXI B, 0
+                    DSUB
+                    LXI D, 2100H
+                    SHLX
+                    ARHL
+                    SHLD 2102H
+                    LHLX
+                    LDHI 5
+                    RDEL
+                    MVI A, 7FH
+                    INR A
+                    RSTV
+                    LXI B, 0
+                    DCX B
+                    JNK FAIL
+                    JK OK
+            FAIL:   HLT
+            OK:     CALL STORE
+                    HLT
+            STORE:  STA 2104H
+                    RET
+                    ORG 40H
+                    PUSH PSW
+                    MVI A, 1
+                    STA 2105H
+                    POP PSW
+                    RET
+        ", &options).unwrap_or_else(|parse_error| panic!("{parse_error}"));
+        let mut sim = simulator::Microcontroller::new();
+        for segment in &image.segments {
+            sim.load_code(&segment.bytes, segment.origin).unwrap();
+        }
+        sim.program_counter = 0x2000;
+        run_to_halt(&mut sim);
+        use simulator::{ Flag, Register };
+        assert_eq!(sim.get_register_pair(Register::H), Ok(0x1000));
+        assert_eq!(sim.get_register_pair(Register::D), Ok(0x200A));
+        assert_eq!(sim.get_register_pair(Register::SP), Ok(0x3000));
+        let written: Vec<_> = (0x2100..0x2106).map(|addr| sim.get_data_at(Some(addr))).collect();
+        assert_eq!(written, [0x00, 0x10, 0x00, 0x08, 0x80, 0x01]);
+        assert!(sim.check_flag(Flag::Overflow));
+        assert!(sim.check_flag(Flag::Underflow));
+    }
+
+    #[test]
+    fn test_cycle_counting() {
+        let image = assembler::assembler::assemble("
+                    LXI SP, 3000H
+                    MVI C, 3
+            LOOP:   DCR C
+                    JNZ LOOP
+                    STC
+                    CC SUBR
+                    CC SUBR
+                    HLT
+            SUBR:   RNC
+                    CMC
+                    RNC
+        ").unwrap_or_else(|parse_error| panic!("{parse_error}"));
+        let mut sim = simulator::Microcontroller::new();
+        sim.load_code(&image.segments[0].bytes, 0).unwrap();
+        sim.running = true;
+        assert_eq!(sim.tick(), Ok(simulator::Cost { t_states: 10, machine_cycles: 3 }));
+        run_to_halt(&mut sim);
+        // JNZ is taken twice (10) and falls through once (7), the first CC calls (18) and the
+        // second does not (9), and the first RNC falls through (6) where the second returns (12).
+        assert_eq!(sim.cycles, 10 + 7 + 3 * 4 + 10 + 10 + 7 + 4 + 18 + 9 + 5 + 6 + 4 + 12);
+        assert_eq!(sim.machine_cycles, 3 + 2 + 3 + 3 + 3 + 2 + 1 + 5 + 2 + 1 + 1 + 1 + 3);
+    }
+
+    #[test]
+    fn test_paced_run() {
+        use clock::{ ClockConfig, Speed };
+        use std::time::Duration;
+        // About 24000 T-states, 24 ms at a 1 MHz clock.
+        let image = assembler::assembler::assemble("
+                    LXI B, 1000
+            LOOP:   DCX B
+                    MOV A, B
+                    ORA C
+                    JNZ LOOP
+                    HLT
+        ").unwrap_or_else(|parse_error| panic!("{parse_error}"));
+        let run = |speed| {
+            let mut sim = simulator::Microcontroller::new();
+            sim.load_code(&image.segments[0].bytes, 0).unwrap();
+            let clock = ClockConfig { crystal_hz: 2_000_000, speed, resync_every: Duration::from_millis(2) };
+            let stats = sim.start_paced(&clock).unwrap();
+            assert_eq!(stats.t_states, sim.cycles);
+            stats
+        };
+
+        let stats = run(Speed::Times(1.0));
+        let seconds = |t_states: u64, rate: f64| t_states as f64 / rate;
+        assert!((stats.simulated.as_secs_f64() - seconds(stats.t_states, 1e6)).abs() < 1e-6);
+        assert!(stats.elapsed >= stats.simulated);
+        assert!(stats.resyncs >= 10);
+        let stats = run(Speed::Times(2.0));
+        assert!((stats.simulated.as_secs_f64() - seconds(stats.t_states, 2e6)).abs() < 1e-6);
+        assert!(stats.elapsed >= stats.simulated);
+        let stats = run(Speed::Unlimited);
+        assert_eq!((stats.resyncs, stats.slept), (0, Duration::ZERO));
+
+        for factor in [0.0, -1.0, f64::NAN, f64::INFINITY, 1e-300, 1e300] {
+            assert_eq!(ClockConfig::new(2_000_000, Speed::Times(factor)), Err(clock::ClockError::InvalidSpeed));
+            let mut sim = simulator::Microcontroller::new();
+            let clock = ClockConfig { speed: Speed::Times(factor), ..ClockConfig::sdk85() };
+            assert_eq!(sim.start_paced(&clock), Err(error::ExecError::InvalidClock(clock::ClockError::InvalidSpeed)));
+        }
+        assert_eq!(ClockConfig::new(0, Speed::Unlimited), Err(clock::ClockError::NoCrystal));
+        assert!(ClockConfig::new(2_000_000, Speed::Times(0.5)).is_ok());
+    }
+
+    #[test]
+    fn test_interrupts() {
+        use interrupts::InterruptPin::{ Intr, Rst55, Rst65, Rst75, Trap };
+        let image = assembler::assembler::assemble("
+                    ORG 2CH
+                    EI
+                    RET
+                    ORG 100H
+                    LXI SP, 3000H
+                    EI
+                    NOP
+                    NOP
+                    HLT
+        ").unwrap_or_else(|parse_error| panic!("{parse_error}"));
+        let mut sim = simulator::Microcontroller::new();
+        for segment in &image.segments {
+            sim.load_code(&segment.bytes, segment.origin).unwrap();
+        }
+        sim.program_counter = 0x100;
+        sim.running = true;
+        let top_of_stack = |sim: &simulator::Microcontroller| {
+            let sp = sim.get_register_pair(simulator::Register::SP).unwrap();
+            (sim.get_data_at(Some(sp + 1)) as u16) << 8 | sim.get_data_at(Some(sp)) as u16
+        };
+
+        // Nothing is taken before EI, nor until the instruction after it has run.
+        sim.tick().unwrap();
+        sim.set_pin(Rst55, true);
+        sim.tick().unwrap();
+        sim.tick().unwrap();
+        assert_eq!(sim.program_counter, 0x105);
+        assert_eq!(sim.tick().unwrap().t_states, 12);
+        assert_eq!((sim.program_counter, top_of_stack(&sim)), (0x2C, 0x105));
+        assert!(!sim.interrupts_enabled());
+        sim.set_pin(Rst55, false);
+        sim.tick().unwrap();
+        sim.tick().unwrap();
+        assert_eq!(sim.program_counter, 0x105);
+
+        // RST 7.5 remembers an edge, TRAP needs one and the pin still high, and both win over the rest.
+        sim.set_pin(Intr, true);
+        sim.set_pin(Rst65, true);
+        assert_eq!(sim.pending_interrupt(), Some(Rst65));
+        sim.set_pin(Rst75, true);
+        sim.set_pin(Rst75, false);
+        assert_eq!(sim.pending_interrupt(), Some(Rst75));
+        sim.set_pin(Trap, true);
+        assert_eq!(sim.pending_interrupt(), Some(Trap));
+        sim.set_pin(Trap, false);
+        assert_eq!(sim.pending_interrupt(), Some(Rst75));
+        sim.tick().unwrap();
+        assert_eq!(sim.program_counter, 0x3C);
+        assert_eq!(sim.pending_interrupt(), None);
+        sim.set_pin(Trap, true);
+        sim.tick().unwrap();
+        assert_eq!((sim.program_counter, top_of_stack(&sim)), (0x24, 0x3C));
+        assert_eq!(sim.pending_interrupt(), None);
+
+        // INTR runs whatever instruction the device answers INTA with.
+        sim.set_pin(Rst65, false);
+        sim.set_intr_instruction(&[0xCD, 0x00, 0x02]);
+        sim.enable_interrupts();
+        assert_eq!(sim.pending_interrupt(), None);
+        sim.tick().unwrap();
+        assert_eq!(sim.pending_interrupt(), Some(Intr));
+        assert_eq!(sim.tick().unwrap().t_states, 18);
+        assert_eq!((sim.program_counter, top_of_stack(&sim)), (0x200, 0x25));
+    }
+
+    #[test]
+    fn test_halt_until_interrupt() {
+        use io::{ IoDevice, Signals };
+
+        /// Pulses RST 7.5 every 1000 T-states, starting 500 T-states in.
+        struct Ticker;
+        impl IoDevice for Ticker {
+            fn read(&mut self, _port: u8, _cycle: u64) -> u8 {
+                0
+            }
+            fn write(&mut self, _port: u8, _value: u8, _cycle: u64) {}
+            fn clock(&mut self, cycle: u64, signals: &mut Signals) {
+                signals.set_pin(interrupts::InterruptPin::Rst75, cycle % 1000 >= 500);
+            }
+        }
+
+        let image = assembler::assembler::assemble("
+                    ORG 3CH
+                    INR B
+                    EI
+                    RET
+                    ORG 100H
+                    LXI SP, 3000H
+                    MVI B, 0
+                    EI
+            WAIT:   HLT
+                    MOV A, B
+                    CPI 3
+                    JNZ WAIT
+                    DI
+                    HLT
+        ").unwrap_or_else(|parse_error| panic!("{parse_error}"));
+        let mut sim = simulator::Microcontroller::new();
+        for segment in &image.segments {
+            sim.load_code(&segment.bytes, segment.origin).unwrap();
+        }
+        sim.program_counter = 0x100;
+        sim.attach_io(0x00..=0x00, Box::new(Ticker));
+
+        // Each HLT idles, with the clock running, until the next tick wakes it.
+        assert_eq!(sim.run_until(|sim| sim.is_halted()), Ok(run::StopReason::ConditionMet));
+        let halted_at = sim.cycles;
+        assert_eq!(sim.step(), Ok(simulator::Cost { t_states: 3, machine_cycles: 1 }));
+        assert_eq!((sim.cycles, sim.program_counter), (halted_at + 3, 0x107));
+        run_to_halt(&mut sim);
+        assert_eq!(sim.get_register(simulator::Register::B), Ok(3));
+        assert!(!sim.is_halted());
+        assert!(sim.cycles >= 2500);
+
+        // With no device attached nothing could wake an EI / HLT, so every kind of run returns.
+        let image = assembler::assembler::assemble("EI\nHLT")
+            .unwrap_or_else(|parse_error| panic!("{parse_error}"));
+        let mut sim = simulator::Microcontroller::new();
+        sim.load_code(&image.segments[0].bytes, 0).unwrap();
+        run_to_halt(&mut sim);
+        assert!(!sim.is_halted());
+        sim.program_counter = 0;
+        assert_eq!(sim.run_until(|_| false), Ok(run::StopReason::Halted));
+        sim.program_counter = 0;
+        assert_eq!(sim.start(), Ok(()));
+        assert_eq!(sim.program_counter, 2);
+    }
+
+    #[test]
+    fn test_sim_rim() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        #[derive(Default)]
+        struct Probe {
+            sampled: Vec<u64>,
+            sod: Vec<(u64, bool)>,
+        }
+        struct Wire(Rc<RefCell<Probe>>);
+        impl serial::SerialDevice for Wire {
+            fn sid(&mut self, cycle: u64) -> bool {
+                self.0.borrow_mut().sampled.push(cycle);
+                true
+            }
+            fn sod(&mut self, cycle: u64, level: bool) {
+                self.0.borrow_mut().sod.push((cycle, level));
+            }
+        }
+
+        let image = assembler::assembler::assemble("
+                    MVI A, 0DH      ; mask RST 7.5 and 5.5
+                    SIM
+                    MVI A, 0C0H     ; SOD high
+                    SIM
+                    MVI A, 40H      ; SOD low
+                    SIM
+                    RIM
+                    STA 100H
+                    MVI A, 18H      ; unmask everything and forget RST 7.5
+                    SIM
+                    RIM
+                    STA 101H
+                    HLT
+        ").unwrap_or_else(|parse_error| panic!("{parse_error}"));
+        let mut sim = simulator::Microcontroller::new();
+        sim.load_code(&image.segments[0].bytes, 0).unwrap();
+        let probe = Rc::new(RefCell::new(Probe::default()));
+        sim.attach_serial(Box::new(Wire(probe.clone())));
+        sim.set_pin(interrupts::InterruptPin::Rst65, true);
+        sim.set_pin(interrupts::InterruptPin::Rst75, true);
+        run_to_halt(&mut sim);
+
+        assert_eq!(sim.get_data_at(Some(0x100)), 0b11100101);
+        assert_eq!(sim.get_data_at(Some(0x101)), 0b10100000);
+        assert!(!sim.sod());
+        let probe = probe.borrow();
+        assert_eq!(probe.sod, [(18, true), (29, false)]);
+        assert_eq!(probe.sampled, [33, 61]);
+    }
+
+    #[test]
+    fn test_serial_terminal() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        struct Screen(Rc<RefCell<Vec<u8>>>);
+        impl std::io::Write for Screen {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.borrow_mut().extend_from_slice(buf);
+                Ok(buf.len())
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        // A software UART with 1000 T-state bits, sending "HI" and then reading two bytes.
+        let image = assembler::assembler::assemble("
+                    LXI SP, 3000H
+                    MVI A, 0C0H
+                    SIM
+                    CALL DELAY
+                    MVI A, 'H'
+                    CALL SEND
+                    MVI A, 'I'
+                    CALL SEND
+                    CALL RECV
+                    STA 2000H
+                    CALL RECV
+                    STA 2001H
+                    HLT
+
+            SEND:   MOV B, A
+                    MVI A, 40H
+                    SIM
+                    CALL DELAY
+                    MVI C, 8
+            SBIT:   MOV A, B
+                    RRC
+                    MOV B, A
+                    ANI 80H
+                    ORI 40H
+                    SIM
+                    CALL DELAY
+                    DCR C
+                    JNZ SBIT
+                    MVI A, 0C0H
+                    SIM
+                    CALL DELAY
+                    RET
+
+            RECV:   RIM
+                    ORA A
+                    JM RECV
+                    MVI D, 33
+                    CALL WAIT
+                    MVI C, 8
+            RBIT:   CALL DELAY
+                    RIM
+                    RAL
+                    MOV A, B
+                    RAR
+                    MOV B, A
+                    DCR C
+                    JNZ RBIT
+                    CALL DELAY
+                    MOV A, B
+                    RET
+
+            DELAY:  MVI D, 66
+            WAIT:   DCR D
+                    JNZ WAIT
+                    RET
+        ").unwrap_or_else(|parse_error| panic!("{parse_error}"));
+        let mut sim = simulator::Microcontroller::new();
+        sim.load_code(&image.segments[0].bytes, 0).unwrap();
+        let screen = Rc::new(RefCell::new(vec![]));
+        let (keyboard, input) = std::sync::mpsc::channel();
+        let config = serial::SerialConfig { baud: 1000, t_states_per_second: 1_000_000, stop_bits: 1 };
+        let terminal = serial::SerialTerminal::new(config, Box::new(Screen(screen.clone())), input);
+        sim.attach_serial(Box::new(terminal));
+        keyboard.send(b'O').unwrap();
+        keyboard.send(b'K').unwrap();
+        run_to_halt(&mut sim);
+        assert_eq!(*screen.borrow(), b"HI");
+        assert_eq!([sim.get_data_at(Some(0x2000)), sim.get_data_at(Some(0x2001))], *b"OK");
+    }
+
+    #[test]
+    fn test_io_devices() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+        use io::{ IoDevice, Signals };
+
+        /// Remembers what was written to it and when; reading port n gives it back plus n.
+        struct Latch(Rc<RefCell<(u8, u64)>>);
+        impl IoDevice for Latch {
+            fn read(&mut self, port: u8, _cycle: u64) -> u8 {
+                self.0.borrow().0 + port
+            }
+            fn write(&mut self, _port: u8, value: u8, cycle: u64) {
+                *self.0.borrow_mut() = (value, cycle);
+            }
+        }
+
+        /// Raises RST 5.5 once 200 T-states have passed, until its port is read.
+        #[derive(Default)]
+        struct Timer {
+            fired: bool,
+            read: bool,
+        }
+        impl IoDevice for Timer {
+            fn read(&mut self, _port: u8, _cycle: u64) -> u8 {
+                self.read = true;
+                0x99
+            }
+            fn write(&mut self, _port: u8, _value: u8, _cycle: u64) {}
+            fn clock(&mut self, cycle: u64, signals: &mut Signals) {
+                if cycle >= 200 && !self.fired {
+                    self.fired = true;
+                    signals.set_pin(interrupts::InterruptPin::Rst55, true);
+                }
+                if std::mem::take(&mut self.read) {
+                    signals.set_pin(interrupts::InterruptPin::Rst55, false);
+                }
+            }
+        }
+
+        let image = assembler::assembler::assemble("
+                    ORG 2CH
+                    IN 20H
+                    STA 2002H
+                    HLT
+                    ORG 100H
+                    LXI SP, 3000H
+                    MVI A, 42H
+                    OUT 10H
+                    IN 1
+                    STA 2000H
+                    IN 80H
+                    STA 2001H
+                    EI
+            LOOP:   JMP LOOP
+        ").unwrap_or_else(|parse_error| panic!("{parse_error}"));
+        let mut sim = simulator::Microcontroller::new();
+        for segment in &image.segments {
+            sim.load_code(&segment.bytes, segment.origin).unwrap();
+        }
+        let latch = Rc::new(RefCell::new((0, 0)));
+        sim.attach_io(0x00..=0x1F, Box::new(Latch(latch.clone())));
+        sim.attach_io(0x20..=0x20, Box::new(Timer::default()));
+        sim.program_counter = 0x100;
+        run_to_halt(&mut sim);
+        assert_eq!(*latch.borrow(), (0x42, 17));
+        let written: Vec<_> = (0x2000..0x2003).map(|addr| sim.get_data_at(Some(addr))).collect();
+        assert_eq!(written, [0x43, 0xFF, 0x99]);
+        assert!(!sim.pin(interrupts::InterruptPin::Rst55));
+        assert!(sim.cycles >= 200);
+    }
+
+    #[test]
+    fn test_memory_map() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+        use memory::{ MemoryDevice, MemoryMap, Region };
+
+        struct Display(Rc<RefCell<Vec<(u16, u8)>>>);
+        impl MemoryDevice for Display {
+            fn read(&self, _offset: u16, _cycle: u64) -> u8 {
+                0
+            }
+            fn write(&mut self, offset: u16, value: u8, _cycle: u64) {
+                self.0.borrow_mut().push((offset, value));
+            }
+        }
+
+        let mut sim = simulator::Microcontroller::new();
+        sim.set_data_at(Some(0xFFFF), 0x12);
+        assert_eq!(sim.get_data_at(Some(0xFFFF)), 0x12);
+
+        // ROM at 0000H, 256 bytes of RAM decoded across 2000H-27FFH and a display at 8000H.
+        let written = Rc::new(RefCell::new(vec![]));
+        sim.set_memory_map(
+            MemoryMap::unmapped(0xFF)
+                .map(0x0000..=0x07FF, Region::Rom)
+                .map_mirrored(0x2000..=0x27FF, 0x100, Region::Ram)
+                .map(0x8000..=0x8007, Region::Device(Box::new(Display(written.clone())))),
+        );
+        let image = assembler::assembler::assemble("
+                    MVI A, 55H
+                    STA 0700H
+                    STA 2005H
+                    LDA 2105H
+                    STA 8003H
+                    LDA 4000H
+                    STA 2006H
+                    HLT
+        ").unwrap_or_else(|parse_error| panic!("{parse_error}"));
+        sim.load_code(&image.segments[0].bytes, 0).unwrap();
+        run_to_halt(&mut sim);
+        assert_eq!(sim.get_data_at(Some(0x0700)), 0x00);
+        assert_eq!(sim.get_data_at(Some(0x2005)), 0x55);
+        assert_eq!(sim.get_data_at(Some(0x2706)), 0xFF);
+        assert_eq!(*written.borrow(), [(3, 0x55)]);
+        assert_eq!(sim.load_code(&[0x76], 0x1000), Err("No memory at 1000H to load code into".to_owned()));
+    }
+
+    #[test]
+    fn test_memory_faults() {
+        use fault::{ FaultAction, FaultKind, FaultPolicy };
+        use memory::{ MemoryMap, Region };
+
+        let mut sim = simulator::Microcontroller::new();
+        sim.set_memory_map(
+            MemoryMap::unmapped(0xFF)
+                .map(0x0000..=0x07FF, Region::Rom)
+                .map(0x2000..=0x5FFF, Region::Ram)
+                .data(0x5000..=0x50FF),
+        );
+        sim.fault_policy = FaultPolicy {
+            rom_write: FaultAction::Log,
+            unmapped_read: FaultAction::Log,
+            ..FaultPolicy::strict()
+        };
+        let image = assembler::assembler::assemble("
+                    MVI A, 55H
+                    STA 0700H
+                    LDA 1000H
+                    LXI H, 5003H
+                    PCHL
+        ").unwrap_or_else(|parse_error| panic!("{parse_error}"));
+        sim.load_code(&image.segments[0].bytes, 0x2000).unwrap();
+        assert!(matches!(sim.start(), Err(error::ExecError::MemoryFault(_))));
+        let kinds: Vec<FaultKind> = sim.faults().iter().map(|fault| fault.kind).collect();
+        assert_eq!(kinds, [FaultKind::RomWrite, FaultKind::UnmappedRead, FaultKind::ExecuteData]);
+        assert_eq!(sim.faults()[0].to_string(), "`STA 0700H` at 2002H wrote to ROM at 0700H");
+        assert_eq!(sim.halted_by().unwrap().to_string(), "The program jumped into data at 5003H");
+        assert_eq!(sim.program_counter, 0x5003);
+
+        // Running off the end of memory wraps the program counter round to 0000H.
+        sim.set_memory_map(MemoryMap::default());
+        sim.clear_faults();
+        sim.fault_policy = FaultPolicy::strict();
+        sim.load_code(&[0x00], 0xFFFF).unwrap();
+        assert!(matches!(sim.start(), Err(error::ExecError::MemoryFault(_))));
+        let fault = sim.halted_by().unwrap();
+        assert_eq!((fault.kind, fault.pc), (FaultKind::PcWrap, 0xFFFF));
+        assert_eq!(sim.program_counter, 0x0000);
+    }
+
+    #[test]
+    fn test_exec_errors() {
+        use error::ExecError;
+        use memory::MemoryMap;
+        use run::StopReason;
+
+        let mut sim = simulator::Microcontroller::new();
+        assert_eq!(sim.tick(), Err(ExecError::Halted));
+
+        // The stack pointer starts at 0000H, so the first push goes to FFFEH where the SDK-85
+        // has nothing.
+        sim.set_memory_map(MemoryMap::sdk85());
+        sim.load_code(&[0x3E, 0x01, 0xF5, 0x76], 0x2000).unwrap();
+        let error = sim.start().unwrap_err();
+        assert_eq!(error, ExecError::StackFault { sp: 0xFFFE, pc: 0x2002 });
+        assert_eq!(error.to_string(), "The stack ran out of RAM at FFFEH, in the instruction at 2002H");
+
+        // LOOP: JMP LOOP
+        sim.load_code(&[0xC3, 0x00, 0x20], 0x2000).unwrap();
+        assert_eq!(sim.run_for(1000), Ok(StopReason::BudgetExhausted));
+        assert!(!sim.running);
+        sim.load_code(&[0x00, 0x76], 0x2000).unwrap();
+        let first = sim.cycles;
+        assert_eq!(sim.run_for(1000), Ok(StopReason::Halted));
+        assert_eq!(sim.cycles - first, 9);
+    }
+
+    #[test]
+    fn test_run_limits() {
+        use run::{ RunLimits, StopReason };
+
+        let mut sim = simulator::Microcontroller::new();
+        // LOOP: INR A
+        //       JMP LOOP
+        sim.load_code(&[0x3C, 0xC3, 0x00, 0x20], 0x2000).unwrap();
+        assert_eq!(sim.run_for(140), Ok(StopReason::BudgetExhausted));
+        assert_eq!(sim.get_register(simulator::Register::A), Ok(10));
+        assert!(!sim.running);
+
+        let stop = sim.run_until(|sim| sim.get_register(simulator::Register::A) == Ok(0x80));
+        assert_eq!(stop, Ok(StopReason::ConditionMet));
+        assert_eq!(sim.program_counter, 0x2001);
+
+        let limits = RunLimits::timeout(std::time::Duration::from_millis(10));
+        assert_eq!(sim.run_with(limits, |_| false), Ok(StopReason::DeadlineReached));
+
+        let cancel = sim.cancel_handle();
+        let canceller = std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+            cancel.cancel();
+        });
+        assert_eq!(sim.run_until(|_| false), Ok(StopReason::Cancelled));
+        canceller.join().unwrap();
+        assert!(!sim.cancel_handle().is_cancelled());
+
+        sim.load_code(&[0x76], 0x2000).unwrap();
+        assert_eq!(sim.run_for(140), Ok(StopReason::Halted));
+    }
+
+
+    #[test]
+    fn test_debugger() {
+        use std::collections::HashMap;
+        use debugger::{ Access, Breakpoint, Condition };
+        use run::StopReason;
+        use simulator::Register;
+
+        let mut sim = simulator::Microcontroller::new();
+        let image = assembler::assembler::assemble("
+                    ORG 2000H
+                    LXI SP, 3000H
+                    MVI B, 03H
+            LOOP:   MOV A, B
+                    STA 2100H
+                    OUT 10H
+                    DCR B
+                    JNZ LOOP
+                    HLT
+        ").unwrap_or_else(|parse_error| panic!("{parse_error}"));
+        sim.load_code(&image.segments[0].bytes, 0x2000).unwrap();
+        let symbols = HashMap::from([("LOOP".to_owned(), 0x2005)]);
+
+        let at_loop = sim.break_at_symbol("LOOP", &symbols).unwrap();
+        assert_eq!(sim.run_for(10_000), Ok(StopReason::Breakpoint(at_loop)));
+        assert_eq!(sim.program_counter, 0x2005);
+        assert_eq!(sim.run_for(10_000), Ok(StopReason::Breakpoint(at_loop)));
+        assert_eq!((sim.get_register(Register::B), sim.breakpoint(at_loop).unwrap().hits), (Ok(2), 2));
+        sim.breakpoint_mut(at_loop).unwrap().enabled = false;
+
+        let condition = Condition::parse("a == 1").unwrap();
+        let store = sim.add_breakpoint(Breakpoint::memory(0x2100..=0x2100, Access::Write).when(condition));
+        assert_eq!(sim.run_for(10_000), Ok(StopReason::Breakpoint(store)));
+        assert_eq!((sim.program_counter, sim.get_data_at(Some(0x2100))), (0x2009, 1));
+        assert!(!sim.debugger.recording);
+        sim.remove_breakpoint(store);
+
+        let out = sim.add_breakpoint(Breakpoint::port(0x10..=0x1F, Access::ReadWrite));
+        assert_eq!(sim.run_for(10_000), Ok(StopReason::Breakpoint(out)));
+        assert_eq!(sim.program_counter, 0x200B);
+        sim.remove_breakpoint(out);
+
+        let condition = "B == 0 && Z && [2100H] == 1 && PC - 2 == 200AH".parse().unwrap();
+        let last = sim.add_breakpoint(Breakpoint::register(Register::B).when(condition));
+        assert_eq!(sim.run_for(10_000), Ok(StopReason::Breakpoint(last)));
+        assert_eq!(sim.program_counter, 0x200C);
+        sim.remove_breakpoint(last);
+        // Accesses stop being recorded however the run ends, halting or failing included.
+        let unused = sim.add_breakpoint(Breakpoint::port(0x20..=0x20, Access::ReadWrite));
+        assert_eq!(sim.run_for(10_000), Ok(StopReason::Halted));
+        assert!(!sim.debugger.recording);
+        sim.set_memory_map(memory::MemoryMap::sdk85());
+        sim.load_code(&[0xF5], 0x2000).unwrap();
+        assert!(matches!(sim.run_for(10_000), Err(error::ExecError::StackFault { .. })));
+        assert!(!sim.debugger.recording);
+        sim.remove_breakpoint(unused);
+
+        assert!(Condition::parse("A ==").is_err());
+        assert_eq!(Condition::parse("A = 1").unwrap_err(), "Unexpected `=` in condition `A = 1`");
+        assert_eq!(Condition::parse("Q").unwrap_err(), "Unknown name `Q` in condition");
+    }
+
 }
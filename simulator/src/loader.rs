@@ -0,0 +1,254 @@
+use std::fmt::Display;
+
+use crate::simulator::Microcontroller;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LoadErrorKind {
+    /// The line is not a record of the expected format.
+    Syntax(String),
+    /// The record's checksum is `.1` where its contents add up to `.0`.
+    Checksum(u8, u8),
+    /// The byte count of the record does not match the data on the line.
+    Length(usize, usize),
+    /// A record type the loader does not know, like `05` in S-records.
+    UnsupportedRecord(String),
+    /// The record's data ends past the simulator's memory.
+    DoesNotFit(u32),
+}
+
+/// An error loading a program, on the 1-based `line` of the input.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LoadError {
+    pub line: usize,
+    pub error: LoadErrorKind,
+}
+
+impl Display for LoadErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LoadErrorKind::Syntax(text) => f.write_fmt(format_args!("Malformed record: {}", text)),
+            LoadErrorKind::Checksum(expected, found) => f.write_fmt(format_args!(
+                "Checksum mismatch: expected {:02X}, found {:02X}",
+                expected, found
+            )),
+            LoadErrorKind::Length(expected, found) => f.write_fmt(format_args!(
+                "Record should have {} bytes, found {}",
+                expected, found
+            )),
+            LoadErrorKind::UnsupportedRecord(kind) => {
+                f.write_fmt(format_args!("Unsupported record type: {}", kind))
+            }
+            LoadErrorKind::DoesNotFit(address) => {
+                f.write_fmt(format_args!("Data at {:04X}H does not fit in memory", address))
+            }
+        }
+    }
+}
+
+impl Display for LoadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!("Load error on line {}: {}", self.line, self.error))
+    }
+}
+
+/// A run of bytes read from one line of the input.
+struct Record {
+    line: usize,
+    address: u32,
+    bytes: Vec<u8>,
+}
+
+/// Everything read from a program file, before any of it is written to memory.
+#[derive(Default)]
+struct Program {
+    records: Vec<Record>,
+    start: Option<u32>,
+}
+
+fn error(line: usize, error: LoadErrorKind) -> LoadError {
+    LoadError { line, error }
+}
+
+fn syntax(line: usize, text: &str) -> LoadError {
+    error(line, LoadErrorKind::Syntax(text.to_owned()))
+}
+
+/// Decodes a string of hex digit pairs.
+fn hex_bytes(line: usize, text: &str) -> Result<Vec<u8>, LoadError> {
+    if !text.len().is_multiple_of(2) || !text.is_ascii() {
+        return Err(syntax(line, text));
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&text[i..i + 2], 16).map_err(|_| syntax(line, text)))
+        .collect()
+}
+
+fn address(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0, |address, byte| address << 8 | *byte as u32)
+}
+
+fn parse_intel_hex(text: &str) -> Result<Program, LoadError> {
+    let mut program = Program::default();
+    // Upper address bits from extended segment (02) and extended linear (04) records.
+    let mut base = 0u32;
+    for (index, raw) in text.lines().enumerate() {
+        let line = index + 1;
+        let record = raw.trim();
+        if record.is_empty() {
+            continue;
+        }
+        let bytes = match record.strip_prefix(':') {
+            Some(digits) => hex_bytes(line, digits)?,
+            None => return Err(syntax(line, record)),
+        };
+        if bytes.len() < 5 {
+            return Err(syntax(line, record));
+        }
+        let length = bytes[0] as usize;
+        if bytes.len() != length + 5 {
+            return Err(error(line, LoadErrorKind::Length(length, bytes.len().saturating_sub(5))));
+        }
+        let (contents, checksum) = bytes.split_at(bytes.len() - 1);
+        let expected = contents.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)).wrapping_neg();
+        if expected != checksum[0] {
+            return Err(error(line, LoadErrorKind::Checksum(expected, checksum[0])));
+        }
+        let data = &contents[4..];
+        match contents[3] {
+            0x00 => program.records.push(Record {
+                line,
+                address: base + address(&contents[1..3]),
+                bytes: data.to_vec(),
+            }),
+            0x01 => break,
+            0x02 if length == 2 => base = address(data) << 4,
+            0x04 if length == 2 => base = address(data) << 16,
+            // CS:IP and linear start addresses
+            0x03 if length == 4 => program.start = Some((address(&data[..2]) << 4) + address(&data[2..])),
+            0x05 if length == 4 => program.start = Some(address(data)),
+            0x02..=0x05 => return Err(syntax(line, record)),
+            kind => return Err(error(line, LoadErrorKind::UnsupportedRecord(format!("{:02X}", kind)))),
+        }
+    }
+    Ok(program)
+}
+
+fn parse_s_records(text: &str) -> Result<Program, LoadError> {
+    let mut program = Program::default();
+    for (index, raw) in text.lines().enumerate() {
+        let line = index + 1;
+        let record = raw.trim();
+        if record.is_empty() {
+            continue;
+        }
+        // `get` rather than indexing, so a multi-byte character after the S is a syntax error.
+        let kind = match record.get(1..2) {
+            Some(kind) if record.starts_with(['S', 's']) => kind,
+            _ => return Err(syntax(line, record)),
+        };
+        let bytes = hex_bytes(line, &record[2..])?;
+        let length = match bytes.first() {
+            Some(length) => *length as usize,
+            None => return Err(syntax(line, record)),
+        };
+        if bytes.len() != length + 1 {
+            return Err(error(line, LoadErrorKind::Length(length, bytes.len() - 1)));
+        }
+        let (contents, checksum) = bytes.split_at(bytes.len() - 1);
+        let expected = !contents.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+        if checksum[0] != expected {
+            return Err(error(line, LoadErrorKind::Checksum(expected, checksum[0])));
+        }
+        let address_length = match kind {
+            "0" | "1" | "5" | "9" => 2,
+            "2" | "6" | "8" => 3,
+            "3" | "7" => 4,
+            _ => return Err(error(line, LoadErrorKind::UnsupportedRecord(format!("S{}", kind)))),
+        };
+        if contents.len() < address_length + 1 {
+            return Err(syntax(line, record));
+        }
+        let record_address = address(&contents[1..address_length + 1]);
+        let data = &contents[address_length + 1..];
+        match kind {
+            "1" | "2" | "3" => program.records.push(Record {
+                line,
+                address: record_address,
+                bytes: data.to_vec(),
+            }),
+            "7" | "8" | "9" => program.start = Some(record_address),
+            // S0 headers and S5/S6 record counts carry nothing to load.
+            _ => {}
+        }
+    }
+    Ok(program)
+}
+
+/// Reads listings as printed in trainer lab manuals: an address, an optional colon and the
+/// bytes stored from there on, one line each (`2000: 3E 05`). Anything after a `;` or after
+/// the first word that is not a two digit byte, such as the mnemonic, is ignored.
+fn parse_trainer_listing(text: &str) -> Result<Program, LoadError> {
+    let mut program = Program::default();
+    for (index, raw) in text.lines().enumerate() {
+        let line = index + 1;
+        let code = raw.split(';').next().unwrap_or_default().trim();
+        if code.is_empty() {
+            continue;
+        }
+        let (location, rest) = match code.split_once(|char: char| char == ':' || char.is_whitespace()) {
+            Some(split) => split,
+            None => (code, ""),
+        };
+        let location = location.trim_end_matches(['H', 'h']);
+        let record_address = match u16::from_str_radix(location, 16) {
+            Ok(record_address) => record_address as u32,
+            Err(_) => return Err(syntax(line, code)),
+        };
+        let bytes = rest
+            .split_whitespace()
+            .map_while(|word| {
+                let word = word.trim_start_matches(':');
+                if word.len() == 2 { u8::from_str_radix(word, 16).ok() } else { None }
+            })
+            .collect();
+        program.records.push(Record { line, address: record_address, bytes });
+    }
+    Ok(program)
+}
+
+impl Microcontroller {
+    /// Writes every record to memory and points `program_counter` at the start address, or at
+    /// the first record when the file gives none.
+    fn load_program(&mut self, program: Program) -> Result<(), LoadError> {
+        for record in &program.records {
+            let end = record.address + record.bytes.len() as u32;
+            if end > u16::MAX as u32 + 1 {
+                return Err(error(record.line, LoadErrorKind::DoesNotFit(record.address)));
+            }
+            self.load_code(&record.bytes, record.address as u16)
+                .map_err(|_| error(record.line, LoadErrorKind::DoesNotFit(record.address)))?;
+        }
+        let start = program.start.or_else(|| program.records.first().map(|record| record.address));
+        self.program_counter = start.unwrap_or(0) as u16;
+        Ok(())
+    }
+
+    /// Loads an Intel HEX file, validating each record's checksum.
+    pub fn load_intel_hex(&mut self, text: &str) -> Result<(), LoadError> {
+        let program = parse_intel_hex(text)?;
+        self.load_program(program)
+    }
+
+    /// Loads Motorola S-records (S19, S28 or S37), validating each record's checksum.
+    pub fn load_s_records(&mut self, text: &str) -> Result<(), LoadError> {
+        let program = parse_s_records(text)?;
+        self.load_program(program)
+    }
+
+    /// Loads an `address: byte byte ...` listing, starting execution at its first address.
+    pub fn load_trainer_listing(&mut self, text: &str) -> Result<(), LoadError> {
+        let program = parse_trainer_listing(text)?;
+        self.load_program(program)
+    }
+}
@@ -0,0 +1,149 @@
+use std::ops::RangeInclusive;
+
+use crate::fault::FaultKind;
+
+/// A peripheral that sits in the memory address space, reached with ordinary loads and stores.
+///
+/// `offset` counts from the start of the device's region, after any mirroring, and `cycle` is the
+/// value of `Microcontroller::cycles` when the instruction making the access started.
+pub trait MemoryDevice {
+    /// Takes `&self` because the simulator also reads memory just to show it, as the disassembler
+    /// does; a device whose registers change when read needs a `Cell` for them.
+    fn read(&self, offset: u16, cycle: u64) -> u8;
+    fn write(&mut self, offset: u16, value: u8, cycle: u64);
+}
+
+/// What answers to a range of addresses.
+pub enum Region {
+    Ram,
+    /// Read-only: stores are ignored. Programs are still loaded into it, as if the chip had been
+    /// programmed before being plugged in.
+    Rom,
+    Device(Box<dyn MemoryDevice>),
+}
+
+struct Mapping {
+    range: RangeInclusive<u16>,
+    /// How many bytes the region really has. Partial address decoding repeats them across the
+    /// rest of `range`.
+    size: u32,
+    region: Region,
+}
+
+/// How the 64 KiB address space is decoded. Addresses nothing is mapped to read as the open bus
+/// value and ignore writes.
+pub struct MemoryMap {
+    mappings: Vec<Mapping>,
+    /// Ranges holding data rather than code, where fetching an instruction is a fault.
+    data: Vec<RangeInclusive<u16>>,
+    open_bus: u8,
+    storage: Vec<u8>,
+}
+
+impl MemoryMap {
+    /// An address space with nothing in it, where reads see `open_bus`. Pull-up resistors on the
+    /// data bus make that FFH on most boards.
+    pub fn unmapped(open_bus: u8) -> MemoryMap {
+        MemoryMap { mappings: vec![], data: vec![], open_bus, storage: vec![0; 0x10000] }
+    }
+
+    /// Maps `region` to every address in `range`, over whatever was mapped there before.
+    pub fn map(self, range: RangeInclusive<u16>, region: Region) -> MemoryMap {
+        let size = *range.end() as u32 - *range.start() as u32 + 1;
+        self.map_mirrored(range, size as u16, region)
+    }
+
+    /// Like `map`, for a region of only `size` bytes that incomplete address decoding repeats
+    /// across the whole of `range`.
+    pub fn map_mirrored(mut self, range: RangeInclusive<u16>, size: u16, region: Region) -> MemoryMap {
+        // A size of 0 can only come from `map` wrapping a full 64 KiB range.
+        let size = if size == 0 { 0x10000 } else { size as u32 };
+        self.mappings.push(Mapping { range, size, region });
+        self
+    }
+
+    /// Marks `range` as holding data, so that running into it is an `ExecuteData` fault.
+    pub fn data(mut self, range: RangeInclusive<u16>) -> MemoryMap {
+        self.mark_data(range);
+        self
+    }
+
+    pub(crate) fn mark_data(&mut self, range: RangeInclusive<u16>) {
+        self.data.push(range);
+    }
+
+    pub(crate) fn is_data(&self, address: u16) -> bool {
+        self.data.iter().any(|range| range.contains(&address))
+    }
+
+    pub(crate) fn is_mapped(&self, address: u16) -> bool {
+        self.find(address).is_some()
+    }
+
+    /// Whether a store to `address` would land somewhere.
+    pub(crate) fn is_writable(&self, address: u16) -> bool {
+        self.find(address).is_some_and(|(index, _)| !matches!(self.mappings[index].region, Region::Rom))
+    }
+
+    /// The SDK-85 as shipped: the monitor's 2 KiB of ROM at 0000H and the 8155's 256 bytes of RAM
+    /// at 2000H.
+    pub fn sdk85() -> MemoryMap {
+        MemoryMap::unmapped(0xFF)
+            .map(0x0000..=0x07FF, Region::Rom)
+            .map(0x2000..=0x20FF, Region::Ram)
+    }
+
+    /// The mapping `address` falls in, and how far into its region the address is.
+    fn find(&self, address: u16) -> Option<(usize, u16)> {
+        let index = self.mappings.iter().rposition(|mapping| mapping.range.contains(&address))?;
+        let mapping = &self.mappings[index];
+        Some((index, ((address - mapping.range.start()) as u32 % mapping.size) as u16))
+    }
+
+    pub(crate) fn read(&self, address: u16, cycle: u64) -> u8 {
+        let Some((index, offset)) = self.find(address) else {
+            return self.open_bus;
+        };
+        let mapping = &self.mappings[index];
+        match &mapping.region {
+            Region::Ram | Region::Rom => self.storage[(mapping.range.start() + offset) as usize],
+            Region::Device(device) => device.read(offset, cycle),
+        }
+    }
+
+    /// Stores `value` at `address`, or says why nothing took it.
+    pub(crate) fn write(&mut self, address: u16, value: u8, cycle: u64) -> Result<(), FaultKind> {
+        self.store(address, value, cycle, false)
+    }
+
+    /// Like `write`, but also into ROM.
+    pub(crate) fn load(&mut self, address: u16, value: u8) -> bool {
+        self.store(address, value, 0, true).is_ok()
+    }
+
+    fn store(&mut self, address: u16, value: u8, cycle: u64, programming: bool) -> Result<(), FaultKind> {
+        let Some((index, offset)) = self.find(address) else {
+            return Err(FaultKind::UnmappedWrite);
+        };
+        let mapping = &mut self.mappings[index];
+        let address = (mapping.range.start() + offset) as usize;
+        match &mut mapping.region {
+            Region::Ram => self.storage[address] = value,
+            Region::Rom if programming => self.storage[address] = value,
+            Region::Rom => return Err(FaultKind::RomWrite),
+            Region::Device(device) => device.write(offset, value, cycle),
+        }
+        Ok(())
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.storage.fill(0);
+    }
+}
+
+impl Default for MemoryMap {
+    /// A full 64 KiB of RAM.
+    fn default() -> MemoryMap {
+        MemoryMap::unmapped(0xFF).map(0x0000..=0xFFFF, Region::Ram)
+    }
+}
@@ -0,0 +1,152 @@
+use std::sync::Arc;
+use std::sync::atomic::{ AtomicBool, Ordering };
+use std::time::{ Duration, Instant };
+
+use crate::debugger::BreakpointId;
+use crate::error::ExecError;
+use crate::simulator::Microcontroller;
+
+/// Instructions run between looks at the host clock, which costs more than running them.
+const DEADLINE_CHECK_EVERY: u32 = 256;
+
+/// Why a bounded run returned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    /// The program ran HLT with nothing able to wake it: interrupts were disabled, or no device
+    /// was attached to raise one.
+    Halted,
+    /// The run used up its T-states.
+    BudgetExhausted,
+    /// The condition the run was waiting for held.
+    ConditionMet,
+    /// The host clock reached the run's deadline.
+    DeadlineReached,
+    /// Someone called `CancelHandle::cancel`.
+    Cancelled,
+    /// A breakpoint or watchpoint fired.
+    Breakpoint(BreakpointId),
+}
+
+/// Stops a run from another thread. Clones share the same flag.
+#[derive(Debug, Clone, Default)]
+pub struct CancelHandle(Arc<AtomicBool>);
+
+impl CancelHandle {
+    /// Makes the current run, or the next one if none is going, stop after its instruction.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    /// Whether a cancellation is waiting to be noticed.
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// Notices a cancellation, clearing it for later runs.
+    fn take(&self) -> bool {
+        self.0.swap(false, Ordering::Relaxed)
+    }
+}
+
+/// Bounds on a run. A run stops at whichever it reaches first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct RunLimits {
+    /// The most T-states to run for.
+    pub t_states: Option<u64>,
+    /// When to stop on the host clock.
+    pub deadline: Option<Instant>,
+}
+
+impl RunLimits {
+    pub fn t_states(t_states: u64) -> RunLimits {
+        RunLimits { t_states: Some(t_states), deadline: None }
+    }
+
+    pub fn timeout(timeout: Duration) -> RunLimits {
+        RunLimits { t_states: None, deadline: Some(Instant::now() + timeout) }
+    }
+}
+
+impl Microcontroller {
+    /// A handle that stops this microcontroller's bounded runs, from any thread.
+    pub fn cancel_handle(&self) -> CancelHandle {
+        self.cancel.clone()
+    }
+
+    /// Runs until HLT or until `t_states` more T-states have gone by. An instruction is never cut
+    /// short, so the run can go a few T-states over.
+    pub fn run_for(&mut self, t_states: u64) -> Result<StopReason, ExecError> {
+        self.run_with(RunLimits::t_states(t_states), |_| false)
+    }
+
+    /// Runs until HLT or until `predicate` holds after an instruction.
+    pub fn run_until(
+        &mut self,
+        predicate: impl FnMut(&Microcontroller) -> bool,
+    ) -> Result<StopReason, ExecError> {
+        self.run_with(RunLimits::default(), predicate)
+    }
+
+    /// Runs until HLT or until `deadline` on the host clock.
+    pub fn run_until_deadline(&mut self, deadline: Instant) -> Result<StopReason, ExecError> {
+        self.run_with(RunLimits { t_states: None, deadline: Some(deadline) }, |_| false)
+    }
+
+    /// Runs until HLT, `predicate` holding, any of `limits` being reached, a breakpoint firing or
+    /// the run being cancelled, and says which. Errors from the program end the run the same way
+    /// as in `tick`.
+    pub fn run_with(
+        &mut self,
+        limits: RunLimits,
+        mut predicate: impl FnMut(&Microcontroller) -> bool,
+    ) -> Result<StopReason, ExecError> {
+        self.running = true;
+        self.halted_by = None;
+        self.start_debugging();
+        let result = self.run_limited(limits, &mut predicate);
+        // However the run ended, accesses are only recorded while one is going.
+        self.stop_debugging();
+        self.running = false;
+        result
+    }
+
+    /// The body of `run_with`, which sets up and tears down around it.
+    fn run_limited(
+        &mut self,
+        limits: RunLimits,
+        predicate: &mut impl FnMut(&Microcontroller) -> bool,
+    ) -> Result<StopReason, ExecError> {
+        let end = limits.t_states.map(|t_states| self.cycles.saturating_add(t_states));
+        let mut until_clock_check = 0;
+        loop {
+            if self.cancel.take() {
+                return Ok(StopReason::Cancelled);
+            }
+            if end.is_some_and(|end| self.cycles >= end) {
+                return Ok(StopReason::BudgetExhausted);
+            }
+            if let Some(deadline) = limits.deadline {
+                if until_clock_check == 0 {
+                    if Instant::now() >= deadline {
+                        return Ok(StopReason::DeadlineReached);
+                    }
+                    until_clock_check = DEADLINE_CHECK_EVERY;
+                }
+                until_clock_check -= 1;
+            }
+            if let Some(id) = self.break_before() {
+                return Ok(StopReason::Breakpoint(id));
+            }
+            self.tick()?;
+            if !self.running {
+                return Ok(StopReason::Halted);
+            }
+            if let Some(id) = self.break_after() {
+                return Ok(StopReason::Breakpoint(id));
+            }
+            if predicate(self) {
+                return Ok(StopReason::ConditionMet);
+            }
+        }
+    }
+}
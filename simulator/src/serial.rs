@@ -0,0 +1,185 @@
+use std::io::Write;
+use std::sync::mpsc::Receiver;
+
+use crate::simulator::Microcontroller;
+
+/// Something wired to the serial pins, which the 8085 drives with SIM and reads with RIM.
+///
+/// `cycle` is the value of `Microcontroller::cycles` when the SIM or RIM started, so the gaps
+/// between calls are the T-states a program spent between its bits.
+pub trait SerialDevice {
+    /// The level the device holds SID at when RIM samples it.
+    fn sid(&mut self, cycle: u64) -> bool;
+    /// SIM changed SOD to `level`. Only called when the level actually changes.
+    fn sod(&mut self, cycle: u64, level: bool);
+    /// Called after every instruction, for devices that need to notice time passing on its own.
+    fn clock(&mut self, _cycle: u64) {}
+}
+
+/// The SID and SOD pins.
+#[derive(Default)]
+pub(crate) struct SerialPins {
+    sid: bool,
+    sod: bool,
+    device: Option<Box<dyn SerialDevice>>,
+}
+
+impl Microcontroller {
+    /// Connects `device` to SID and SOD, returning the one it replaces.
+    pub fn attach_serial(&mut self, device: Box<dyn SerialDevice>) -> Option<Box<dyn SerialDevice>> {
+        self.serial.device.replace(device)
+    }
+
+    pub fn detach_serial(&mut self) -> Option<Box<dyn SerialDevice>> {
+        self.serial.device.take()
+    }
+
+    /// Holds SID at `level` while no device is attached.
+    pub fn set_sid(&mut self, level: bool) {
+        self.serial.sid = level;
+    }
+
+    /// The level SIM last put on SOD.
+    pub fn sod(&self) -> bool {
+        self.serial.sod
+    }
+
+    pub(crate) fn clock_serial(&mut self) {
+        let cycle = self.cycles;
+        if let Some(device) = &mut self.serial.device {
+            device.clock(cycle);
+        }
+    }
+
+    pub(crate) fn read_sid(&mut self) -> bool {
+        let cycle = self.cycles;
+        match &mut self.serial.device {
+            Some(device) => device.sid(cycle),
+            None => self.serial.sid,
+        }
+    }
+
+    pub(crate) fn write_sod(&mut self, level: bool) {
+        if level == self.serial.sod {
+            return;
+        }
+        self.serial.sod = level;
+        let cycle = self.cycles;
+        if let Some(device) = &mut self.serial.device {
+            device.sod(cycle, level);
+        }
+    }
+}
+
+/// Line settings for a `SerialTerminal`. Frames have a start bit, eight data bits sent least
+/// significant first and `stop_bits` stop bits, with the line idling high.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SerialConfig {
+    pub baud: u32,
+    /// The clock rate of the 8085, which turns bit times into T-states.
+    pub t_states_per_second: u64,
+    pub stop_bits: u8,
+}
+
+impl SerialConfig {
+    /// The SDK-85 monitor's teletype: 110 baud with two stop bits on a 3.072 MHz clock.
+    pub fn sdk85_teletype() -> SerialConfig {
+        SerialConfig { baud: 110, t_states_per_second: 3_072_000, stop_bits: 2 }
+    }
+
+    /// T-states per bit.
+    fn bit(&self) -> f64 {
+        self.t_states_per_second as f64 / self.baud as f64
+    }
+}
+
+/// A terminal on the serial pins: what a program bit-bangs out of SOD is decoded and written to
+/// `output`, and bytes sent down `input` are played into SID with the configured timing.
+pub struct SerialTerminal {
+    config: SerialConfig,
+    output: Box<dyn Write>,
+    input: Receiver<u8>,
+    /// The level on SOD, and every change since the start bit of the frame being received.
+    sod: bool,
+    frame: Vec<(u64, bool)>,
+    /// The frame being sent on SID, as the cycle it started and the byte.
+    sending: Option<(u64, u8)>,
+    /// Frames whose stop bit was low, which are dropped.
+    pub framing_errors: u64,
+}
+
+impl SerialTerminal {
+    pub fn new(config: SerialConfig, output: Box<dyn Write>, input: Receiver<u8>) -> SerialTerminal {
+        SerialTerminal {
+            config,
+            output,
+            input,
+            sod: false,
+            frame: vec![],
+            sending: None,
+            framing_errors: 0,
+        }
+    }
+
+    /// The SOD level at `cycle` within the frame being received.
+    fn level_at(&self, cycle: f64) -> bool {
+        let changes = self.frame.iter().take_while(|(at, _)| *at as f64 <= cycle);
+        changes.last().map_or(self.sod, |(_, level)| *level)
+    }
+
+    /// Decodes the frame being received once `cycle` is past the middle of its stop bit.
+    fn receive(&mut self, cycle: u64) {
+        let Some(&(start, _)) = self.frame.first() else {
+            return;
+        };
+        let bit = self.config.bit();
+        let sample = |index: u8| start as f64 + (index as f64 + 0.5) * bit;
+        if (cycle as f64) <= sample(9) {
+            return;
+        }
+        let byte = (0..8).fold(0u8, |byte, index| byte | (self.level_at(sample(index + 1)) as u8) << index);
+        if self.level_at(sample(9)) {
+            // A closed pipe only means nobody is listening any more.
+            let _ = self.output.write_all(&[byte]).and_then(|_| self.output.flush());
+        } else {
+            self.framing_errors += 1;
+        }
+        self.frame.clear();
+    }
+}
+
+impl SerialDevice for SerialTerminal {
+    fn sid(&mut self, cycle: u64) -> bool {
+        let bit = self.config.bit();
+        let frame_bits = 9 + self.config.stop_bits as u64;
+        if let Some((start, _)) = self.sending {
+            if cycle as f64 >= start as f64 + frame_bits as f64 * bit {
+                self.sending = None;
+            }
+        }
+        if self.sending.is_none() {
+            self.sending = self.input.try_recv().ok().map(|byte| (cycle, byte));
+        }
+        match self.sending {
+            Some((start, byte)) => match ((cycle - start) as f64 / bit) as u64 {
+                0 => false,
+                index @ 1..=8 => byte >> (index - 1) & 1 == 1,
+                _ => true,
+            },
+            None => true,
+        }
+    }
+
+    fn sod(&mut self, cycle: u64, level: bool) {
+        self.receive(cycle);
+        // A falling edge on an idle line is a start bit.
+        if !self.frame.is_empty() || (self.sod && !level) {
+            self.frame.push((cycle, level));
+        }
+        self.sod = level;
+    }
+
+    fn clock(&mut self, cycle: u64) {
+        self.receive(cycle);
+    }
+}
@@ -1,7 +1,25 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::ops::{ Range, RangeInclusive };
+
+use assembler::disassembler::{ disassemble, Disassembly };
+use assembler::timing::{ machine_cycles, t_states };
+
+use crate::debugger::{ Access, Debugger };
+use crate::error::ExecError;
+use crate::fault::{ Fault, FaultAction, FaultKind, FaultPolicy };
+use crate::interrupts::Interrupts;
+use crate::io::IoBus;
+use crate::memory::MemoryMap;
+use crate::run::CancelHandle;
+use crate::serial::SerialPins;
+
+#[allow(dead_code)]
 static MEMORY_LOWER_LIMIT: usize = 1024;
-static MEMORY_UPPER_LIMIT: usize = 64000;
+static MEMORY_UPPER_LIMIT: usize = 0x10000;
 
-#[derive(Clone, Copy)]
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Register {
     A,
     B,
@@ -27,19 +45,58 @@ pub struct Microcontroller {
     flags: u8,
     pub program_counter: u16,
     pub instruction_register: u8,
-    memory: [u8; 65535],
-    io: [u8; 255],
+    memory: MemoryMap,
+    pub(crate) io: IoBus,
     interrupts: bool,
+    pub(crate) interrupt_logic: Interrupts,
+    pub(crate) serial: SerialPins,
     pub running: bool,
+    /// Set by a HLT that an interrupt can still end: the CPU idles until one is acknowledged.
+    pub(crate) halted: bool,
+    /// T-states elapsed since the microcontroller was created.
+    pub cycles: u64,
+    /// Machine cycles elapsed since the microcontroller was created.
+    pub machine_cycles: u64,
+    /// Set by conditional jumps, calls and returns whose condition held, which take longer.
+    pub(crate) branch_taken: bool,
+    /// What to do about each kind of fault.
+    pub fault_policy: FaultPolicy,
+    faults: Vec<Fault>,
+    pub(crate) halted_by: Option<Fault>,
+    /// Where the instruction being executed starts.
+    instruction_start: u16,
+    /// Faults the instruction being executed has made so far. Reads only borrow the simulator,
+    /// hence the `RefCell`.
+    pending_faults: RefCell<Vec<(FaultKind, u16)>>,
+    pub(crate) cancel: CancelHandle,
+    pub(crate) debugger: Debugger,
     op_table: [crate::instructions::Instruction; 256]
 }
 
+/// How long one instruction took to execute.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Cost {
+    pub t_states: u8,
+    pub machine_cycles: u8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Flag {
     Parity,
     Sign,
     Zero,
     Carry,
     AuxCarry,
+    /// V, bit 1: two's complement overflow. Undocumented.
+    Overflow,
+    /// K or X5, bit 5: set when INX/DCX wraps around, and to S xor V by arithmetic. Undocumented.
+    Underflow,
+}
+
+impl Default for Microcontroller {
+    fn default() -> Self {
+        Microcontroller::new()
+    }
 }
 
 impl Microcontroller {
@@ -54,7 +111,7 @@ impl Microcontroller {
             instructions::DCR_B, // 5
             instructions::MVI_B, // 6
             instructions::RLC, // 7
-            instructions::NOOP, // 8
+            instructions::DSUB, // 8
             instructions::DAD_B, // 9
             instructions::LDAX_B, // a
             instructions::DCX_B, // b
@@ -62,7 +119,7 @@ impl Microcontroller {
             instructions::DCR_C, // d
             instructions::MVI_C, // e
             instructions::RRC, // f
-            instructions::NOOP, // 10
+            instructions::ARHL, // 10
             instructions::LXI_D, // 11
             instructions::STAX_D, // 12
             instructions::INX_D, // 13
@@ -70,7 +127,7 @@ impl Microcontroller {
             instructions::DCR_D, // 15
             instructions::MVI_D, // 16
             instructions::RAL, // 17
-            instructions::NOOP, // 18
+            instructions::RDEL, // 18
             instructions::DAD_D, // 19
             instructions::LDAX_D, // 1a
             instructions::DCX_D, // 1b
@@ -86,8 +143,8 @@ impl Microcontroller {
             instructions::DCR_H, // 25
             instructions::MVI_H, // 26
             instructions::DAA, // 27
-            instructions::NOOP, // 28
-            instructions::DAD_D, // 29
+            instructions::LDHI, // 28
+            instructions::DAD_H, // 29
             instructions::LHLD, // 2a
             instructions::DCX_H, // 2b
             instructions::INR_L, // 2c
@@ -102,7 +159,7 @@ impl Microcontroller {
             instructions::DCR_M, // 35
             instructions::MVI_M, // 36
             instructions::STC, // 37
-            instructions::NOOP, // 38
+            instructions::LDSI, // 38
             instructions::DAD_SP, // 39
             instructions::LDA, // 3a
             instructions::DCX_SP, // 3b
@@ -249,7 +306,7 @@ impl Microcontroller {
             instructions::RZ, // c8
             instructions::RET, // c9
             instructions::JZ, // ca
-            instructions::NOOP, // cb
+            instructions::RSTV, // cb
             instructions::CZ, // cc
             instructions::CALL, // cd
             instructions::ACI, // ce
@@ -263,11 +320,11 @@ impl Microcontroller {
             instructions::SUI, // d6
             instructions::RST_2, // d7
             instructions::RC, // d8
-            instructions::NOOP, // d9
+            instructions::SHLX, // d9
             instructions::JC, // da
             instructions::INPUT, // db
             instructions::CC, // dc
-            instructions::NOOP, // dd
+            instructions::JNK, // dd
             instructions::SBI, // de
             instructions::RST_3, // df
             instructions::RPO, // e0
@@ -283,7 +340,7 @@ impl Microcontroller {
             instructions::JPE, // ea
             instructions::XCHG, // eb
             instructions::CPE, // ec
-            instructions::NOOP, // ed
+            instructions::LHLX, // ed
             instructions::XRI, // ee
             instructions::RST_5, // ef
             instructions::RP, // f0
@@ -299,9 +356,9 @@ impl Microcontroller {
             instructions::JM, // fa
             instructions::EI, // fb
             instructions::CM, // fc
-            instructions::NOOP, // fd
+            instructions::JK, // fd
             instructions::CPI, // fe
-            instructions::NOOP  // ff
+            instructions::RST_7  // ff
         ];
         Microcontroller {
             reg_a: 0,
@@ -315,10 +372,23 @@ impl Microcontroller {
             flags: 0,
             program_counter: 0,
             instruction_register: 0,
-            memory: [0u8; 65535],
-            io: [0u8; 255],
+            memory: MemoryMap::default(),
+            io: IoBus::default(),
             interrupts: false,
+            interrupt_logic: Interrupts::default(),
+            serial: SerialPins::default(),
             running: false,
+            halted: false,
+            cycles: 0,
+            machine_cycles: 0,
+            branch_taken: false,
+            fault_policy: FaultPolicy::default(),
+            faults: vec![],
+            halted_by: None,
+            instruction_start: 0,
+            pending_faults: RefCell::new(vec![]),
+            cancel: CancelHandle::default(),
+            debugger: Debugger::default(),
             op_table
         }
     }
@@ -350,24 +420,70 @@ impl Microcontroller {
         }
     }
 
+    /// Reads memory at `location`, or at HL when it is `None`.
     pub fn get_data_at(&self, location: Option<u16>) -> u8 {
-        if let Some(location) = location {
-            self.memory[location as usize]
-        } else {
-            let location = (self.reg_h as u16) << 8 | self.reg_l as u16;
-            self.memory[location as usize]
+        let location = location.unwrap_or((self.reg_h as u16) << 8 | self.reg_l as u16);
+        if self.debugger.recording {
+            self.debugger.memory.borrow_mut().push((location, Access::Read));
         }
+        self.read_memory(location)
     }
 
+    /// Reads memory as the processor does, noting a fault when nothing is there.
+    fn read_memory(&self, location: u16) -> u8 {
+        if !self.memory.is_mapped(location) {
+            self.pending_faults.borrow_mut().push((FaultKind::UnmappedRead, location));
+        }
+        self.peek(location)
+    }
+
+    /// Reads memory without it counting as an access, as a debugger looks at it.
+    pub(crate) fn peek(&self, location: u16) -> u8 {
+        self.memory.read(location, self.cycles)
+    }
+
+    /// Disassembles the memory in `range`, writing addresses that match `symbols` by name.
+    pub fn disassemble(&self, range: Range<u16>, symbols: &HashMap<String, u16>) -> Disassembly {
+        let bytes: Vec<u8> = range.clone().map(|address| self.peek(address)).collect();
+        disassemble(&bytes, range.start, symbols)
+    }
+
+    /// Writes memory at `location`, or at HL when it is `None`. Writes to ROM or to addresses
+    /// nothing is mapped to are lost.
     pub fn set_data_at(&mut self, location: Option<u16>, data: u8) {
-        if let Some(location) = location {
-            self.memory[location as usize] = data;
-        } else {
-            let location = (self.reg_h as u16) << 8 | self.reg_l as u16;
-            self.memory[location as usize] = data;
+        let location = location.unwrap_or((self.reg_h as u16) << 8 | self.reg_l as u16);
+        if self.debugger.recording {
+            self.debugger.memory.get_mut().push((location, Access::Write));
+        }
+        if let Err(kind) = self.memory.write(location, data, self.cycles) {
+            self.pending_faults.get_mut().push((kind, location));
         }
     }
 
+    /// Replaces the memory map, and with it everything in memory.
+    pub fn set_memory_map(&mut self, map: MemoryMap) {
+        self.memory = map;
+    }
+
+    /// Marks `range` as holding data, so that running into it is an `ExecuteData` fault.
+    pub fn mark_data(&mut self, range: RangeInclusive<u16>) {
+        self.memory.mark_data(range);
+    }
+
+    /// Faults logged or halted on, oldest first.
+    pub fn faults(&self) -> &[Fault] {
+        &self.faults
+    }
+
+    pub fn clear_faults(&mut self) {
+        self.faults.clear();
+    }
+
+    /// The fault that stopped the last run, if one did.
+    pub fn halted_by(&self) -> Option<&Fault> {
+        self.halted_by.as_ref()
+    }
+
     pub fn set_register(&mut self, register: Register, data: u8) -> Result<(), &'static str> {
         use Register::{A, B, C, D, E, H, L, M};
         match register {
@@ -387,7 +503,7 @@ impl Microcontroller {
     }
 
     pub fn set_register_pair(&mut self, register: Register, data: u16) -> Result<(), &'static str> {
-        use Register::{B, D, H, SP};
+        use Register::{B, D, H, SP, PSW};
         match register {
             B => {
                 self.reg_c = (data << 8 >> 8) as u8;
@@ -405,6 +521,10 @@ impl Microcontroller {
                 self.stack_pointer.1 = (data << 8 >> 8) as u8;
                 self.stack_pointer.0 = (data >> 8) as u8;
             }
+            PSW => {
+                self.flags = (data << 8 >> 8) as u8;
+                self.reg_a = (data >> 8) as u8;
+            }
             _ => {
                 return Err("not a register pair");
             }
@@ -412,15 +532,21 @@ impl Microcontroller {
         Ok(())
     }
 
-    pub fn set_flag(&mut self, flag: Flag, value: bool) {
-        use Flag::{AuxCarry, Carry, Parity, Sign, Zero};
-        let mask = match flag {
+    fn flag_mask(flag: Flag) -> u8 {
+        use Flag::{AuxCarry, Carry, Overflow, Parity, Sign, Underflow, Zero};
+        match flag {
             Sign => 0b10000000,
             Zero => 0b01000000,
+            Underflow => 0b00100000,
             AuxCarry => 0b00010000,
             Parity => 0b00000100,
+            Overflow => 0b00000010,
             Carry => 0b00000001,
-        };
+        }
+    }
+
+    pub fn set_flag(&mut self, flag: Flag, value: bool) {
+        let mask = Microcontroller::flag_mask(flag);
         if value {
             self.flags |= mask;
         } else {
@@ -429,14 +555,7 @@ impl Microcontroller {
     }
 
     pub fn check_flag(&self, flag: Flag) -> bool {
-        use Flag::{AuxCarry, Carry, Parity, Sign, Zero};
-        let mask = match flag {
-            Sign => 0b10000000,
-            Zero => 0b01000000,
-            AuxCarry => 0b00010000,
-            Parity => 0b00000100,
-            Carry => 0b00000001,
-        };
+        let mask = Microcontroller::flag_mask(flag);
         self.flags & mask == mask
     }
 
@@ -447,44 +566,148 @@ impl Microcontroller {
             Err(format!("Cant load at {load_point} as bytes till {MEMORY_LOWER_LIMIT} are reserved"))
         }*/
         if code.len() + load_point > MEMORY_UPPER_LIMIT {
-            Err(format!("Code does not fit inside memory when loaded at {load_point}"))
-        } else {
-            Ok({
-                self.program_counter = pc;
-                for i in 0..code.len() {
-                    self.memory[i + load_point] = code[i];
-                }
-            })
+            return Err(format!("Code does not fit inside memory when loaded at {load_point}"));
+        }
+        for (offset, byte) in code.iter().enumerate() {
+            let address = pc + offset as u16;
+            if !self.memory.load(address, *byte) {
+                return Err(format!("No memory at {address:04X}H to load code into"));
+            }
         }
+        self.program_counter = pc;
+        Ok(())
     }
 
-    pub fn tick(&mut self) -> Result<(), &'static str> {
-        if self.running {
-            Ok({
-                self.fetch();
-                self.execute();
-            })
-        } else {
-            Err("Microcontroller not started!")
+    /// Runs one instruction, or acknowledges a pending interrupt, returning what it cost. While
+    /// halted waiting for an interrupt, idles for a machine cycle instead of running anything.
+    pub fn tick(&mut self) -> Result<Cost, ExecError> {
+        if !self.running {
+            return Err(ExecError::Halted);
         }
+        self.instruction_start = self.program_counter;
+        self.pending_faults.get_mut().clear();
+        let cost = match self.service_interrupt()? {
+            Some(cost) => {
+                self.halted = false;
+                cost
+            }
+            None if self.halted => self.idle(),
+            None => {
+                if self.memory.is_data(self.program_counter) {
+                    self.pending_faults.get_mut().push((FaultKind::ExecuteData, self.program_counter));
+                    self.handle_faults()?;
+                }
+                self.fetch();
+                self.execute()?
+            }
+        };
+        self.handle_faults()?;
+        self.clock_serial();
+        self.clock_io();
+        Ok(cost)
     }
 
-    pub fn start(&mut self) {
+    /// Runs one instruction whether or not the microcontroller was started, as a debugger's step.
+    /// Breakpoints are not checked.
+    pub fn step(&mut self) -> Result<Cost, ExecError> {
         self.running = true;
+        self.tick()
+    }
+
+    /// Runs until HLT. A HLT with interrupts enabled waits for an interrupt instead when an I/O
+    /// device is attached to raise one; with none attached, nothing could, so the run ends.
+    ///
+    /// Breakpoints and watchpoints are ignored; use `run_with` or one of its shorthands to stop
+    /// on them.
+    pub fn start(&mut self) -> Result<(), ExecError> {
+        self.running = true;
+        self.halted_by = None;
         while self.running {
-            self.tick().unwrap();
+            self.tick()?;
         }
+        Ok(())
     }
 
+    /// Reads the next byte of the instruction stream: from memory at the program counter, or
+    /// from the interrupting device while INTR is being acknowledged.
     pub fn fetch(&mut self) -> u8 {
-        self.instruction_register = self.get_data_at(Some(self.program_counter));
-        self.program_counter += 1;
+        if let Some(byte) = self.interrupt_logic.inta.pop_front() {
+            self.instruction_register = byte;
+            return byte;
+        }
+        self.instruction_register = self.read_memory(self.program_counter);
+        let (next, wrapped) = self.program_counter.overflowing_add(1);
+        if wrapped {
+            self.pending_faults.get_mut().push((FaultKind::PcWrap, self.program_counter));
+        }
+        self.program_counter = next;
         self.instruction_register
     }
 
-    pub fn execute(&mut self) {
-        // TODO: execute instruction
-        self.op_table[self.instruction_register as usize](self);
+    /// Applies `fault_policy` to the faults the current instruction made, failing on the first
+    /// one to halt on.
+    fn handle_faults(&mut self) -> Result<(), ExecError> {
+        for (kind, address) in std::mem::take(self.pending_faults.get_mut()) {
+            let action = self.fault_policy.action(kind);
+            if action == FaultAction::Ignore {
+                continue;
+            }
+            let pc = self.instruction_start;
+            let instruction = self.disassemble(pc..pc.saturating_add(3), &HashMap::new())
+                .lines.first().map_or(String::new(), |line| line.text.clone());
+            let fault = Fault { kind, address, pc, instruction };
+            self.faults.push(fault.clone());
+            if action == FaultAction::Halt {
+                self.halted_by = Some(fault.clone());
+                self.running = false;
+                return Err(fault.into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Executes the instruction in the instruction register and adds its cost to the cycle counters.
+    pub fn execute(&mut self) -> Result<Cost, ExecError> {
+        let opcode = self.instruction_register;
+        let Some((not_taken, taken)) = t_states(opcode) else {
+            return Err(ExecError::IllegalOpcode { opcode, pc: self.instruction_start });
+        };
+        self.branch_taken = false;
+        self.op_table[opcode as usize](self)?;
+        Ok(self.charge(if self.branch_taken { taken } else { not_taken }))
+    }
+
+    /// Fails unless the word a push or pop at `sp` moves is in memory that can hold it.
+    pub(crate) fn check_stack(&self, sp: u16, writing: bool) -> Result<(), ExecError> {
+        let usable = |address| match writing {
+            true => self.memory.is_writable(address),
+            false => self.memory.is_mapped(address),
+        };
+        if sp == 0xFFFF || !usable(sp) || !usable(sp + 1) {
+            return Err(ExecError::StackFault { sp, pc: self.instruction_start });
+        }
+        Ok(())
+    }
+
+    /// Whether a HLT has the CPU waiting for an interrupt.
+    pub fn is_halted(&self) -> bool {
+        self.halted
+    }
+
+    /// Spends one machine cycle halted. The clock keeps running, so devices still see time pass.
+    fn idle(&mut self) -> Cost {
+        let cost = Cost { t_states: 3, machine_cycles: 1 };
+        self.cycles += cost.t_states as u64;
+        self.machine_cycles += cost.machine_cycles as u64;
+        cost
+    }
+
+    /// Adds `t_states` to the cycle counters.
+    pub(crate) fn charge(&mut self, t_states: u8) -> Cost {
+        let cost = Cost { t_states, machine_cycles: machine_cycles(t_states) };
+        self.cycles += cost.t_states as u64;
+        self.machine_cycles += cost.machine_cycles as u64;
+        cost
     }
 
     pub fn check_parity(x: u8) -> bool {
@@ -523,7 +746,7 @@ impl Microcontroller {
     }
 
     pub fn clear_memory(&mut self) {
-        self.memory.fill(0b0);
+        self.memory.clear();
     }
 
     pub fn clear_registers(&mut self) {
@@ -539,19 +762,18 @@ impl Microcontroller {
         self.instruction_register = 0;
     }
 
+    /// EI: interrupts are accepted again once the instruction after it has run.
     pub fn enable_interrupts(&mut self) {
         self.interrupts = true;
+        self.interrupt_logic.ei_delay = true;
     }
 
-    pub fn disable_interrupts(&mut self) {
-        self.interrupts = false;
-    }
-
-    pub fn write_io(&mut self, addr: u16, byte: u8) {
+    pub fn interrupts_enabled(&self) -> bool {
+        self.interrupts
     }
 
-    pub fn read_io(&mut self, addr: u16) -> u8 {
-        0
+    pub fn disable_interrupts(&mut self) {
+        self.interrupts = false;
     }
 
 }
@@ -0,0 +1,2 @@
+        ORG 2000H
+        INCLUDE "bad.asm"
@@ -0,0 +1 @@
+        INCLUDE "cycle2.asm"
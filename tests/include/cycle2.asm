@@ -0,0 +1,2 @@
+        NOP
+        INCLUDE "cycle.asm"
@@ -0,0 +1,2 @@
+        NOP
+        MVI A, 100H
@@ -0,0 +1,7 @@
+; Multiplies A by B, leaving the product in A. Clobbers B and C.
+MUL:    MOV C, A
+        XRA A
+MULLP:  ADD C
+        DCR B
+        JNZ MULLP
+        RET
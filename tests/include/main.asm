@@ -0,0 +1,8 @@
+        ORG 2000H
+START:  MVI A, 3
+        INCLUDE "setup.asm"
+        CALL MUL
+        HLT
+        DB 0
+        INCLUDE "mul.asm"
+        END START
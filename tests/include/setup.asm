@@ -0,0 +1,2 @@
+; Loads the multiplier.
+        MVI B, 4